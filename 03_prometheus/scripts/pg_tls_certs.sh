@@ -0,0 +1,31 @@
+#!/usr/bin/env bash
+
+# Generates self-signed CA, Postgres server certificate for given host and
+# client certificate for given DB user, to try service with DB over TLS
+# locally. Usage: pg_tls_certs.sh [OUT_DIR] [HOST] [DB_USER]
+
+set -eu
+
+OUT=${1:-./certs}
+HOST=${2:-localhost}
+DB_USER=${3:-postgres}
+
+mkdir -p "$OUT"
+cd "$OUT"
+
+openssl req -new -x509 -days 365 -nodes -subj "/CN=Local Postgres CA" \
+    -keyout ca.key -out ca.crt
+
+openssl req -new -nodes -subj "/CN=$HOST" -keyout server.key -out server.csr
+printf "subjectAltName=DNS:%s,IP:127.0.0.1" "$HOST" > server.ext
+openssl x509 -req -days 365 -in server.csr -CA ca.crt -CAkey ca.key \
+    -CAcreateserial -extfile server.ext -out server.crt
+
+# Postgres maps client certificate to user by CN
+openssl req -new -nodes -subj "/CN=$DB_USER" -keyout client.key -out client.csr
+openssl x509 -req -days 365 -in client.csr -CA ca.crt -CAkey ca.key \
+    -CAcreateserial -out client.crt
+
+chmod 600 server.key client.key
+rm -f server.csr client.csr server.ext
+echo "Certificates are written to $OUT"
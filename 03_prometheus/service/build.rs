@@ -0,0 +1,48 @@
+use std::env;
+use std::path::Path;
+use std::process::Command;
+
+fn command_output(cmd: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(cmd).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let out = String::from_utf8(output.stdout).ok()?;
+    Some(out.trim().to_owned()).filter(|o| !o.is_empty())
+}
+
+// Exposes build details to `service_build_info` metric. Docker build has no
+// access to git, so commit can be passed with `GIT_COMMIT` instead.
+fn main() {
+    let git_commit = env::var("GIT_COMMIT")
+        .ok()
+        .filter(|c| !c.is_empty())
+        .or_else(|| command_output("git", &["rev-parse", "--short", "HEAD"]))
+        .unwrap_or_else(|| "unknown".to_owned());
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_owned());
+    let rustc_version =
+        command_output(&rustc, &["--version"]).unwrap_or_else(|| "unknown".to_owned());
+
+    println!("cargo:rustc-env=GIT_COMMIT={}", git_commit);
+    println!("cargo:rustc-env=RUSTC_VERSION={}", rustc_version);
+    println!("cargo:rerun-if-env-changed=GIT_COMMIT");
+    rerun_on_new_commit();
+}
+
+// Commit changes with checkout (HEAD) or with new commit on current branch
+// (its ref, which could also be packed)
+fn rerun_on_new_commit() {
+    let git_dir = match command_output("git", &["rev-parse", "--absolute-git-dir"]) {
+        Some(git_dir) => git_dir,
+        None => return,
+    };
+    let mut paths = vec!["HEAD".to_owned(), "packed-refs".to_owned()];
+    paths.extend(command_output("git", &["symbolic-ref", "-q", "HEAD"]));
+    for path in paths {
+        let path = Path::new(&git_dir).join(path);
+        // Missing file would make build script rerun on every build
+        if path.exists() {
+            println!("cargo:rerun-if-changed={}", path.display());
+        }
+    }
+}
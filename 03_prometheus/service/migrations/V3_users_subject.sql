@@ -0,0 +1,2 @@
+-- Identity (e.g. JWT subject) user record belongs to, if any
+ALTER TABLE users ADD COLUMN IF NOT EXISTS subject text UNIQUE;
@@ -0,0 +1,17 @@
+CREATE TABLE IF NOT EXISTS credentials (
+    user_id integer PRIMARY KEY NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    login text NOT NULL UNIQUE,
+    -- Argon2id hash in PHC string format, includes salt & parameters
+    password_hash text NOT NULL,
+    failed_attempts integer NOT NULL DEFAULT 0,
+    locked_until timestamp with time zone,
+    updated_at timestamp with time zone DEFAULT (now() at time zone 'utc')
+);
+
+CREATE TABLE IF NOT EXISTS sessions (
+    id text PRIMARY KEY NOT NULL,
+    user_id integer NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    created_at timestamp with time zone DEFAULT (now() at time zone 'utc'),
+    expires_at timestamp with time zone NOT NULL,
+    revoked_at timestamp with time zone
+);
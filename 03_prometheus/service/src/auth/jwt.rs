@@ -0,0 +1,411 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use jsonwebtoken::jwk::{AlgorithmParameters, EllipticCurve, Jwk, JwkSet};
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use serde_json::Value;
+use tokio::sync::Mutex;
+use tokio::time::{interval, Duration, Instant, MissedTickBehavior};
+use warp::http::{Request, StatusCode};
+use warp::hyper::Body;
+
+use super::{Principal, Scope};
+use crate::config::Config;
+use crate::context::{log_eprintln, log_println};
+use crate::error::Error::{InvalidToken, JwksError};
+use crate::{http, Result};
+
+// Gateway rotates keys rarely, periodic refresh is mostly for dropping
+// revoked ones
+const REFRESH_INTERVAL: Duration = Duration::from_secs(600);
+// Unknown key id usually means keys were just rotated, but refetching on
+// every such token would let anyone hammer JWKS endpoint
+const MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Algorithms token may be signed with using given key: one set in JWK, or
+/// ones matching key type. Symmetric keys are never accepted, since anyone
+/// who can read JWKS could sign tokens with them.
+fn key_algorithms(jwk: &Jwk) -> Vec<Algorithm> {
+    let by_type = match &jwk.algorithm {
+        AlgorithmParameters::RSA(_) => vec![
+            Algorithm::RS256,
+            Algorithm::RS384,
+            Algorithm::RS512,
+            Algorithm::PS256,
+            Algorithm::PS384,
+            Algorithm::PS512,
+        ],
+        AlgorithmParameters::EllipticCurve(ec) => match ec.curve {
+            EllipticCurve::P256 => vec![Algorithm::ES256],
+            EllipticCurve::P384 => vec![Algorithm::ES384],
+            _ => vec![],
+        },
+        AlgorithmParameters::OctetKeyPair(_) => vec![Algorithm::EdDSA],
+        AlgorithmParameters::OctetKey(_) => vec![],
+    };
+    // Key algorithms include encryption ones, which aren't token algorithms
+    let pinned = jwk
+        .common
+        .key_algorithm
+        .map(|a| a.to_string().parse::<Algorithm>());
+    match pinned {
+        None => by_type,
+        Some(Ok(alg)) if by_type.contains(&alg) => vec![alg],
+        Some(_) => vec![],
+    }
+}
+
+/// Scopes granted by role from token. Unknown roles grant nothing.
+fn role_scopes(role: &str) -> &'static [Scope] {
+    match role {
+        "admin" => &[Scope::Admin],
+        "editor" => &[Scope::UsersRead, Scope::UsersWrite],
+        "viewer" => &[Scope::UsersRead],
+        _ => &[],
+    }
+}
+
+async fn fetch_url(url: &str) -> std::result::Result<Vec<u8>, String> {
+    let req = Request::get(url)
+        .header("accept", "application/json")
+        .body(Body::empty())
+        .map_err(|e| e.to_string())?;
+    let res = http::send(req).await?;
+    if res.status() != StatusCode::OK {
+        return Err(format!("unexpected status {}", res.status()));
+    }
+    Ok(res.into_body())
+}
+
+/// Reads JWKS from `http(s)://` URL or from file
+async fn fetch_jwks(source: &str) -> Result<JwkSet> {
+    let content = if source.starts_with("http://") || source.starts_with("https://") {
+        tokio::time::timeout(FETCH_TIMEOUT, fetch_url(source))
+            .await
+            .unwrap_or_else(|_| Err("request timed out".to_owned()))
+    } else {
+        tokio::fs::read(source).await.map_err(|e| e.to_string())
+    }
+    .map_err(|e| JwksError(format!("can't fetch {}: {}", source, e)))?;
+    serde_json::from_slice(&content)
+        .map_err(|e| JwksError(format!("can't parse {}: {}", source, e)))
+}
+
+/// Roles from claim, which is either list or space separated string.
+/// Nested claims are referred with dots, e.g. `realm_access.roles`.
+fn roles(claims: &HashMap<String, Value>, claim: &str) -> Vec<String> {
+    let mut path = claim.split('.');
+    let mut value = path.next().and_then(|key| claims.get(key));
+    for key in path {
+        value = value.and_then(|v| v.get(key));
+    }
+    match value {
+        Some(Value::Array(roles)) => roles
+            .iter()
+            .filter_map(|r| r.as_str())
+            .map(|r| r.to_owned())
+            .collect(),
+        Some(Value::String(roles)) => roles.split_whitespace().map(|r| r.to_owned()).collect(),
+        _ => vec![],
+    }
+}
+
+struct Inner {
+    jwks_source: String,
+    issuer: String,
+    audience: String,
+    roles_claim: String,
+    keys: RwLock<JwkSet>,
+    last_refresh: Mutex<Option<Instant>>,
+}
+
+/// Validates bearer tokens issued by gateway against its JWKS
+#[derive(Clone)]
+pub struct JwtValidator {
+    inner: Arc<Inner>,
+}
+
+impl JwtValidator {
+    /// Validator if JWKS is configured. Keys are fetched right away, but
+    /// service still starts if it fails, since keys are refetched on demand.
+    pub async fn of(config: &Config) -> Option<JwtValidator> {
+        let jwks_source = config.jwt_jwks.clone()?;
+        let validator = JwtValidator {
+            inner: Arc::new(Inner {
+                jwks_source,
+                issuer: config.jwt_issuer.clone().unwrap_or_default(),
+                audience: config.jwt_audience.clone().unwrap_or_default(),
+                roles_claim: config.jwt_roles_claim.clone(),
+                keys: RwLock::new(JwkSet { keys: vec![] }),
+                last_refresh: Mutex::new(None),
+            }),
+        };
+        validator.refresh(Duration::ZERO).await;
+        Some(validator)
+    }
+
+    /// Refetches keys unless they were fetched within `min_interval`.
+    /// Failed fetch keeps current keys.
+    async fn refresh(&self, min_interval: Duration) {
+        let mut last_refresh = self.inner.last_refresh.lock().await;
+        if last_refresh.is_some_and(|at| at.elapsed() < min_interval) {
+            return;
+        }
+        *last_refresh = Some(Instant::now());
+
+        match fetch_jwks(&self.inner.jwks_source).await {
+            Ok(keys) => {
+                log_println!(
+                    "Loaded {} JWT keys from {}",
+                    keys.keys.len(),
+                    self.inner.jwks_source
+                );
+                *self.inner.keys.write().unwrap() = keys;
+            }
+            Err(e) => log_eprintln!("{}", e),
+        }
+    }
+
+    fn key(&self, kid: &str) -> Option<std::result::Result<(DecodingKey, Vec<Algorithm>), String>> {
+        let keys = self.inner.keys.read().unwrap();
+        keys.find(kid).map(|jwk| {
+            DecodingKey::from_jwk(jwk)
+                .map(|key| (key, key_algorithms(jwk)))
+                .map_err(|e| e.to_string())
+        })
+    }
+
+    pub async fn validate(&self, token: &str) -> Result<Principal> {
+        let header = decode_header(token).map_err(|e| InvalidToken(e.to_string()))?;
+        let kid = header
+            .kid
+            .ok_or_else(|| InvalidToken("token has no kid".to_owned()))?;
+        let (key, algorithms) = match self.key(&kid) {
+            Some(key) => key,
+            None => {
+                self.refresh(MIN_REFRESH_INTERVAL).await;
+                self.key(&kid)
+                    .ok_or_else(|| InvalidToken(format!("unknown kid {}", kid)))?
+            }
+        }
+        .map_err(InvalidToken)?;
+
+        // Algorithm comes from token, so it's only trusted if key allows it
+        if !algorithms.contains(&header.alg) {
+            return Err(InvalidToken(format!(
+                "algorithm {:?} is not allowed for kid {}",
+                header.alg, kid
+            )));
+        }
+        let mut validation = Validation::new(header.alg);
+        validation.algorithms = algorithms;
+        validation.set_issuer(&[&self.inner.issuer]);
+        validation.set_audience(&[&self.inner.audience]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+        let claims = decode::<HashMap<String, Value>>(token, &key, &validation)
+            .map_err(|e| InvalidToken(e.to_string()))?
+            .claims;
+
+        // Subject is identity & record owner, so it can't be left blank
+        let subject = match claims.get("sub").and_then(|s| s.as_str()) {
+            Some(subject) if !subject.is_empty() => subject,
+            _ => return Err(InvalidToken("sub should be non-empty string".to_owned())),
+        };
+        let mut scopes = vec![];
+        for role in roles(&claims, &self.inner.roles_claim) {
+            scopes.extend_from_slice(role_scopes(&role));
+        }
+        Ok(Principal {
+            identity: format!("jwt:{}", subject),
+            scopes,
+            subject: Some(subject.to_owned()),
+            session: None,
+        })
+    }
+
+    /// Refetches keys periodically, so revoked ones stop being accepted
+    pub async fn watch(self) {
+        let mut ticker = interval(REFRESH_INTERVAL);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        // First tick is immediate, and keys were just loaded
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            self.refresh(MIN_REFRESH_INTERVAL).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use openssl::rsa::Rsa;
+    use serde_json::json;
+    use warp::Filter;
+
+    use super::*;
+    use crate::error::Error;
+
+    const ISSUER: &str = "https://gateway.test";
+    const AUDIENCE: &str = "service";
+
+    fn base64url(bytes: &[u8]) -> String {
+        openssl::base64::encode_block(bytes)
+            .replace('+', "-")
+            .replace('/', "_")
+            .trim_end_matches('=')
+            .to_owned()
+    }
+
+    struct Gateway {
+        validator: JwtValidator,
+        signing_key: EncodingKey,
+        fetches: Arc<AtomicUsize>,
+    }
+
+    /// Gateway stub serving JWKS with same RSA key as `pinned`, which only
+    /// allows RS256, and `any-rsa`, which has no `alg`
+    async fn gateway() -> Gateway {
+        let rsa = Rsa::generate(2048).unwrap();
+        let jwk = |kid: &str| {
+            json!({
+                "kty": "RSA",
+                "kid": kid,
+                "n": base64url(&rsa.n().to_vec()),
+                "e": base64url(&rsa.e().to_vec()),
+            })
+        };
+        let mut pinned = jwk("pinned");
+        pinned["alg"] = json!("RS256");
+        let jwks = json!({ "keys": [pinned, jwk("any-rsa")] }).to_string();
+
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let counter = fetches.clone();
+        let route = warp::path("jwks").map(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            jwks.clone()
+        });
+        let (addr, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let args = vec![
+            "--port=3000".to_owned(),
+            "--db-conn-string=postgres://db/app".to_owned(),
+            format!("--jwt-jwks=http://{}/jwks", addr),
+            format!("--jwt-issuer={}", ISSUER),
+            format!("--jwt-audience={}", AUDIENCE),
+        ];
+        let config = Config::load(&args).unwrap();
+        Gateway {
+            validator: JwtValidator::of(&config).await.unwrap(),
+            signing_key: EncodingKey::from_rsa_pem(&rsa.private_key_to_pem().unwrap()).unwrap(),
+            fetches,
+        }
+    }
+
+    impl Gateway {
+        fn sign(&self, alg: Algorithm, kid: Option<&str>, claims: &Value) -> String {
+            sign(&self.signing_key, alg, kid, claims)
+        }
+    }
+
+    fn claims() -> Value {
+        json!({
+            "iss": ISSUER,
+            "aud": AUDIENCE,
+            "sub": "alice",
+            "exp": chrono::Utc::now().timestamp() + 60,
+            "roles": ["editor"],
+        })
+    }
+
+    fn sign(key: &EncodingKey, alg: Algorithm, kid: Option<&str>, claims: &Value) -> String {
+        let mut header = Header::new(alg);
+        header.kid = kid.map(|k| k.to_owned());
+        encode(&header, claims, key).unwrap()
+    }
+
+    async fn rejection(gateway: &Gateway, token: &str) -> String {
+        match gateway.validator.validate(token).await {
+            Err(Error::InvalidToken(e)) => e,
+            other => panic!(
+                "expected invalid token, got {:?}",
+                other.map(|p| p.identity)
+            ),
+        }
+    }
+
+    #[tokio::test]
+    async fn accepts_token_signed_by_gateway() {
+        let gateway = gateway().await;
+        for (alg, kid) in [
+            (Algorithm::RS256, "pinned"),
+            (Algorithm::RS256, "any-rsa"),
+            (Algorithm::PS512, "any-rsa"),
+        ] {
+            let token = gateway.sign(alg, Some(kid), &claims());
+            let principal = gateway.validator.validate(&token).await.unwrap();
+            assert_eq!(principal.identity, "jwt:alice");
+            assert_eq!(principal.subject.as_deref(), Some("alice"));
+            assert_eq!(principal.scopes, [Scope::UsersRead, Scope::UsersWrite]);
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_algorithm_not_allowed_by_key() {
+        let gateway = gateway().await;
+        let token = gateway.sign(Algorithm::PS256, Some("pinned"), &claims());
+        assert!(rejection(&gateway, &token).await.contains("PS256"));
+
+        // Anyone could sign with published key if it was taken as HMAC secret
+        let secret = EncodingKey::from_secret(b"secret");
+        let token = sign(&secret, Algorithm::HS256, Some("any-rsa"), &claims());
+        assert!(rejection(&gateway, &token).await.contains("HS256"));
+    }
+
+    #[tokio::test]
+    async fn rejects_wrong_claims() {
+        let gateway = gateway().await;
+        let mut wrong_issuer = claims();
+        wrong_issuer["iss"] = json!("https://other.test");
+        let mut wrong_audience = claims();
+        wrong_audience["aud"] = json!("other");
+        let mut expired = claims();
+        expired["exp"] = json!(chrono::Utc::now().timestamp() - 3600);
+        let mut no_subject = claims();
+        no_subject.as_object_mut().unwrap().remove("sub");
+        let mut numeric_subject = claims();
+        numeric_subject["sub"] = json!(42);
+        let mut empty_subject = claims();
+        empty_subject["sub"] = json!("");
+
+        for claims in [
+            wrong_issuer,
+            wrong_audience,
+            expired,
+            no_subject,
+            numeric_subject,
+            empty_subject,
+        ] {
+            let token = gateway.sign(Algorithm::RS256, Some("pinned"), &claims);
+            rejection(&gateway, &token).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn unknown_kid_refetch_is_rate_limited() {
+        let gateway = gateway().await;
+        assert_eq!(gateway.fetches.load(Ordering::SeqCst), 1);
+
+        let token = gateway.sign(Algorithm::RS256, None, &claims());
+        assert_eq!(rejection(&gateway, &token).await, "token has no kid");
+
+        let token = gateway.sign(Algorithm::RS256, Some("rotated"), &claims());
+        assert_eq!(rejection(&gateway, &token).await, "unknown kid rotated");
+        // Keys were just fetched on startup
+        assert_eq!(gateway.fetches.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,324 @@
+use std::convert::Infallible;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use warp::filters::path::FullPath;
+use warp::{Filter, Rejection};
+
+use crate::config::Config;
+use crate::context;
+use crate::db::keys;
+use crate::error::Error;
+use crate::routes::path_matches;
+use crate::{DBPool, Result};
+use jwt::JwtValidator;
+use session::Sessions;
+
+pub mod jwt;
+pub mod password;
+pub mod session;
+
+pub const API_KEY_HEADER: &str = "x-api-key";
+// Paths to get credentials at, which can't require them
+const PUBLIC_PATHS: [&str; 2] = ["/auth/register", "/auth/login"];
+
+// Prefix makes keys easy to spot in config & leaked secret scanners
+const KEY_PREFIX: &str = "sk_";
+const KEY_LENGTH: usize = 32;
+// Part of key stored as is, so key can be recognised in listing
+const KEY_VISIBLE_LENGTH: usize = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Scope {
+    UsersRead,
+    UsersWrite,
+    /// Implies all other scopes
+    Admin,
+}
+
+impl FromStr for Scope {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Scope, String> {
+        match s {
+            "users:read" => Ok(Scope::UsersRead),
+            "users:write" => Ok(Scope::UsersWrite),
+            "admin" => Ok(Scope::Admin),
+            _ => Err(format!(
+                "unknown scope {}, should be one of users:read, users:write, admin",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Scope::UsersRead => "users:read",
+            Scope::UsersWrite => "users:write",
+            Scope::Admin => "admin",
+        })
+    }
+}
+
+/// Authenticated caller, either with API key or bearer token
+#[derive(Clone, Debug)]
+pub struct Principal {
+    /// `api-key:NAME`, `jwt:SUBJECT` or subject of user logged in with
+    /// password (`local:NAME`), used in logs
+    pub identity: String,
+    pub scopes: Vec<Scope>,
+    /// Set for end users, whose access is limited to records they own.
+    /// API keys belong to services, so they are limited by scopes only.
+    pub subject: Option<String>,
+    /// Set if caller logged in with password, so session can be ended
+    pub session: Option<String>,
+}
+
+impl Principal {
+    pub fn has_scope(&self, scope: Scope) -> bool {
+        self.scopes.contains(&Scope::Admin) || self.scopes.contains(&scope)
+    }
+
+    /// Subject which records caller is limited to, if any. Admins can act
+    /// on anyone's records.
+    pub fn owner(&self) -> Option<String> {
+        if self.has_scope(Scope::Admin) {
+            None
+        } else {
+            self.subject.clone()
+        }
+    }
+}
+
+/// New random key along with its visible prefix. Key is shown only once,
+/// when it's created.
+pub fn generate_key() -> (String, String) {
+    let secret: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(KEY_LENGTH)
+        .map(char::from)
+        .collect();
+    let key = format!("{}{}", KEY_PREFIX, secret);
+    let prefix = key[..KEY_PREFIX.len() + KEY_VISIBLE_LENGTH].to_owned();
+    (key, prefix)
+}
+
+/// Keys are long & random, so plain SHA-256 is enough, and it allows
+/// to look key up by hash
+pub fn hash_key(key: &str) -> String {
+    openssl::sha::sha256(key.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+async fn key_principal(db_pool: &DBPool, key: &str) -> Result<Principal> {
+    let api_key = keys::find_active_key(db_pool, &hash_key(key))
+        .await?
+        .ok_or(Error::Unauthorized)?;
+    // Scope can be dropped from code while keys still have it
+    let scopes = api_key
+        .scopes
+        .iter()
+        .filter_map(|s| s.parse().ok())
+        .collect();
+    Ok(Principal {
+        identity: format!("api-key:{}", api_key.name),
+        scopes,
+        subject: None,
+        session: None,
+    })
+}
+
+fn bearer_token(authorization: &str) -> Option<&str> {
+    let (scheme, token) = authorization.split_once(' ')?;
+    if scheme.eq_ignore_ascii_case("bearer") {
+        Some(token.trim())
+    } else {
+        None
+    }
+}
+
+#[derive(Clone)]
+struct Authenticator {
+    db_pool: DBPool,
+    jwt: Option<JwtValidator>,
+    sessions: Sessions,
+    exempt_paths: Arc<Vec<String>>,
+}
+
+impl Authenticator {
+    async fn authenticate(
+        self,
+        path: FullPath,
+        key: Option<String>,
+        authorization: Option<String>,
+    ) -> std::result::Result<(), Rejection> {
+        if PUBLIC_PATHS.contains(&path.as_str())
+            || self
+                .exempt_paths
+                .iter()
+                .any(|p| path_matches(p, path.as_str()))
+        {
+            return Ok(());
+        }
+        let token = authorization.as_deref().and_then(bearer_token);
+        let principal = match (key, token, &self.jwt) {
+            (Some(key), _, _) => key_principal(&self.db_pool, &key).await,
+            (None, Some(token), _) if Sessions::is_session_token(token) => {
+                self.sessions.validate(&self.db_pool, token).await
+            }
+            (None, Some(token), Some(jwt)) => jwt.validate(token).await,
+            _ => Err(Error::Unauthorized),
+        };
+        match principal {
+            Ok(principal) => {
+                context::set_principal(principal);
+                Ok(())
+            }
+            Err(e) => Err(warp::reject::custom(e)),
+        }
+    }
+}
+
+/// Requires valid API key in `X-API-Key` header, or bearer token which is
+/// either session one or gateway one (if JWT validation is configured),
+/// except for login paths & paths listed in `auth_exempt_paths`. Should go
+/// after path & method filters of route, like `require` which checks scopes.
+pub fn authenticate(
+    config: &Config,
+    db_pool: &DBPool,
+    jwt: &Option<JwtValidator>,
+    sessions: &Sessions,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    let authenticator = Authenticator {
+        db_pool: db_pool.clone(),
+        jwt: jwt.clone(),
+        sessions: sessions.clone(),
+        exempt_paths: Arc::new(config.auth_exempt_paths.clone()),
+    };
+    warp::path::full()
+        .and(warp::header::optional::<String>(API_KEY_HEADER))
+        .and(warp::header::optional::<String>("authorization"))
+        .and_then(move |path, key, authorization| {
+            authenticator.clone().authenticate(path, key, authorization)
+        })
+        .untuple_one()
+}
+
+/// Subject which caller is limited to, see `Principal::owner`
+pub fn owner() -> impl Filter<Extract = (Option<String>,), Error = Infallible> + Clone {
+    warp::any().map(|| context::principal().and_then(|p| p.owner()))
+}
+
+/// Caller's own subject, regardless of whether caller is limited to it
+pub fn subject() -> impl Filter<Extract = (Option<String>,), Error = Infallible> + Clone {
+    warp::any().map(|| context::principal().and_then(|p| p.subject))
+}
+
+/// Caller's session along with subject, if caller logged in with password
+pub fn session() -> impl Filter<Extract = (Option<(String, String)>,), Error = Infallible> + Clone {
+    warp::any().map(|| {
+        context::principal().and_then(|p| match (p.session, p.subject) {
+            (Some(session), Some(subject)) => Some((session, subject)),
+            _ => None,
+        })
+    })
+}
+
+/// Requires caller either to have given scope, or to be end user, who is
+/// limited to own records anyway (e.g. anyone can delete own record, but
+/// only admin can delete others')
+pub fn require_or_owner(scope: Scope) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::any()
+        .and_then(move || async move {
+            match context::principal() {
+                Some(p) if p.has_scope(scope) || p.subject.is_some() => Ok(()),
+                Some(_) => Err(warp::reject::custom(Error::Forbidden(scope))),
+                None => Err(warp::reject::custom(Error::Unauthorized)),
+            }
+        })
+        .untuple_one()
+}
+
+/// Requires authenticated caller to have given scope. Should go after
+/// path & method filters, so that only matching route rejects request.
+pub fn require(scope: Scope) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::any()
+        .and_then(move || async move {
+            match context::principal() {
+                Some(p) if p.has_scope(scope) => Ok(()),
+                Some(_) => Err(warp::reject::custom(Error::Forbidden(scope))),
+                None => Err(warp::reject::custom(Error::Unauthorized)),
+            }
+        })
+        .untuple_one()
+}
+
+const KEY_COMMAND_USAGE: &str = "keys create NAME SCOPE[,SCOPE...] | keys list | keys revoke ID";
+
+fn invalid_command(message: &str) -> Error {
+    Error::InvalidKeyCommand(format!("{}, usage: {}", message, KEY_COMMAND_USAGE))
+}
+
+/// Runs `keys` admin command. Created key is printed only once, since
+/// only its hash is stored.
+pub async fn key_command(db_pool: &DBPool, command: &[String]) -> Result<()> {
+    let command: Vec<&str> = command.iter().map(|s| s.as_str()).collect();
+    match command[..] {
+        ["create", name, scopes] => {
+            let scopes = scopes
+                .split(',')
+                .map(|s| s.trim().parse::<Scope>().map(|s| s.to_string()))
+                .collect::<std::result::Result<Vec<_>, _>>()
+                .map_err(|e| invalid_command(&e))?;
+            let (key, prefix) = generate_key();
+            let api_key =
+                keys::create_key(db_pool, name, &prefix, &hash_key(&key), &scopes).await?;
+            println!(
+                "Created key {} ({}) with scopes {}",
+                api_key.id,
+                api_key.name,
+                scopes.join(",")
+            );
+            println!("{}", key);
+        }
+        ["list"] => {
+            for key in keys::get_keys(db_pool).await? {
+                let status = match key.revoked_at {
+                    Some(at) => format!("revoked at {}", at.to_rfc3339()),
+                    None => "active".to_owned(),
+                };
+                println!(
+                    "{}\t{}\t{}...\t{}\tcreated at {}\t{}",
+                    key.id,
+                    key.name,
+                    key.prefix,
+                    key.scopes.join(","),
+                    key.created_at.to_rfc3339(),
+                    status
+                );
+            }
+        }
+        ["revoke", id] => {
+            let id = id
+                .parse()
+                .map_err(|_| invalid_command(&format!("key id {} is not a number", id)))?;
+            if keys::revoke_key(db_pool, id).await? {
+                println!("Revoked key {}", id);
+            } else {
+                return Err(Error::InvalidKeyCommand(format!(
+                    "no active key with id {}",
+                    id
+                )));
+            }
+        }
+        _ => return Err(invalid_command("unknown command")),
+    }
+    Ok(())
+}
@@ -0,0 +1,42 @@
+use lazy_static::lazy_static;
+use rand::RngCore;
+use tokio::task;
+
+use crate::error::Error::PasswordHashError;
+use crate::Result;
+
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+const SALT_LENGTH: usize = 16;
+
+lazy_static! {
+    // Checked against when login is unknown, so that response time doesn't
+    // tell which logins exist
+    static ref DUMMY_HASH: String =
+        argon2::hash_encoded(b"dummy password", &[0; SALT_LENGTH], &argon2::Config::default())
+            .expect("dummy password can be hashed");
+}
+
+/// Argon2id hash with OWASP recommended parameters. Hashing takes tens of
+/// milliseconds of CPU on purpose, so it runs off async workers.
+pub async fn hash(password: String) -> Result<String> {
+    task::spawn_blocking(move || {
+        let mut salt = [0; SALT_LENGTH];
+        rand::thread_rng().fill_bytes(&mut salt);
+        argon2::hash_encoded(password.as_bytes(), &salt, &argon2::Config::default())
+            .map_err(|e| PasswordHashError(e.to_string()))
+    })
+    .await
+    .map_err(|e| PasswordHashError(e.to_string()))?
+}
+
+/// Checks password against stored hash, or against dummy one if there's
+/// no such login, so that it takes the same time
+pub async fn verify(hash: Option<String>, password: String) -> Result<bool> {
+    let known = hash.is_some();
+    let hash = hash.unwrap_or_else(|| DUMMY_HASH.clone());
+    let matches = task::spawn_blocking(move || argon2::verify_encoded(&hash, password.as_bytes()))
+        .await
+        .map_err(|e| PasswordHashError(e.to_string()))?
+        .map_err(|e| PasswordHashError(e.to_string()))?;
+    Ok(known && matches)
+}
@@ -0,0 +1,132 @@
+use std::sync::Arc;
+
+use chrono::{DateTime, Duration, Utc};
+use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use super::{Principal, Scope};
+use crate::config::Config;
+use crate::context::log_eprintln;
+use crate::db::credentials;
+use crate::error::Error::{InvalidToken, Unauthorized};
+use crate::{DBPool, Result};
+
+/// Issuer of session tokens, tells them apart from gateway ones. Gateway
+/// can't be configured with it.
+pub const SESSION_ISSUER: &str = "service:session";
+const SESSION_ID_LENGTH: usize = 32;
+
+/// Prefix of subjects of users registered with password, so they can't
+/// clash with gateway subjects
+pub const LOCAL_SUBJECT_PREFIX: &str = "local:";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Claims {
+    sub: String,
+    /// Session id, so that token can be revoked on logout
+    sid: String,
+    iss: String,
+    iat: i64,
+    exp: i64,
+}
+
+/// Only claim read to tell session token from gateway one
+#[derive(Deserialize)]
+struct IssuerClaim {
+    iss: Option<String>,
+}
+
+/// Issues & validates session tokens for users logged in with password.
+/// Tokens are signed, and sessions are stored in DB so they can be revoked.
+#[derive(Clone)]
+pub struct Sessions {
+    encoding_key: Arc<EncodingKey>,
+    decoding_key: Arc<DecodingKey>,
+    pub ttl: Duration,
+    pub max_failed_attempts: i32,
+    pub lockout: Duration,
+}
+
+impl Sessions {
+    pub fn of(config: &Config) -> Sessions {
+        let secret = match &config.session_secret {
+            Some(secret) => secret.clone(),
+            None => {
+                log_eprintln!("session_secret is not set, sessions won't survive restart or work across replicas");
+                rand::thread_rng()
+                    .sample_iter(&Alphanumeric)
+                    .take(48)
+                    .map(char::from)
+                    .collect()
+            }
+        };
+        Sessions {
+            encoding_key: Arc::new(EncodingKey::from_secret(secret.as_bytes())),
+            decoding_key: Arc::new(DecodingKey::from_secret(secret.as_bytes())),
+            ttl: Duration::seconds(config.session_ttl_seconds as i64),
+            max_failed_attempts: config.login_max_failed_attempts as i32,
+            lockout: Duration::seconds(config.login_lockout_seconds as i64),
+        }
+    }
+
+    /// Checks unverified issuer, only to pick validator for token
+    pub fn is_session_token(token: &str) -> bool {
+        let mut validation = Validation::default();
+        validation.insecure_disable_signature_validation();
+        validation.validate_exp = false;
+        validation.validate_aud = false;
+        validation.required_spec_claims.clear();
+        decode::<IssuerClaim>(token, &DecodingKey::from_secret(&[]), &validation)
+            .is_ok_and(|d| d.claims.iss.as_deref() == Some(SESSION_ISSUER))
+    }
+
+    /// Starts new session, returns its token & expiration time
+    pub async fn issue(
+        &self,
+        db_pool: &DBPool,
+        user_id: i32,
+        subject: &str,
+    ) -> Result<(String, DateTime<Utc>)> {
+        let session_id: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(SESSION_ID_LENGTH)
+            .map(char::from)
+            .collect();
+        let now = Utc::now();
+        let expires_at = now + self.ttl;
+        credentials::create_session(db_pool, &session_id, user_id, expires_at).await?;
+
+        let header = Header::new(Algorithm::HS256);
+        let claims = Claims {
+            sub: subject.to_owned(),
+            sid: session_id,
+            iss: SESSION_ISSUER.to_owned(),
+            iat: now.timestamp(),
+            exp: expires_at.timestamp(),
+        };
+        let token = encode(&header, &claims, &self.encoding_key)
+            .map_err(|e| InvalidToken(e.to_string()))?;
+        Ok((token, expires_at))
+    }
+
+    pub async fn validate(&self, db_pool: &DBPool, token: &str) -> Result<Principal> {
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.set_issuer(&[SESSION_ISSUER]);
+        let claims = decode::<Claims>(token, &self.decoding_key, &validation)
+            .map_err(|e| InvalidToken(e.to_string()))?
+            .claims;
+        // Signature only proves token was issued by us, session could be
+        // ended since then
+        let (_, subject) = credentials::get_active_session(db_pool, &claims.sid)
+            .await?
+            .ok_or(Unauthorized)?;
+        Ok(Principal {
+            identity: subject.clone(),
+            scopes: vec![Scope::UsersRead, Scope::UsersWrite],
+            subject: Some(subject),
+            session: Some(claims.sid),
+        })
+    }
+}
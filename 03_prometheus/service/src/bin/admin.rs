@@ -0,0 +1,27 @@
+use service::config::Config;
+use std::env;
+
+const USAGE: &str =
+    "Usage: admin keys (create NAME SCOPE[,SCOPE...] | list | revoke ID) [config flags]";
+
+#[tokio::main]
+async fn main() {
+    // Command comes first, everything from first flag on is config flags
+    let args: Vec<String> = env::args().skip(1).collect();
+    let split = args
+        .iter()
+        .position(|a| a.starts_with("--"))
+        .unwrap_or(args.len());
+    let (command, flags) = args.split_at(split);
+
+    match command.split_first() {
+        Some((subcommand, rest)) if subcommand == "keys" => {
+            let config = Config::load_or_exit(flags, Config::load);
+            service::keys(&config, rest).await;
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            std::process::exit(2);
+        }
+    }
+}
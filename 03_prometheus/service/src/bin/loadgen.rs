@@ -0,0 +1,419 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+use serde::Serialize;
+use serde_json::json;
+use tokio::sync::Semaphore;
+use tokio::time::{interval, MissedTickBehavior};
+use warp::http::{Method, Request};
+use warp::hyper::client::HttpConnector;
+use warp::hyper::{Body, Client};
+
+const USAGE: &str = "Usage: loadgen [--url URL] [--rps N] [--concurrency N] [--duration SECONDS] \
+[--mix create=1,get=5,list=1,update=2,delete=1] [--api-key KEY] [--json]";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Op {
+    Create,
+    Get,
+    List,
+    Update,
+    Delete,
+}
+
+impl Op {
+    fn parse(s: &str) -> Option<Op> {
+        match s {
+            "create" => Some(Op::Create),
+            "get" => Some(Op::Get),
+            "list" => Some(Op::List),
+            "update" => Some(Op::Update),
+            "delete" => Some(Op::Delete),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Op::Create => "create",
+            Op::Get => "get",
+            Op::List => "list",
+            Op::Update => "update",
+            Op::Delete => "delete",
+        }
+    }
+}
+
+struct Options {
+    url: String,
+    rps: u32,
+    concurrency: usize,
+    duration: Duration,
+    mix: Vec<(Op, u32)>,
+    // Defaults to API_KEY env var, so key doesn't end up in shell history
+    api_key: Option<String>,
+    json: bool,
+}
+
+impl Options {
+    fn from_args() -> Result<Options, String> {
+        let mut options = Options {
+            url: "http://localhost:3000".into(),
+            rps: 10,
+            concurrency: 8,
+            duration: Duration::from_secs(30),
+            mix: vec![
+                (Op::Create, 1),
+                (Op::Get, 5),
+                (Op::List, 1),
+                (Op::Update, 2),
+                (Op::Delete, 1),
+            ],
+            api_key: env::var("API_KEY").ok().filter(|k| !k.is_empty()),
+            json: false,
+        };
+
+        let mut args = env::args().skip(1);
+        while let Some(arg) = args.next() {
+            if arg == "--json" {
+                options.json = true;
+                continue;
+            }
+            let value = args
+                .next()
+                .ok_or_else(|| format!("missing value for {}", arg))?;
+            match arg.as_str() {
+                "--url" => options.url = value.trim_end_matches('/').to_owned(),
+                "--rps" => options.rps = parse_positive(&arg, &value)?,
+                "--concurrency" => options.concurrency = parse_positive(&arg, &value)?,
+                "--duration" => {
+                    options.duration = Duration::from_secs(parse_positive(&arg, &value)?)
+                }
+                "--mix" => options.mix = parse_mix(&value)?,
+                "--api-key" => options.api_key = Some(value),
+                _ => return Err(format!("unexpected argument {}", arg)),
+            }
+        }
+        Ok(options)
+    }
+}
+
+fn parse_positive<T: std::str::FromStr + PartialOrd + Default>(
+    name: &str,
+    value: &str,
+) -> Result<T, String> {
+    match value.parse::<T>() {
+        Ok(v) if v > T::default() => Ok(v),
+        _ => Err(format!(
+            "{} should be a positive number, got {}",
+            name, value
+        )),
+    }
+}
+
+fn parse_mix(value: &str) -> Result<Vec<(Op, u32)>, String> {
+    let mut mix = vec![];
+    for part in value.split(',') {
+        let (name, weight) = part
+            .split_once('=')
+            .ok_or_else(|| format!("mix entry should be op=weight, got {}", part))?;
+        let op = Op::parse(name.trim()).ok_or_else(|| format!("unknown operation {}", name))?;
+        let weight = weight
+            .trim()
+            .parse::<u32>()
+            .map_err(|_| format!("weight of {} is not a number", name))?;
+        mix.push((op, weight));
+    }
+    if mix.iter().all(|(_, w)| *w == 0) {
+        return Err("mix should have at least one operation with positive weight".into());
+    }
+    Ok(mix)
+}
+
+fn pick_op(mix: &[(Op, u32)]) -> Op {
+    let total: u32 = mix.iter().map(|(_, w)| w).sum();
+    let mut n = rand::thread_rng().gen_range(0..total);
+    for (op, weight) in mix {
+        if n < *weight {
+            return *op;
+        }
+        n -= weight;
+    }
+    mix[0].0
+}
+
+#[derive(Default)]
+struct OpStats {
+    latencies: Vec<Duration>,
+    statuses: BTreeMap<String, usize>,
+}
+
+#[derive(Default)]
+struct Stats {
+    ops: BTreeMap<Op, OpStats>,
+    // Ticks when all workers were busy, so request was not sent
+    skipped: usize,
+}
+
+impl Stats {
+    fn record(&mut self, op: Op, latency: Duration, status: String) {
+        let stats = self.ops.entry(op).or_default();
+        stats.latencies.push(latency);
+        *stats.statuses.entry(status).or_default() += 1;
+    }
+}
+
+#[derive(Serialize)]
+struct LatencySummary {
+    count: usize,
+    p50_ms: f64,
+    p90_ms: f64,
+    p95_ms: f64,
+    p99_ms: f64,
+    max_ms: f64,
+}
+
+impl LatencySummary {
+    fn of(latencies: &[Duration]) -> LatencySummary {
+        let mut sorted = latencies.to_vec();
+        sorted.sort();
+        let percentile = |p: f64| -> f64 {
+            if sorted.is_empty() {
+                return 0.0;
+            }
+            let idx = ((sorted.len() as f64 * p).ceil() as usize).clamp(1, sorted.len()) - 1;
+            sorted[idx].as_secs_f64() * 1000.0
+        };
+        LatencySummary {
+            count: sorted.len(),
+            p50_ms: percentile(0.5),
+            p90_ms: percentile(0.9),
+            p95_ms: percentile(0.95),
+            p99_ms: percentile(0.99),
+            max_ms: percentile(1.0),
+        }
+    }
+}
+
+struct LoadGen {
+    client: Client<HttpConnector>,
+    url: String,
+    api_key: Option<String>,
+    // Ids of users created during run, used by get/update/delete
+    user_ids: Mutex<Vec<i32>>,
+    stats: Mutex<Stats>,
+}
+
+impl LoadGen {
+    fn random_user_id(&self, remove: bool) -> Option<i32> {
+        let mut ids = self.user_ids.lock().unwrap();
+        if ids.is_empty() {
+            return None;
+        }
+        let idx = rand::thread_rng().gen_range(0..ids.len());
+        Some(if remove {
+            ids.swap_remove(idx)
+        } else {
+            ids[idx]
+        })
+    }
+
+    fn user_body() -> Body {
+        let n: u32 = rand::thread_rng().gen();
+        Body::from(
+            json!({
+                "username": format!("loadgen{}", n),
+                "firstname": "Load",
+                "lastname": "Generator",
+                "email": format!("loadgen{}@example.com", n),
+                "phone": "+10000000000",
+            })
+            .to_string(),
+        )
+    }
+
+    fn build_request(&self, op: Op) -> (Op, Request<Body>) {
+        // Without known users there's nothing to read or modify, so we
+        // have to create one first
+        let id = match op {
+            Op::Get | Op::Update => self.random_user_id(false),
+            Op::Delete => self.random_user_id(true),
+            _ => None,
+        };
+        let (op, method, path, body) = match (op, id) {
+            (Op::List, _) => (op, Method::GET, "/user".to_owned(), Body::empty()),
+            (Op::Get, Some(id)) => (op, Method::GET, format!("/user/{}", id), Body::empty()),
+            (Op::Update, Some(id)) => (op, Method::PUT, format!("/user/{}", id), Self::user_body()),
+            (Op::Delete, Some(id)) => (op, Method::DELETE, format!("/user/{}", id), Body::empty()),
+            _ => (
+                Op::Create,
+                Method::POST,
+                "/user".to_owned(),
+                Self::user_body(),
+            ),
+        };
+        let mut builder = Request::builder()
+            .method(method)
+            .uri(format!("{}{}", self.url, path))
+            .header("content-type", "application/json");
+        if let Some(key) = &self.api_key {
+            builder = builder.header("x-api-key", key);
+        }
+        let req = builder.body(body).expect("request can be built");
+        (op, req)
+    }
+
+    async fn run_once(&self, op: Op) {
+        let (op, req) = self.build_request(op);
+        let started_at = Instant::now();
+        let res = self.client.request(req).await;
+        let latency = started_at.elapsed();
+
+        let status = match res {
+            Ok(res) => {
+                let status = res.status();
+                if op == Op::Create && status.is_success() {
+                    if let Ok(body) = warp::hyper::body::to_bytes(res.into_body()).await {
+                        let id = serde_json::from_slice::<serde_json::Value>(&body)
+                            .ok()
+                            .and_then(|v| v["id"].as_i64());
+                        if let Some(id) = id {
+                            self.user_ids.lock().unwrap().push(id as i32);
+                        }
+                    }
+                }
+                status.as_u16().to_string()
+            }
+            Err(_) => "error".to_owned(),
+        };
+        self.stats.lock().unwrap().record(op, latency, status);
+    }
+}
+
+fn report(stats: &Stats, elapsed: Duration, as_json: bool) {
+    let all: Vec<Duration> = stats
+        .ops
+        .values()
+        .flat_map(|s| s.latencies.iter().cloned())
+        .collect();
+    let mut all_statuses: BTreeMap<String, usize> = BTreeMap::new();
+    for s in stats.ops.values() {
+        for (status, count) in &s.statuses {
+            *all_statuses.entry(status.clone()).or_default() += count;
+        }
+    }
+    let total = LatencySummary::of(&all);
+    let achieved_rps = total.count as f64 / elapsed.as_secs_f64();
+
+    if as_json {
+        let ops: BTreeMap<&str, serde_json::Value> = stats
+            .ops
+            .iter()
+            .map(|(op, s)| {
+                (
+                    op.name(),
+                    json!({"latency": LatencySummary::of(&s.latencies), "statuses": s.statuses}),
+                )
+            })
+            .collect();
+        let out = json!({
+            "elapsed_seconds": elapsed.as_secs_f64(),
+            "achieved_rps": achieved_rps,
+            "skipped": stats.skipped,
+            "total": {"latency": total, "statuses": all_statuses},
+            "operations": ops,
+        });
+        println!("{}", serde_json::to_string_pretty(&out).unwrap());
+        return;
+    }
+
+    println!(
+        "Sent {} requests in {:.1}s ({:.1} rps), {} skipped as all workers were busy",
+        total.count,
+        elapsed.as_secs_f64(),
+        achieved_rps,
+        stats.skipped
+    );
+    println!(
+        "{:<8} {:>7} {:>9} {:>9} {:>9} {:>9} {:>9}  statuses",
+        "op", "count", "p50 ms", "p90 ms", "p95 ms", "p99 ms", "max ms"
+    );
+    let print_row = |name: &str, l: &LatencySummary, statuses: &BTreeMap<String, usize>| {
+        let statuses: Vec<String> = statuses
+            .iter()
+            .map(|(s, c)| format!("{}={}", s, c))
+            .collect();
+        println!(
+            "{:<8} {:>7} {:>9.2} {:>9.2} {:>9.2} {:>9.2} {:>9.2}  {}",
+            name,
+            l.count,
+            l.p50_ms,
+            l.p90_ms,
+            l.p95_ms,
+            l.p99_ms,
+            l.max_ms,
+            statuses.join(" ")
+        );
+    };
+    for (op, s) in &stats.ops {
+        print_row(op.name(), &LatencySummary::of(&s.latencies), &s.statuses);
+    }
+    print_row("total", &total, &all_statuses);
+}
+
+#[tokio::main]
+async fn main() {
+    let options = match Options::from_args() {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    if !options.json {
+        println!(
+            "Sending {} rps to {} for {:?} with {} workers",
+            options.rps, options.url, options.duration, options.concurrency
+        );
+    }
+
+    let loadgen = Arc::new(LoadGen {
+        client: Client::new(),
+        url: options.url.clone(),
+        api_key: options.api_key.clone(),
+        user_ids: Mutex::new(vec![]),
+        stats: Mutex::new(Stats::default()),
+    });
+    let workers = Arc::new(Semaphore::new(options.concurrency));
+    let mut ticker = interval(Duration::from_secs_f64(1.0 / options.rps as f64));
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+    let started_at = Instant::now();
+    while started_at.elapsed() < options.duration {
+        ticker.tick().await;
+        let permit = match workers.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                loadgen.stats.lock().unwrap().skipped += 1;
+                continue;
+            }
+        };
+        let op = pick_op(&options.mix);
+        let loadgen = loadgen.clone();
+        tokio::spawn(async move {
+            loadgen.run_once(op).await;
+            drop(permit);
+        });
+    }
+
+    // Wait for in-flight requests to finish
+    let _ = workers.acquire_many(options.concurrency as u32).await;
+    let elapsed = started_at.elapsed();
+
+    let stats = loadgen.stats.lock().unwrap();
+    report(&stats, elapsed, options.json);
+}
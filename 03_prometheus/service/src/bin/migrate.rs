@@ -4,19 +4,17 @@ use std::env;
 
 #[tokio::main]
 async fn main() {
-    let config = Config::from_env();
+    // Everything except "--wait" is config flags
+    let (wait, args): (Vec<String>, Vec<String>) = env::args().skip(1).partition(|a| a == "--wait");
+    let config = Config::load_or_exit(&args, Config::load);
 
-    let args: Vec<String> = env::args().collect();
-
-    if args.len() == 1 {
+    if wait.is_empty() {
         println!("Running migrations...");
         migrate(&config).await;
         println!("Running migrations...DONE");
-    } else if args.len() == 2 && args[1].eq("--wait") {
+    } else {
         println!("Waiting for migration to be applied...");
         wait_for_migrate(&config).await;
         println!("Waiting for migration to be applied...DONE");
-    } else {
-        panic!("Unexpected arguments, only optional \"--wait\" can be passed");
     }
 }
@@ -1,27 +1,1058 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
 use std::env;
+use std::fmt::Display;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
 
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use warp::http::{header::HeaderName, Method};
+
+use crate::error::Error::InvalidConfig;
+use crate::Result;
+
+/// Config file can be set with this env var as well as `--config` flag
+const CONFIG_FILE_ENV: &str = "CONFIG_FILE";
+const REDACTED: &str = "<redacted>";
+
+/// Setting which can be set in config file, env var & CLI flag. Flag is
+/// the key with `_` replaced by `-`, e.g. `--metrics-port`. Only reloadable
+/// settings can be changed without restart.
+struct Setting {
+    key: &'static str,
+    env: &'static str,
+    secret: bool,
+    reloadable: bool,
+}
+
+const fn setting(key: &'static str, env: &'static str) -> Setting {
+    Setting {
+        key,
+        env,
+        secret: false,
+        reloadable: false,
+    }
+}
+
+const fn secret(key: &'static str, env: &'static str) -> Setting {
+    Setting {
+        secret: true,
+        ..setting(key, env)
+    }
+}
+
+const fn reloadable(key: &'static str, env: &'static str) -> Setting {
+    Setting {
+        reloadable: true,
+        ..setting(key, env)
+    }
+}
+
+const SETTINGS: &[Setting] = &[
+    setting("port", "PORT"),
+    setting("metrics_port", "METRICS_PORT"),
+    setting("tls_cert", "TLS_CERT"),
+    setting("tls_key", "TLS_KEY"),
+    setting("tls_client_ca", "TLS_CLIENT_CA"),
+    secret("db_conn_string", "PG_CONN_STRING"),
+    // Same env vars as libpq uses
+    setting("db_ssl_root_cert", "PGSSLROOTCERT"),
+    setting("db_ssl_cert", "PGSSLCERT"),
+    setting("db_ssl_key", "PGSSLKEY"),
+    setting("db_pool_max_open", "DB_POOL_MAX_OPEN"),
+    reloadable("db_pool_max_idle", "DB_POOL_MAX_IDLE"),
+    setting("db_pool_timeout_seconds", "DB_POOL_TIMEOUT_SECONDS"),
+    setting("db_retry_max_attempts", "DB_RETRY_MAX_ATTEMPTS"),
+    setting("db_retry_base_delay_ms", "DB_RETRY_BASE_DELAY_MS"),
+    setting("db_retry_max_delay_ms", "DB_RETRY_MAX_DELAY_MS"),
+    setting("db_retry_budget_ratio", "DB_RETRY_BUDGET_RATIO"),
+    setting("db_wait_timeout_seconds", "DB_WAIT_TIMEOUT_SECONDS"),
+    setting("db_wait_max_delay_seconds", "DB_WAIT_MAX_DELAY_SECONDS"),
+    setting(
+        "db_breaker_failure_threshold",
+        "DB_BREAKER_FAILURE_THRESHOLD",
+    ),
+    setting("db_breaker_open_seconds", "DB_BREAKER_OPEN_SECONDS"),
+    setting("env", "RUST_ENV"),
+    reloadable("log_level", "LOG_LEVEL"),
+    reloadable("cors_allowed_origins", "CORS_ALLOWED_ORIGINS"),
+    reloadable("cors_allowed_methods", "CORS_ALLOWED_METHODS"),
+    reloadable("cors_allowed_headers", "CORS_ALLOWED_HEADERS"),
+    reloadable("cors_exposed_headers", "CORS_EXPOSED_HEADERS"),
+    reloadable("cors_allow_credentials", "CORS_ALLOW_CREDENTIALS"),
+    reloadable("cors_max_age_seconds", "CORS_MAX_AGE_SECONDS"),
+    reloadable("auth_exempt_paths", "AUTH_EXEMPT_PATHS"),
+    reloadable("address_rate_limits", "ADDRESS_RATE_LIMITS"),
+    reloadable("rate_limits", "RATE_LIMITS"),
+    setting("trusted_proxies", "TRUSTED_PROXIES"),
+    reloadable("max_body_bytes", "MAX_BODY_BYTES"),
+    reloadable("request_timeout_seconds", "REQUEST_TIMEOUT_SECONDS"),
+    reloadable("route_limits", "ROUTE_LIMITS"),
+    reloadable("uncapped_paths", "UNCAPPED_PATHS"),
+    setting("max_in_flight_requests", "MAX_IN_FLIGHT_REQUESTS"),
+    setting("jwt_jwks", "JWT_JWKS"),
+    setting("jwt_issuer", "JWT_ISSUER"),
+    setting("jwt_audience", "JWT_AUDIENCE"),
+    setting("jwt_roles_claim", "JWT_ROLES_CLAIM"),
+    secret("session_secret", "SESSION_SECRET"),
+    setting("session_ttl_seconds", "SESSION_TTL_SECONDS"),
+    setting("login_max_failed_attempts", "LOGIN_MAX_FAILED_ATTEMPTS"),
+    setting("login_lockout_seconds", "LOGIN_LOCKOUT_SECONDS"),
+    setting("fault_injection", "FAULT_INJECTION"),
+    setting("pushgateway_url", "PUSHGATEWAY_URL"),
+    setting("pushgateway_timeout_seconds", "PUSHGATEWAY_TIMEOUT_SECONDS"),
+    setting("otlp_endpoint", "OTEL_EXPORTER_OTLP_ENDPOINT"),
+    setting("trace_sampling_ratio", "OTEL_TRACES_SAMPLING_RATIO"),
+    setting("shutdown_drain_seconds", "SHUTDOWN_DRAIN_SECONDS"),
+    setting("shutdown_timeout_seconds", "SHUTDOWN_TIMEOUT_SECONDS"),
+    setting("slos", "SLOS"),
+];
+
+fn flag_name(key: &str) -> String {
+    format!("--{}", key.replace('_', "-"))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl FromStr for LogLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<LogLevel, String> {
+        match s.to_lowercase().as_str() {
+            "error" => Ok(LogLevel::Error),
+            "warn" => Ok(LogLevel::Warn),
+            "info" => Ok(LogLevel::Info),
+            "debug" => Ok(LogLevel::Debug),
+            _ => Err("should be one of error, warn, info, debug".to_owned()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SloKind {
+    /// Request is good if it didn't fail with 5xx
+    Availability,
+    /// Request is good if it finished within `latency_threshold_seconds`
+    Latency,
+}
+
+/// Service level objective for requests matching method & path. Path
+/// segments can be replaced with `*`, and trailing `**` matches the rest
+/// of path, e.g. `/user/**`. No path means all requests.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct SloConfig {
+    pub name: String,
+    pub kind: SloKind,
+    pub method: Option<String>,
+    pub path: Option<String>,
+    /// Target ratio of good requests, e.g. `0.999`
+    pub objective: f64,
+    pub latency_threshold_seconds: Option<f64>,
+}
+
+impl SloConfig {
+    fn validate(&self) -> std::result::Result<(), String> {
+        if !(self.objective > 0.0 && self.objective < 1.0) {
+            return Err(format!(
+                "SLO {} objective {} is not in (0, 1)",
+                self.name, self.objective
+            ));
+        }
+        if self.kind == SloKind::Latency && self.latency_threshold_seconds.is_none() {
+            return Err(format!(
+                "SLO {} has no latency_threshold_seconds",
+                self.name
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn default_slos() -> Vec<SloConfig> {
+    vec![
+        SloConfig {
+            name: "user-availability".to_owned(),
+            kind: SloKind::Availability,
+            method: None,
+            path: Some("/user/**".to_owned()),
+            objective: 0.999,
+            latency_threshold_seconds: None,
+        },
+        SloConfig {
+            name: "user-latency".to_owned(),
+            kind: SloKind::Latency,
+            method: None,
+            path: Some("/user/**".to_owned()),
+            objective: 0.99,
+            latency_threshold_seconds: Some(0.3),
+        },
+    ]
+}
+
+/// Token bucket limit for requests matching method & path, same patterns
+/// as SLO paths. Each client has its own bucket of `burst` requests, which
+/// refills at `requests_per_second`. First matching limit applies.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub method: Option<String>,
+    pub path: Option<String>,
+    pub requests_per_second: f64,
+    pub burst: u32,
+}
+
+impl RateLimitConfig {
+    fn validate(&self) -> std::result::Result<(), String> {
+        let path = self.path.as_deref().unwrap_or("/**");
+        if self.requests_per_second.is_nan() || self.requests_per_second <= 0.0 {
+            return Err(format!(
+                "rate limit for {} requests_per_second should be positive",
+                path
+            ));
+        }
+        if self.burst == 0 {
+            return Err(format!("rate limit for {} burst should be positive", path));
+        }
+        Ok(())
+    }
+}
+
+/// Body size & timeout for requests matching method & path, overriding
+/// global ones. First matching route applies.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteLimitConfig {
+    pub method: Option<String>,
+    pub path: String,
+    pub max_body_bytes: Option<u64>,
+    pub timeout_seconds: Option<u64>,
+}
+
+#[derive(Clone, Debug, Serialize)]
 pub struct Config {
-    pub port: u16,
+    /// Only server needs it, admin & migrate binaries don't listen
+    pub port: Option<u16>,
+    pub metrics_port: Option<u16>,
+    /// Server certificate chain & key, API is served over HTTPS if set.
+    /// Files are re-read when they change.
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+    /// CA bundle to verify client certificates with. If set, clients have
+    /// to present certificate (mutual TLS).
+    pub tls_client_ca: Option<String>,
     pub db_conn_string: String,
+    /// CA bundle to verify DB server certificate with
+    pub db_ssl_root_cert: Option<String>,
+    /// Client certificate & key, if DB requires certificate authentication
+    pub db_ssl_cert: Option<String>,
+    pub db_ssl_key: Option<String>,
+    pub db_pool_max_open: u64,
+    pub db_pool_max_idle: u64,
+    pub db_pool_timeout_seconds: u64,
+    /// Attempts of idempotent DB operations, including first one
+    pub db_retry_max_attempts: u32,
+    pub db_retry_base_delay_ms: u64,
+    pub db_retry_max_delay_ms: u64,
+    /// Retries allowed per successful operation
+    pub db_retry_budget_ratio: f64,
+    /// How long binaries wait for database on startup
+    pub db_wait_timeout_seconds: u64,
+    pub db_wait_max_delay_seconds: u64,
+    /// Failures to get connection in a row which open circuit breaker
+    pub db_breaker_failure_threshold: u32,
+    /// How long breaker stays open before probing database
+    pub db_breaker_open_seconds: u64,
     pub env: String,
+    pub log_level: LogLevel,
+    /// Origins like `https://example.com`, `https://*.example.com` for its
+    /// subdomains, or `*` for any
+    pub cors_allowed_origins: Vec<String>,
+    pub cors_allowed_methods: Vec<String>,
+    /// Request headers browsers may send, besides CORS-safelisted ones
+    pub cors_allowed_headers: Vec<String>,
+    /// Response headers scripts may read, besides CORS-safelisted ones
+    pub cors_exposed_headers: Vec<String>,
+    /// Whether browsers may send cookies & client certificates. Requires
+    /// listing origins explicitly.
+    pub cors_allow_credentials: bool,
+    /// How long browsers may cache preflight response
+    pub cors_max_age_seconds: u64,
+    /// Paths served without API key, same patterns as SLO paths
+    pub auth_exempt_paths: Vec<String>,
+    /// Per-route limits keyed by client address, checked before
+    /// authentication
+    pub address_rate_limits: Vec<RateLimitConfig>,
+    /// Per-route limits, keyed by caller identity or address
+    pub rate_limits: Vec<RateLimitConfig>,
+    /// Proxy addresses or networks, which client address is taken from
+    /// `X-Forwarded-For` for
+    pub trusted_proxies: Vec<String>,
+    pub max_body_bytes: u64,
+    /// Requests taking longer get 504, so that stuck ones don't hold DB
+    /// connections
+    pub request_timeout_seconds: u64,
+    pub route_limits: Vec<RouteLimitConfig>,
+    /// Requests beyond that get 503 right away instead of queueing
+    pub max_in_flight_requests: usize,
+    /// Paths not counted against `max_in_flight_requests`
+    pub uncapped_paths: Vec<String>,
+    /// JWKS file or `http(s)://` URL. Bearer tokens are accepted only if set,
+    /// and have to be issued by `jwt_issuer` for `jwt_audience`.
+    pub jwt_jwks: Option<String>,
+    pub jwt_issuer: Option<String>,
+    pub jwt_audience: Option<String>,
+    /// Claim with roles, nested ones are referred with dots
+    pub jwt_roles_claim: String,
+    /// Key to sign session tokens with, should be the same for all replicas
+    pub session_secret: Option<String>,
+    pub session_ttl_seconds: u64,
+    /// Login is locked for `login_lockout_seconds` after that many failed
+    /// attempts in a row
+    pub login_max_failed_attempts: u32,
+    pub login_lockout_seconds: u64,
+    pub fault_injection: bool,
+    pub pushgateway_url: Option<String>,
+    /// Push is given up after that, so migration isn't held by it
+    pub pushgateway_timeout_seconds: u64,
+    pub otlp_endpoint: Option<String>,
+    pub trace_sampling_ratio: f64,
+    pub shutdown_drain_seconds: u64,
+    pub shutdown_timeout_seconds: u64,
+    pub slos: Vec<SloConfig>,
+    // Where config was loaded from, so it can be loaded again on reload
+    #[serde(skip)]
+    file: Option<String>,
+    #[serde(skip)]
+    args: Vec<String>,
 }
 
-impl Config {
-    pub fn from_env() -> Config {
-        let port = env::var("PORT")
-            .expect("PORT is not set")
-            .parse::<u16>()
-            .unwrap();
-        let db_conn_string = env::var("PG_CONN_STRING").expect("PG_CONN_STRING is not set");
-        let env = match env::var("RUST_ENV") {
-            Ok(x) => x,
-            Err(_) => "develop".to_owned(),
+/// Raw setting value along with where it came from, for error messages
+struct RawValue {
+    value: String,
+    origin: String,
+}
+
+/// Reads environment variable, so that tests don't have to touch process env
+type EnvLookup<'a> = &'a dyn Fn(&str) -> Option<String>;
+
+/// Raw values of all layers merged, so that later layer wins. Typed values
+/// are parsed from here, collecting errors instead of failing on first one.
+#[derive(Default)]
+struct Layers {
+    values: BTreeMap<&'static str, RawValue>,
+    errors: Vec<String>,
+}
+
+impl Layers {
+    fn set(&mut self, key: &'static str, value: String, origin: String) {
+        self.values.insert(key, RawValue { value, origin });
+    }
+
+    fn load_file(&mut self, path: &str) {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) => {
+                self.errors
+                    .push(format!("can't read config file {}: {}", path, e));
+                return;
+            }
+        };
+        let parsed = if path.ends_with(".yaml") || path.ends_with(".yml") {
+            serde_yaml::from_str::<Value>(&content).map_err(|e| e.to_string())
+        } else {
+            toml::from_str::<Value>(&content).map_err(|e| e.to_string())
+        };
+        let table = match parsed {
+            Ok(Value::Object(table)) => table,
+            Ok(Value::Null) => return,
+            Ok(_) => {
+                self.errors
+                    .push(format!("config file {} should be a table", path));
+                return;
+            }
+            Err(e) => {
+                self.errors
+                    .push(format!("can't parse config file {}: {}", path, e));
+                return;
+            }
         };
 
-        Config {
-            port,
-            db_conn_string,
+        for (key, value) in table {
+            let setting = match SETTINGS.iter().find(|s| s.key == key) {
+                Some(setting) => setting,
+                None => {
+                    self.errors
+                        .push(format!("unknown setting {} in config file {}", key, path));
+                    continue;
+                }
+            };
+            let value = match value {
+                Value::Null => continue,
+                Value::String(s) => s,
+                // Lists & tables are passed as JSON, same as in env vars
+                other => other.to_string(),
+            };
+            self.set(setting.key, value, format!("config file {}", path));
+        }
+    }
+
+    fn load_env(&mut self, vars: EnvLookup) {
+        for setting in SETTINGS {
+            // Empty value is what unset variable usually looks like in
+            // k8s manifests & compose files
+            if let Some(value) = vars(setting.env) {
+                if !value.is_empty() {
+                    self.set(setting.key, value, format!("env {}", setting.env));
+                }
+            }
+        }
+    }
+
+    fn load_args(&mut self, args: &[(String, String)]) {
+        for (flag, value) in args {
+            match SETTINGS.iter().find(|s| flag_name(s.key) == *flag) {
+                Some(setting) => self.set(setting.key, value.clone(), format!("flag {}", flag)),
+                None => self.errors.push(format!("unknown flag {}", flag)),
+            }
+        }
+    }
+
+    fn parse_with<T, F>(&mut self, key: &'static str, parse: F) -> Option<T>
+    where
+        F: FnOnce(&str) -> std::result::Result<T, String>,
+    {
+        let raw = self.values.get(key)?;
+        match parse(&raw.value) {
+            Ok(value) => Some(value),
+            Err(e) => {
+                let error = format!("{} from {} is invalid: {}", key, raw.origin, e);
+                self.errors.push(error);
+                None
+            }
+        }
+    }
+
+    fn optional<T>(&mut self, key: &'static str) -> Option<T>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        self.parse_with(key, |v| v.parse::<T>().map_err(|e| e.to_string()))
+    }
+
+    fn or<T>(&mut self, key: &'static str, default: T) -> T
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        self.optional(key).unwrap_or(default)
+    }
+
+    fn required<T>(&mut self, key: &'static str) -> T
+    where
+        T: FromStr + Default,
+        T::Err: Display,
+    {
+        if !self.values.contains_key(key) {
+            let setting = SETTINGS.iter().find(|s| s.key == key).unwrap();
+            self.errors.push(format!(
+                "{} is not set, use env {} or flag {}",
+                key,
+                setting.env,
+                flag_name(key)
+            ));
+        }
+        self.optional(key).unwrap_or_default()
+    }
+
+    fn json<T: DeserializeOwned>(&mut self, key: &'static str) -> Option<T> {
+        self.parse_with(key, |v| serde_json::from_str(v).map_err(|e| e.to_string()))
+    }
+
+    /// Either JSON list or comma separated values
+    fn list(&mut self, key: &'static str) -> Option<Vec<String>> {
+        self.parse_with(key, |v| {
+            if v.trim_start().starts_with('[') {
+                serde_json::from_str(v).map_err(|e| e.to_string())
+            } else {
+                Ok(v.split(',')
+                    .map(|s| s.trim().to_owned())
+                    .filter(|s| !s.is_empty())
+                    .collect())
+            }
+        })
+    }
+}
+
+impl Config {
+    /// Loads config from optional file, then env vars, then CLI flags
+    /// (`--key value` or `--key=value`), each overriding previous one.
+    /// All invalid settings are reported at once.
+    pub fn load(args: &[String]) -> Result<Config> {
+        Config::load_with(args, false, &|name| env::var(name).ok())
+    }
+
+    /// Same as `load`, but also requires settings which server needs
+    pub fn load_server(args: &[String]) -> Result<Config> {
+        Config::load_with(args, true, &|name| env::var(name).ok())
+    }
+
+    fn load_with(args: &[String], server: bool, vars: EnvLookup) -> Result<Config> {
+        let mut layers = Layers::default();
+
+        let mut flags = vec![];
+        let mut config_file = vars(CONFIG_FILE_ENV).filter(|x| !x.is_empty());
+        let mut iter = args.iter();
+        while let Some(arg) = iter.next() {
+            let (flag, value) = match arg.split_once('=') {
+                Some((flag, value)) => (flag.to_owned(), Some(value.to_owned())),
+                None => (arg.clone(), iter.next().cloned()),
+            };
+            match value {
+                Some(value) if flag == "--config" => config_file = Some(value),
+                Some(value) => flags.push((flag, value)),
+                None => layers.errors.push(format!("missing value for {}", flag)),
+            }
+        }
+
+        if let Some(path) = &config_file {
+            layers.load_file(path);
+        }
+        layers.load_env(vars);
+        layers.load_args(&flags);
+
+        let env = layers.or("env", "develop".to_owned());
+        let config = Config {
+            port: if server {
+                Some(layers.required("port"))
+            } else {
+                layers.optional("port")
+            },
+            metrics_port: layers.optional("metrics_port"),
+            tls_cert: layers.optional("tls_cert"),
+            tls_key: layers.optional("tls_key"),
+            tls_client_ca: layers.optional("tls_client_ca"),
+            db_conn_string: layers.required("db_conn_string"),
+            db_ssl_root_cert: layers.optional("db_ssl_root_cert"),
+            db_ssl_cert: layers.optional("db_ssl_cert"),
+            db_ssl_key: layers.optional("db_ssl_key"),
+            db_pool_max_open: layers.or("db_pool_max_open", 32),
+            db_pool_max_idle: layers.or("db_pool_max_idle", 8),
+            db_pool_timeout_seconds: layers.or("db_pool_timeout_seconds", 15),
+            db_retry_max_attempts: layers.or("db_retry_max_attempts", 3),
+            db_retry_base_delay_ms: layers.or("db_retry_base_delay_ms", 50),
+            db_retry_max_delay_ms: layers.or("db_retry_max_delay_ms", 1000),
+            db_retry_budget_ratio: layers.or("db_retry_budget_ratio", 0.2),
+            db_wait_timeout_seconds: layers.or("db_wait_timeout_seconds", 60),
+            db_wait_max_delay_seconds: layers.or("db_wait_max_delay_seconds", 5),
+            db_breaker_failure_threshold: layers.or("db_breaker_failure_threshold", 5),
+            db_breaker_open_seconds: layers.or("db_breaker_open_seconds", 10),
+            cors_allowed_origins: layers
+                .list("cors_allowed_origins")
+                .unwrap_or_else(|| vec!["*".to_owned()]),
+            cors_allowed_methods: layers.list("cors_allowed_methods").unwrap_or_else(|| {
+                ["GET", "POST", "PUT", "DELETE"]
+                    .iter()
+                    .map(|m| m.to_string())
+                    .collect()
+            }),
+            // What user routes are called with
+            cors_allowed_headers: layers.list("cors_allowed_headers").unwrap_or_else(|| {
+                ["content-type", "authorization", "x-api-key"]
+                    .iter()
+                    .map(|h| h.to_string())
+                    .collect()
+            }),
+            // What clients need to correlate requests & back off
+            cors_exposed_headers: layers.list("cors_exposed_headers").unwrap_or_else(|| {
+                [
+                    "x-request-id",
+                    "retry-after",
+                    "ratelimit-limit",
+                    "ratelimit-remaining",
+                    "ratelimit-reset",
+                ]
+                .iter()
+                .map(|h| h.to_string())
+                .collect()
+            }),
+            cors_allow_credentials: layers.or("cors_allow_credentials", false),
+            cors_max_age_seconds: layers.or("cors_max_age_seconds", 600),
+            // Probes & scrapes come without API key
+            auth_exempt_paths: layers.list("auth_exempt_paths").unwrap_or_else(|| {
+                ["/health", "/livez", "/readyz", "/startupz", "/metrics"]
+                    .iter()
+                    .map(|p| p.to_string())
+                    .collect()
+            }),
+            jwt_jwks: layers.optional("jwt_jwks"),
+            jwt_issuer: layers.optional("jwt_issuer"),
+            jwt_audience: layers.optional("jwt_audience"),
+            jwt_roles_claim: layers.or("jwt_roles_claim", "roles".to_owned()),
+            session_secret: layers.optional("session_secret"),
+            session_ttl_seconds: layers.or("session_ttl_seconds", 86400),
+            login_max_failed_attempts: layers.or("login_max_failed_attempts", 5),
+            login_lockout_seconds: layers.or("login_lockout_seconds", 900),
+            // Fault injection is meant for alert rehearsal only, so it can't
+            // be turned on in production even by mistake
+            fault_injection: layers.or("fault_injection", false) && env != "production",
             env,
+            log_level: layers.or("log_level", LogLevel::Info),
+            pushgateway_url: layers.optional("pushgateway_url"),
+            pushgateway_timeout_seconds: layers.or("pushgateway_timeout_seconds", 5),
+            otlp_endpoint: layers.optional("otlp_endpoint"),
+            trace_sampling_ratio: layers.or("trace_sampling_ratio", 1.0),
+            shutdown_drain_seconds: layers.or("shutdown_drain_seconds", 5),
+            shutdown_timeout_seconds: layers.or("shutdown_timeout_seconds", 20),
+            slos: layers.json("slos").unwrap_or_else(default_slos),
+            address_rate_limits: layers.json("address_rate_limits").unwrap_or_default(),
+            rate_limits: layers.json("rate_limits").unwrap_or_default(),
+            trusted_proxies: layers.list("trusted_proxies").unwrap_or_default(),
+            max_body_bytes: layers.or("max_body_bytes", 64 * 1024),
+            request_timeout_seconds: layers.or("request_timeout_seconds", 10),
+            route_limits: layers.json("route_limits").unwrap_or_default(),
+            max_in_flight_requests: layers.or("max_in_flight_requests", 512),
+            // Probes & scrapes are cheap, and failing them under load would
+            // get instance restarted or blind monitoring when it matters
+            uncapped_paths: layers.list("uncapped_paths").unwrap_or_else(|| {
+                ["/livez", "/readyz", "/startupz", "/metrics"]
+                    .iter()
+                    .map(|p| p.to_string())
+                    .collect()
+            }),
+            file: config_file,
+            args: args.to_vec(),
+        };
+
+        let mut errors = layers.errors;
+        errors.extend(config.validate());
+        if !errors.is_empty() {
+            return Err(InvalidConfig(errors));
+        }
+        Ok(config)
+    }
+
+    fn validate(&self) -> Vec<String> {
+        let mut errors = vec![];
+        if let Some(port) = self.port.filter(|p| self.metrics_port == Some(*p)) {
+            errors.push(format!("metrics_port {} should differ from port", port));
+        }
+        if self.tls_cert.is_some() != self.tls_key.is_some() {
+            errors.push("tls_cert & tls_key should be set together".to_owned());
+        }
+        if self.tls_client_ca.is_some() && self.tls_cert.is_none() {
+            errors.push("tls_client_ca requires tls_cert & tls_key".to_owned());
+        }
+        if self.db_ssl_cert.is_some() != self.db_ssl_key.is_some() {
+            errors.push("db_ssl_cert & db_ssl_key should be set together".to_owned());
+        }
+        if self.db_pool_max_open == 0 {
+            errors.push("db_pool_max_open should be positive".to_owned());
+        }
+        if self.db_pool_max_idle > self.db_pool_max_open {
+            errors.push(format!(
+                "db_pool_max_idle {} should not exceed db_pool_max_open {}",
+                self.db_pool_max_idle, self.db_pool_max_open
+            ));
+        }
+        for origin in &self.cors_allowed_origins {
+            let valid = origin == "*"
+                || ["http://", "https://"].iter().any(|scheme| {
+                    origin.strip_prefix(scheme).is_some_and(|host| {
+                        let host = host.strip_prefix("*.").unwrap_or(host);
+                        !host.is_empty() && !host.contains('/') && !host.contains('*')
+                    })
+                });
+            if !valid {
+                errors.push(format!(
+                    "cors_allowed_origins entry {} should be *, scheme://host[:port] or scheme://*.domain[:port]",
+                    origin
+                ));
+            }
+        }
+        if self.cors_allow_credentials && self.cors_allowed_origins.iter().any(|o| o == "*") {
+            errors.push(
+                "cors_allow_credentials requires cors_allowed_origins to be listed instead of *"
+                    .to_owned(),
+            );
+        }
+        for method in &self.cors_allowed_methods {
+            if Method::from_bytes(method.to_ascii_uppercase().as_bytes()).is_err() {
+                errors.push(format!(
+                    "cors_allowed_methods entry {} is not a method",
+                    method
+                ));
+            }
+        }
+        for header in &self.cors_allowed_headers {
+            if HeaderName::from_bytes(header.as_bytes()).is_err() {
+                errors.push(format!(
+                    "cors_allowed_headers entry {} is not a header name",
+                    header
+                ));
+            }
+        }
+        for header in &self.cors_exposed_headers {
+            if HeaderName::from_bytes(header.as_bytes()).is_err() {
+                errors.push(format!(
+                    "cors_exposed_headers entry {} is not a header name",
+                    header
+                ));
+            }
+        }
+        for (key, paths) in [
+            ("auth_exempt_paths", &self.auth_exempt_paths),
+            ("uncapped_paths", &self.uncapped_paths),
+        ] {
+            for path in paths {
+                if !path.starts_with('/') {
+                    errors.push(format!("{} entry {} should start with /", key, path));
+                }
+            }
+        }
+        if let Some(url) = &self.pushgateway_url {
+            if !url.starts_with("http://") && !url.starts_with("https://") {
+                errors.push(format!(
+                    "pushgateway_url {} should be http:// or https:// URL",
+                    url
+                ));
+            }
+        }
+        if self.pushgateway_timeout_seconds == 0 {
+            errors.push("pushgateway_timeout_seconds should be positive".to_owned());
+        }
+        if self.jwt_jwks.is_some() && (self.jwt_issuer.is_none() || self.jwt_audience.is_none()) {
+            errors.push("jwt_jwks requires jwt_issuer & jwt_audience".to_owned());
         }
+        if self.jwt_issuer.as_deref() == Some(crate::auth::session::SESSION_ISSUER) {
+            errors.push(format!(
+                "jwt_issuer {} is reserved for session tokens",
+                crate::auth::session::SESSION_ISSUER
+            ));
+        }
+        if self.db_retry_max_attempts == 0 {
+            errors.push("db_retry_max_attempts should be positive".to_owned());
+        }
+        if self.db_retry_base_delay_ms > self.db_retry_max_delay_ms {
+            errors.push(format!(
+                "db_retry_base_delay_ms {} should not exceed db_retry_max_delay_ms {}",
+                self.db_retry_base_delay_ms, self.db_retry_max_delay_ms
+            ));
+        }
+        if !(0.0..=1.0).contains(&self.db_retry_budget_ratio) {
+            errors.push(format!(
+                "db_retry_budget_ratio {} is not in [0, 1]",
+                self.db_retry_budget_ratio
+            ));
+        }
+        if self.db_wait_max_delay_seconds == 0 {
+            errors.push("db_wait_max_delay_seconds should be positive".to_owned());
+        }
+        if self.db_breaker_failure_threshold == 0 {
+            errors.push("db_breaker_failure_threshold should be positive".to_owned());
+        }
+        if self.db_breaker_open_seconds == 0 {
+            errors.push("db_breaker_open_seconds should be positive".to_owned());
+        }
+        if self.session_secret.as_ref().is_some_and(|s| s.len() < 32) {
+            errors.push("session_secret should be at least 32 characters long".to_owned());
+        }
+        if self.session_ttl_seconds == 0 {
+            errors.push("session_ttl_seconds should be positive".to_owned());
+        }
+        if self.login_max_failed_attempts == 0 {
+            errors.push("login_max_failed_attempts should be positive".to_owned());
+        }
+        if !(0.0..=1.0).contains(&self.trace_sampling_ratio) {
+            errors.push(format!(
+                "trace_sampling_ratio {} is not in [0, 1]",
+                self.trace_sampling_ratio
+            ));
+        }
+        if self.shutdown_drain_seconds >= self.shutdown_timeout_seconds {
+            errors.push(format!(
+                "shutdown_drain_seconds {} should be less than shutdown_timeout_seconds {}",
+                self.shutdown_drain_seconds, self.shutdown_timeout_seconds
+            ));
+        }
+        if self.request_timeout_seconds == 0 {
+            errors.push("request_timeout_seconds should be positive".to_owned());
+        }
+        if self.max_in_flight_requests == 0 {
+            errors.push("max_in_flight_requests should be positive".to_owned());
+        }
+        for route in &self.route_limits {
+            if route.timeout_seconds == Some(0) {
+                errors.push(format!(
+                    "route limit for {} timeout_seconds should be positive",
+                    route.path
+                ));
+            }
+        }
+        for limit in self.address_rate_limits.iter().chain(&self.rate_limits) {
+            if let Err(e) = limit.validate() {
+                errors.push(e);
+            }
+        }
+        for proxy in &self.trusted_proxies {
+            if crate::rate_limit::parse_proxy(proxy).is_none() {
+                errors.push(format!(
+                    "trusted_proxies entry {} is not address or network",
+                    proxy
+                ));
+            }
+        }
+        for (i, slo) in self.slos.iter().enumerate() {
+            if let Err(e) = slo.validate() {
+                errors.push(e);
+            }
+            if self.slos[..i].iter().any(|s| s.name == slo.name) {
+                errors.push(format!("SLO {} is defined more than once", slo.name));
+            }
+        }
+        errors
+    }
+
+    pub fn file(&self) -> Option<&str> {
+        self.file.as_deref()
+    }
+
+    /// Short hash of effective config, to tell which config instance runs.
+    /// It's exported in metrics, so secrets are redacted before hashing.
+    pub fn hash(&self) -> String {
+        let mut hasher = DefaultHasher::new();
+        self.to_redacted_toml().hash(&mut hasher);
+        format!("{:016x}", hasher.finish())
+    }
+
+    /// Loads config again from the same file, env & flags. Settings which
+    /// can't be changed without restart keep their current values, and
+    /// are returned along with new config, so they can be reported.
+    pub fn reload(&self) -> Result<(Config, Vec<&'static str>)> {
+        let loaded = Config::load_server(&self.args)?;
+        let current_values = serde_json::to_value(self).expect("config can be serialised");
+        let loaded_values = serde_json::to_value(&loaded).expect("config can be serialised");
+        let ignored = SETTINGS
+            .iter()
+            .filter(|s| !s.reloadable && current_values[s.key] != loaded_values[s.key])
+            .map(|s| s.key)
+            .collect();
+
+        let config = Config {
+            db_pool_max_idle: loaded.db_pool_max_idle,
+            log_level: loaded.log_level,
+            cors_allowed_origins: loaded.cors_allowed_origins,
+            cors_allowed_methods: loaded.cors_allowed_methods,
+            cors_allowed_headers: loaded.cors_allowed_headers,
+            cors_exposed_headers: loaded.cors_exposed_headers,
+            cors_allow_credentials: loaded.cors_allow_credentials,
+            cors_max_age_seconds: loaded.cors_max_age_seconds,
+            auth_exempt_paths: loaded.auth_exempt_paths,
+            address_rate_limits: loaded.address_rate_limits,
+            rate_limits: loaded.rate_limits,
+            max_body_bytes: loaded.max_body_bytes,
+            request_timeout_seconds: loaded.request_timeout_seconds,
+            route_limits: loaded.route_limits,
+            uncapped_paths: loaded.uncapped_paths,
+            ..self.clone()
+        };
+        // New values could be valid only along with ignored ones
+        let errors = config.validate();
+        if !errors.is_empty() {
+            return Err(InvalidConfig(errors));
+        }
+        Ok((config, ignored))
+    }
+
+    /// Effective config in TOML, so it can be used as config file.
+    /// Secrets are redacted.
+    pub fn to_redacted_toml(&self) -> String {
+        let mut value = serde_json::to_value(self).expect("config can be serialised");
+        if let Value::Object(table) = &mut value {
+            // TOML has no null
+            table.retain(|_, v| !v.is_null());
+            for setting in SETTINGS.iter().filter(|s| s.secret) {
+                if let Some(v) = table.get_mut(setting.key) {
+                    *v = Value::String(REDACTED.to_owned());
+                }
+            }
+            for key in ["slos", "address_rate_limits", "rate_limits", "route_limits"] {
+                for item in table
+                    .get_mut(key)
+                    .and_then(|v| v.as_array_mut())
+                    .into_iter()
+                    .flatten()
+                {
+                    if let Value::Object(item) = item {
+                        item.retain(|_, v| !v.is_null());
+                    }
+                }
+            }
+        }
+        toml::to_string_pretty(&value).expect("config can be written as TOML")
+    }
+
+    /// Loads config from CLI args with given loader, exiting with all
+    /// errors listed if config is invalid. With `--print-config` it prints
+    /// effective config & exits.
+    pub fn load_or_exit(args: &[String], load: fn(&[String]) -> Result<Config>) -> Config {
+        let print_config = args.iter().any(|a| a == "--print-config");
+        let args: Vec<String> = args
+            .iter()
+            .filter(|a| *a != "--print-config")
+            .cloned()
+            .collect();
+
+        let config = match load(&args) {
+            Ok(config) => config,
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(2);
+            }
+        };
+        if print_config {
+            print!("{}", config.to_redacted_toml());
+            std::process::exit(0);
+        }
+        config
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes config file unique to test & process
+    fn config_file(name: &str, content: &str) -> String {
+        let path = env::temp_dir().join(format!("config-{}-{}.toml", std::process::id(), name));
+        fs::write(&path, content).expect("config file can be written");
+        path.to_string_lossy().into_owned()
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        let mut all = vec!["--port=3000", "--db-conn-string=postgres://u:secret@db/app"];
+        all.extend_from_slice(args);
+        all.iter().map(|a| a.to_string()).collect()
+    }
+
+    fn errors(args: &[String]) -> Vec<String> {
+        errors_of(Config::load(args))
+    }
+
+    fn errors_of(loaded: Result<Config>) -> Vec<String> {
+        match loaded {
+            Err(InvalidConfig(errors)) => errors,
+            other => panic!("expected invalid config, got {:?}", other.map(|c| c.hash())),
+        }
+    }
+
+    #[test]
+    fn layers_override_file_env_flags() {
+        let file = config_file(
+            "layers",
+            "db_pool_max_open = 10\ndb_pool_max_idle = 1\nlog_level = \"debug\"\n",
+        );
+        let vars = |name: &str| (name == "DB_POOL_MAX_IDLE").then(|| "4".to_owned());
+        let args = args(&["--config", &file, "--log-level", "warn"]);
+        let config = Config::load_with(&args, false, &vars).unwrap();
+
+        assert_eq!(config.db_pool_max_open, 10);
+        assert_eq!(config.db_pool_max_idle, 4);
+        assert_eq!(config.log_level, LogLevel::Warn);
+        assert_eq!(config.file(), Some(file.as_str()));
+        fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn port_is_required_by_server_only() {
+        let args = vec!["--db-conn-string=postgres://db/app".to_owned()];
+        assert!(Config::load_with(&args, false, &|_| None).is_ok());
+        let errors = errors_of(Config::load_with(&args, true, &|_| None));
+        assert!(errors[0].starts_with("port is not set"), "{:?}", errors);
+    }
+
+    #[test]
+    fn lists_accept_commas_and_json() {
+        let config = Config::load(&args(&[
+            "--cors-allowed-methods=get, post,",
+            "--auth-exempt-paths=[\"/health\",\"/a,b\"]",
+        ]))
+        .unwrap();
+        assert_eq!(config.cors_allowed_methods, ["get", "post"]);
+        assert_eq!(config.auth_exempt_paths, ["/health", "/a,b"]);
+    }
+
+    #[test]
+    fn reports_all_errors_with_origin() {
+        let file = config_file("errors", "unknown_setting = 1\n");
+        let errors = errors(&args(&[
+            "--config",
+            &file,
+            "--db-pool-max-open=many",
+            "--nope=1",
+            "--pushgateway-url=ftp://gateway",
+            "--trusted-proxies=10.0.0.0/33",
+        ]));
+        assert!(errors
+            .iter()
+            .any(|e| e.starts_with("unknown setting unknown_setting")));
+        assert!(errors
+            .iter()
+            .any(|e| e.starts_with("db_pool_max_open from flag --db-pool-max-open is invalid")));
+        assert!(errors.contains(&"unknown flag --nope".to_owned()));
+        assert!(errors.iter().any(|e| e.starts_with("pushgateway_url")));
+        assert!(errors.iter().any(|e| e.starts_with("trusted_proxies")));
+        fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn rejects_inconsistent_settings() {
+        let errors = errors(&args(&[
+            "--metrics-port=3000",
+            "--tls-cert=cert.pem",
+            "--db-pool-max-open=2",
+            "--db-pool-max-idle=3",
+            "--cors-allow-credentials=true",
+            "--jwt-issuer=service:session",
+            "--uncapped-paths=/livez,metrics",
+        ]));
+        assert_eq!(errors.len(), 6, "{:?}", errors);
+    }
+
+    #[test]
+    fn hash_ignores_secrets() {
+        let config = Config::load(&args(&[])).unwrap();
+        let other_secret =
+            Config::load(&args(&["--db-conn-string=postgres://u:other@db/app"])).unwrap();
+        let other_level = Config::load(&args(&["--log-level=debug"])).unwrap();
+
+        assert_eq!(config.hash(), other_secret.hash());
+        assert_ne!(config.hash(), other_level.hash());
+        assert!(!config.to_redacted_toml().contains("secret"));
+    }
+
+    #[test]
+    fn reload_keeps_settings_requiring_restart() {
+        let file = config_file("reload", "db_pool_max_open = 10\nlog_level = \"info\"\n");
+        let config = Config::load(&args(&["--config", &file])).unwrap();
+        fs::write(&file, "db_pool_max_open = 20\nlog_level = \"debug\"\n").unwrap();
+
+        let (reloaded, ignored) = config.reload().unwrap();
+        assert_eq!(reloaded.db_pool_max_open, 10);
+        assert_eq!(reloaded.log_level, LogLevel::Debug);
+        assert_eq!(ignored, ["db_pool_max_open"]);
+        fs::remove_file(file).unwrap();
     }
 }
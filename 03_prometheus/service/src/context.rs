@@ -0,0 +1,163 @@
+use std::cell::{Cell, RefCell};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use uuid::Uuid;
+use warp::http::{HeaderValue, Request, Response};
+
+use crate::auth::Principal;
+use crate::config::LogLevel;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+// Longer ids are most likely garbage, so we'd rather generate our own
+const REQUEST_ID_MAX_LENGTH: usize = 128;
+
+/// Client ids end up in logs, so only plain characters are accepted
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= REQUEST_ID_MAX_LENGTH
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'.' | b'_' | b'-'))
+}
+
+// Can be changed on config reload, so it's kept outside of config
+static LOG_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+
+#[derive(Clone, Debug)]
+pub struct RequestContext {
+    pub request_id: String,
+    pub remote_addr: Option<SocketAddr>,
+    /// Subject of client certificate, if request came over mutual TLS
+    pub peer_identity: Option<String>,
+    /// Set once request is authenticated with API key or token
+    pub principal: RefCell<Option<Principal>>,
+    pub fault_injected: Cell<bool>,
+}
+
+tokio::task_local! {
+    static CONTEXT: RequestContext;
+}
+
+impl RequestContext {
+    pub fn of<B>(
+        req: &Request<B>,
+        remote_addr: Option<SocketAddr>,
+        peer_identity: Option<String>,
+    ) -> RequestContext {
+        let request_id = req
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.trim())
+            .filter(|v| is_valid_request_id(v))
+            .map(|v| v.to_owned())
+            .unwrap_or_else(|| Uuid::new_v4().to_string());
+
+        RequestContext {
+            request_id,
+            remote_addr,
+            peer_identity,
+            principal: RefCell::new(None),
+            fault_injected: Cell::new(false),
+        }
+    }
+}
+
+/// Runs request handling future with given context available, and attaches
+/// request id to the response, so client can refer to it later.
+pub async fn scope<F, B, E>(ctx: RequestContext, fut: F) -> Result<Response<B>, E>
+where
+    F: Future<Output = Result<Response<B>, E>>,
+{
+    let header = HeaderValue::from_str(&ctx.request_id).ok();
+    let mut res = CONTEXT.scope(ctx, fut).await?;
+    if let Some(header) = header {
+        res.headers_mut().insert(REQUEST_ID_HEADER, header);
+    }
+    Ok(res)
+}
+
+/// Id of request being currently handled, if any
+pub fn request_id() -> Option<String> {
+    CONTEXT.try_with(|ctx| ctx.request_id.clone()).ok()
+}
+
+pub fn remote_addr() -> Option<SocketAddr> {
+    CONTEXT.try_with(|ctx| ctx.remote_addr).ok().flatten()
+}
+
+/// Identity of caller, either authenticated one like `api-key:billing`, or
+/// client certificate subject like `CN=billing,O=internal`
+pub fn caller_identity() -> Option<String> {
+    CONTEXT
+        .try_with(|ctx| match &*ctx.principal.borrow() {
+            Some(principal) => Some(principal.identity.clone()),
+            None => ctx.peer_identity.clone(),
+        })
+        .ok()
+        .flatten()
+}
+
+pub fn set_principal(principal: Principal) {
+    CONTEXT
+        .try_with(|ctx| ctx.principal.replace(Some(principal)))
+        .ok();
+}
+
+pub fn principal() -> Option<Principal> {
+    CONTEXT
+        .try_with(|ctx| ctx.principal.borrow().clone())
+        .ok()
+        .flatten()
+}
+
+/// Marks response as affected by fault injection, so it can be told
+/// apart from real ones in metrics
+pub fn mark_fault_injected() {
+    CONTEXT.try_with(|ctx| ctx.fault_injected.set(true)).ok();
+}
+
+pub fn is_fault_injected() -> bool {
+    CONTEXT
+        .try_with(|ctx| ctx.fault_injected.get())
+        .unwrap_or(false)
+}
+
+pub fn set_log_level(level: LogLevel) {
+    LOG_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn log_enabled(level: LogLevel) -> bool {
+    level as u8 <= LOG_LEVEL.load(Ordering::Relaxed)
+}
+
+/// Prefix for log lines, so they can be correlated with request
+pub fn log_prefix() -> String {
+    match request_id() {
+        Some(id) => format!("[request_id={}] ", id),
+        None => String::new(),
+    }
+}
+
+/// Same as `println!`, but tags line with current request id. Printed
+/// on `info` log level & above.
+macro_rules! log_println {
+    ($($arg:tt)*) => {
+        if $crate::context::log_enabled($crate::config::LogLevel::Info) {
+            println!("{}{}", $crate::context::log_prefix(), format_args!($($arg)*))
+        }
+    };
+}
+
+/// Same as `eprintln!`, but tags line with current request id
+macro_rules! log_eprintln {
+    ($($arg:tt)*) => {
+        eprintln!("{}{}", $crate::context::log_prefix(), format_args!($($arg)*))
+    };
+}
+
+pub(crate) use log_eprintln;
+pub(crate) use log_println;
@@ -0,0 +1,391 @@
+use std::convert::Infallible;
+use std::sync::Arc;
+
+use warp::http::header::{
+    AsHeaderName, HeaderMap, HeaderValue, ACCESS_CONTROL_ALLOW_CREDENTIALS,
+    ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN,
+    ACCESS_CONTROL_EXPOSE_HEADERS, ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_HEADERS,
+    ACCESS_CONTROL_REQUEST_METHOD, ORIGIN, VARY,
+};
+use warp::http::{Method, StatusCode};
+use warp::reply::Response;
+use warp::{Filter, Reply};
+
+use crate::config::Config;
+use crate::context::log_println;
+use crate::error::Error::CorsForbidden;
+
+/// Entry of `cors_allowed_origins`
+enum AllowedOrigin {
+    Any,
+    Exact(String),
+    /// `scheme://*.domain[:port]`, matches subdomains of any depth but not
+    /// domain itself
+    Subdomains {
+        prefix: String,
+        suffix: String,
+    },
+}
+
+impl AllowedOrigin {
+    fn of(origin: &str) -> AllowedOrigin {
+        let origin = origin.to_ascii_lowercase();
+        if origin == "*" {
+            return AllowedOrigin::Any;
+        }
+        match origin.split_once("://*.") {
+            Some((scheme, domain)) => AllowedOrigin::Subdomains {
+                prefix: format!("{}://", scheme),
+                suffix: format!(".{}", domain),
+            },
+            None => AllowedOrigin::Exact(origin),
+        }
+    }
+
+    fn matches(&self, origin: &str) -> bool {
+        let origin = origin.to_ascii_lowercase();
+        match self {
+            AllowedOrigin::Any => true,
+            AllowedOrigin::Exact(allowed) => *allowed == origin,
+            AllowedOrigin::Subdomains { prefix, suffix } => origin
+                .strip_prefix(prefix.as_str())
+                .and_then(|host| host.strip_suffix(suffix.as_str()))
+                .is_some_and(|sub| {
+                    !sub.is_empty()
+                        && sub
+                            .chars()
+                            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+                }),
+        }
+    }
+}
+
+struct Inner {
+    origins: Vec<AllowedOrigin>,
+    methods: Vec<String>,
+    headers: Vec<String>,
+    exposed_headers: Vec<String>,
+    allow_credentials: bool,
+    max_age_seconds: u64,
+}
+
+/// CORS policy from config. Rebuilt along with routes on reload.
+#[derive(Clone)]
+pub struct Policy {
+    inner: Arc<Inner>,
+}
+
+fn header_str(headers: &HeaderMap, name: impl AsHeaderName) -> Option<&str> {
+    headers.get(name).and_then(|v| v.to_str().ok())
+}
+
+fn header_value(value: &str) -> HeaderValue {
+    HeaderValue::from_str(value).expect("CORS header value can be created")
+}
+
+impl Policy {
+    pub fn of(config: &Config) -> Policy {
+        Policy {
+            inner: Arc::new(Inner {
+                origins: config
+                    .cors_allowed_origins
+                    .iter()
+                    .map(|o| AllowedOrigin::of(o))
+                    .collect(),
+                methods: config
+                    .cors_allowed_methods
+                    .iter()
+                    .map(|m| m.to_ascii_uppercase())
+                    .collect(),
+                headers: config
+                    .cors_allowed_headers
+                    .iter()
+                    .map(|h| h.to_ascii_lowercase())
+                    .collect(),
+                exposed_headers: config
+                    .cors_exposed_headers
+                    .iter()
+                    .map(|h| h.to_ascii_lowercase())
+                    .collect(),
+                allow_credentials: config.cors_allow_credentials,
+                max_age_seconds: config.cors_max_age_seconds,
+            }),
+        }
+    }
+
+    fn allows_origin(&self, origin: &str) -> bool {
+        self.inner.origins.iter().any(|o| o.matches(origin))
+    }
+
+    /// Whether any origin gets `*`. Wildcard can't be used along with
+    /// credentials.
+    fn is_wildcard(&self) -> bool {
+        !self.inner.allow_credentials
+            && self
+                .inner
+                .origins
+                .iter()
+                .any(|o| matches!(o, AllowedOrigin::Any))
+    }
+
+    /// Unless policy is wildcard, response depends on origin, including
+    /// whether it has CORS headers at all, so caches have to tell them apart
+    fn add_vary(&self, headers: &mut HeaderMap) {
+        if !self.is_wildcard() {
+            headers.append(VARY, HeaderValue::from_static("origin"));
+        }
+    }
+
+    /// Adds headers which let browser read response from allowed origin
+    fn add_origin_headers(&self, headers: &mut HeaderMap, origin: &str) {
+        if self.is_wildcard() {
+            headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_static("*"));
+        } else {
+            headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, header_value(origin));
+        }
+        if self.inner.allow_credentials {
+            headers.insert(
+                ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                HeaderValue::from_static("true"),
+            );
+        }
+    }
+
+    /// Only matters for actual responses, not preflights
+    fn add_exposed_headers(&self, headers: &mut HeaderMap) {
+        if !self.inner.exposed_headers.is_empty() {
+            headers.insert(
+                ACCESS_CONTROL_EXPOSE_HEADERS,
+                header_value(&self.inner.exposed_headers.join(", ")),
+            );
+        }
+    }
+
+    fn check_preflight(&self, headers: &HeaderMap) -> std::result::Result<(), String> {
+        let origin = header_str(headers, ORIGIN).unwrap_or_default();
+        if !self.allows_origin(origin) {
+            return Err(format!("origin {} is not allowed", origin));
+        }
+        let method = header_str(headers, ACCESS_CONTROL_REQUEST_METHOD).unwrap_or_default();
+        if !self.inner.methods.iter().any(|m| m == method) {
+            return Err(format!("method {} is not allowed", method));
+        }
+        let requested_headers = header_str(headers, ACCESS_CONTROL_REQUEST_HEADERS)
+            .unwrap_or_default()
+            .split(',')
+            .map(|h| h.trim().to_ascii_lowercase())
+            .filter(|h| !h.is_empty());
+        for header in requested_headers {
+            if !self.inner.headers.contains(&header) {
+                return Err(format!("header {} is not allowed", header));
+            }
+        }
+        Ok(())
+    }
+
+    fn preflight(&self, headers: &HeaderMap) -> Response {
+        if let Err(e) = self.check_preflight(headers) {
+            log_println!("CORS preflight rejected: {}", e);
+            let mut res = CorsForbidden(e).into_response();
+            self.add_vary(res.headers_mut());
+            return res;
+        }
+        let mut res = StatusCode::NO_CONTENT.into_response();
+        let res_headers = res.headers_mut();
+        let origin = header_str(headers, ORIGIN).unwrap_or_default();
+        self.add_vary(res_headers);
+        self.add_origin_headers(res_headers, origin);
+        res_headers.insert(
+            ACCESS_CONTROL_ALLOW_METHODS,
+            header_value(&self.inner.methods.join(", ")),
+        );
+        if !self.inner.headers.is_empty() {
+            res_headers.insert(
+                ACCESS_CONTROL_ALLOW_HEADERS,
+                header_value(&self.inner.headers.join(", ")),
+            );
+        }
+        res_headers.insert(
+            ACCESS_CONTROL_MAX_AGE,
+            HeaderValue::from(self.inner.max_age_seconds),
+        );
+        res_headers.append(
+            VARY,
+            HeaderValue::from_static(
+                "access-control-request-method, access-control-request-headers",
+            ),
+        );
+        res
+    }
+}
+
+fn is_preflight(method: &Method, headers: &HeaderMap) -> bool {
+    method == Method::OPTIONS
+        && headers.contains_key(ORIGIN)
+        && headers.contains_key(ACCESS_CONTROL_REQUEST_METHOD)
+}
+
+/// Answers preflight requests without passing them to routes, since
+/// browsers send them without credentials. Other requests from allowed
+/// origins get CORS headers, ones from other origins are served without
+/// them, so browser doesn't let page read response.
+pub fn wrap<F, R>(
+    policy: &Policy,
+    filter: F,
+) -> impl Filter<Extract = (Response,), Error = Infallible> + Clone
+where
+    F: Filter<Extract = (R,), Error = Infallible> + Clone + Send + Sync + 'static,
+    R: Reply,
+{
+    let preflight_policy = policy.clone();
+    let preflight = warp::method().and(warp::header::headers_cloned()).and_then(
+        move |method: Method, headers: HeaderMap| {
+            let policy = preflight_policy.clone();
+            async move {
+                if is_preflight(&method, &headers) {
+                    Ok(policy.preflight(&headers))
+                } else {
+                    Err(warp::reject::not_found())
+                }
+            }
+        },
+    );
+
+    let policy = policy.clone();
+    let request =
+        warp::header::headers_cloned()
+            .and(filter)
+            .map(move |headers: HeaderMap, reply: R| {
+                let mut res = reply.into_response();
+                policy.add_vary(res.headers_mut());
+                if let Some(origin) = header_str(&headers, ORIGIN) {
+                    if policy.allows_origin(origin) {
+                        policy.add_origin_headers(res.headers_mut(), origin);
+                        policy.add_exposed_headers(res.headers_mut());
+                    }
+                }
+                res
+            });
+
+    preflight.or(request).unify()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(flags: &[&str]) -> Policy {
+        let mut args = vec!["--port=3000", "--db-conn-string=postgres://db/app"];
+        args.extend_from_slice(flags);
+        let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+        Policy::of(&Config::load(&args).unwrap())
+    }
+
+    async fn request(policy: &Policy, method: &str, headers: &[(&str, &str)]) -> Response {
+        let mut req = warp::test::request().method(method).path("/user");
+        for (name, value) in headers {
+            req = req.header(*name, *value);
+        }
+        req.reply(&wrap(policy, warp::any().map(|| "ok")))
+            .await
+            .map(warp::hyper::Body::from)
+    }
+
+    async fn preflight(policy: &Policy, origin: &str, method: &str, headers: &str) -> Response {
+        request(
+            policy,
+            "OPTIONS",
+            &[
+                ("origin", origin),
+                ("access-control-request-method", method),
+                ("access-control-request-headers", headers),
+            ],
+        )
+        .await
+    }
+
+    #[test]
+    fn origin_patterns() {
+        let exact = AllowedOrigin::of("https://App.example.com");
+        assert!(exact.matches("https://app.example.com"));
+        assert!(exact.matches("HTTPS://APP.EXAMPLE.COM"));
+        assert!(!exact.matches("http://app.example.com"));
+        assert!(!exact.matches("https://app.example.com:8443"));
+
+        let subdomains = AllowedOrigin::of("https://*.example.com");
+        assert!(subdomains.matches("https://app.example.com"));
+        assert!(subdomains.matches("https://a.b.example.com"));
+        assert!(!subdomains.matches("https://example.com"));
+        assert!(!subdomains.matches("https://evilexample.com"));
+        assert!(!subdomains.matches("https://evil.com/.example.com"));
+        assert!(!subdomains.matches("http://app.example.com"));
+
+        assert!(AllowedOrigin::of("*").matches("null"));
+    }
+
+    #[tokio::test]
+    async fn listed_origins_vary_by_origin() {
+        let policy = policy(&["--cors-allowed-origins=https://app.example.com"]);
+
+        let res = request(&policy, "GET", &[("origin", "https://app.example.com")]).await;
+        let headers = res.headers();
+        assert_eq!(
+            headers[ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://app.example.com"
+        );
+        assert_eq!(headers[VARY], "origin");
+        let exposed = headers[ACCESS_CONTROL_EXPOSE_HEADERS].to_str().unwrap();
+        assert!(exposed.contains("x-request-id") && exposed.contains("retry-after"));
+
+        // Rejected origin & no origin get no CORS headers, but still vary
+        for headers in [&[("origin", "https://evil.com")][..], &[]] {
+            let res = request(&policy, "GET", headers).await;
+            assert!(!res.headers().contains_key(ACCESS_CONTROL_ALLOW_ORIGIN));
+            assert!(!res.headers().contains_key(ACCESS_CONTROL_EXPOSE_HEADERS));
+            assert_eq!(res.headers()[VARY], "origin");
+        }
+    }
+
+    #[tokio::test]
+    async fn wildcard_does_not_vary() {
+        let policy = policy(&[]);
+        let res = request(&policy, "GET", &[("origin", "https://any.com")]).await;
+        assert_eq!(res.headers()[ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+        assert!(!res.headers().contains_key(VARY));
+    }
+
+    #[tokio::test]
+    async fn preflights() {
+        let policy = policy(&["--cors-allowed-origins=https://*.example.com"]);
+        let res = preflight(
+            &policy,
+            "https://app.example.com",
+            "PUT",
+            "Content-Type, X-API-Key",
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        let headers = res.headers();
+        assert_eq!(
+            headers[ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://app.example.com"
+        );
+        assert_eq!(
+            headers[ACCESS_CONTROL_ALLOW_METHODS],
+            "GET, POST, PUT, DELETE"
+        );
+        assert_eq!(headers[ACCESS_CONTROL_MAX_AGE], "600");
+        assert!(headers.get_all(VARY).iter().any(|v| v == "origin"));
+
+        for (origin, method, headers) in [
+            ("https://evil.com", "GET", ""),
+            ("https://app.example.com", "PATCH", ""),
+            ("https://app.example.com", "GET", "x-custom"),
+        ] {
+            let res = preflight(&policy, origin, method, headers).await;
+            assert_eq!(res.status(), StatusCode::FORBIDDEN);
+            assert!(!res.headers().contains_key(ACCESS_CONTROL_ALLOW_ORIGIN));
+            assert_eq!(res.headers()[VARY], "origin");
+        }
+    }
+}
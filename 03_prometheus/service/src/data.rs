@@ -8,8 +8,6 @@ pub struct User {
     pub lastname: String,
     pub email: String,
     pub phone: String,
-    pub created_at: DateTime<Utc>,
-    pub updated_at: DateTime<Utc>,
 }
 
 #[derive(Deserialize)]
@@ -75,3 +73,29 @@ impl UserUpdateResponse {
         }
     }
 }
+
+#[derive(Deserialize)]
+pub struct RegisterRequest {
+    pub login: String,
+    pub password: String,
+    #[serde(flatten)]
+    pub user: UserCreateRequest,
+}
+
+#[derive(Deserialize)]
+pub struct LoginRequest {
+    pub login: String,
+    pub password: String,
+}
+
+#[derive(Serialize)]
+pub struct LoginResponse {
+    pub token: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+pub struct PasswordChangeRequest {
+    pub current_password: String,
+    pub new_password: String,
+}
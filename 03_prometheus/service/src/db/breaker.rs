@@ -0,0 +1,237 @@
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use tokio::time::{Duration, Instant};
+
+use crate::config::Config;
+use crate::context::log_eprintln;
+use crate::error::Error::DBCircuitOpen;
+use crate::metrics;
+use crate::Result;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    /// Connections are requested as usual, counting failures in a row
+    Closed { failures: u32 },
+    /// Requests fail fast until given time
+    Open { until: Instant },
+    /// One request probes database, others fail fast meanwhile
+    HalfOpen,
+}
+
+impl State {
+    fn name(&self) -> &'static str {
+        match self {
+            State::Closed { .. } => "closed",
+            State::Open { .. } => "open",
+            State::HalfOpen => "half_open",
+        }
+    }
+
+    /// Value of `db_circuit_breaker_state` gauge
+    fn value(&self) -> i64 {
+        match self {
+            State::Closed { .. } => 0,
+            State::HalfOpen => 1,
+            State::Open { .. } => 2,
+        }
+    }
+}
+
+struct Breaker {
+    state: State,
+    failure_threshold: u32,
+    open_for: Duration,
+}
+
+impl Breaker {
+    fn new(failure_threshold: u32, open_for: Duration) -> Breaker {
+        Breaker {
+            state: State::Closed { failures: 0 },
+            failure_threshold,
+            open_for,
+        }
+    }
+}
+
+lazy_static! {
+    static ref BREAKER: Mutex<Breaker> = Mutex::new(Breaker::new(5, Duration::from_secs(10)));
+}
+
+pub fn configure(config: &Config) {
+    let mut breaker = BREAKER.lock().unwrap();
+    breaker.failure_threshold = config.db_breaker_failure_threshold;
+    breaker.open_for = Duration::from_secs(config.db_breaker_open_seconds);
+}
+
+fn set_state(breaker: &mut Breaker, state: State) {
+    if breaker.state.name() != state.name() {
+        log_eprintln!("DB circuit breaker is {}", state.name());
+    }
+    breaker.state = state;
+    metrics::DB_CIRCUIT_BREAKER_STATE.set(state.value());
+}
+
+/// Name of current state, for health check
+pub fn state() -> &'static str {
+    BREAKER.lock().unwrap().state.name()
+}
+
+/// Allows call to database. Dropping it without reporting outcome, e.g.
+/// when request is cancelled, lets next call probe instead.
+pub struct Permit {
+    breaker: &'static Mutex<Breaker>,
+    probe: bool,
+    done: bool,
+}
+
+impl Permit {
+    pub fn succeeded(mut self) {
+        self.done = true;
+        let mut breaker = self.breaker.lock().unwrap();
+        set_state(&mut breaker, State::Closed { failures: 0 });
+    }
+
+    pub fn failed(mut self) {
+        self.done = true;
+        let mut breaker = self.breaker.lock().unwrap();
+        let failures = match breaker.state {
+            State::Closed { failures } => failures + 1,
+            // Probe failed, or other calls were let through before opening
+            _ => breaker.failure_threshold,
+        };
+        let state = if failures >= breaker.failure_threshold {
+            State::Open {
+                until: Instant::now() + breaker.open_for,
+            }
+        } else {
+            State::Closed { failures }
+        };
+        set_state(&mut breaker, state);
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if self.probe && !self.done {
+            let mut breaker = self.breaker.lock().unwrap();
+            if breaker.state == State::HalfOpen {
+                set_state(
+                    &mut breaker,
+                    State::Open {
+                        until: Instant::now(),
+                    },
+                );
+            }
+        }
+    }
+}
+
+/// Permit to call database, or error with time to retry after if circuit
+/// is open or being probed
+pub fn permit() -> Result<Permit> {
+    permit_of(&BREAKER)
+}
+
+fn permit_of(mutex: &'static Mutex<Breaker>) -> Result<Permit> {
+    let mut breaker = mutex.lock().unwrap();
+    match breaker.state {
+        State::Closed { .. } => Ok(Permit {
+            breaker: mutex,
+            probe: false,
+            done: false,
+        }),
+        State::Open { until } if Instant::now() >= until => {
+            set_state(&mut breaker, State::HalfOpen);
+            Ok(Permit {
+                breaker: mutex,
+                probe: true,
+                done: false,
+            })
+        }
+        State::Open { until } => Err(DBCircuitOpen(until - Instant::now())),
+        // Probe should end soon, but it's not known when
+        State::HalfOpen => Err(DBCircuitOpen(Duration::from_secs(1))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+
+    const OPEN_FOR: Duration = Duration::from_millis(50);
+
+    /// Breaker of its own, so tests don't share state
+    fn breaker() -> &'static Mutex<Breaker> {
+        Box::leak(Box::new(Mutex::new(Breaker::new(3, OPEN_FOR))))
+    }
+
+    fn state(breaker: &Mutex<Breaker>) -> &'static str {
+        breaker.lock().unwrap().state.name()
+    }
+
+    fn open(breaker: &'static Mutex<Breaker>) {
+        for _ in 0..3 {
+            permit_of(breaker).unwrap().failed();
+        }
+        assert_eq!(state(breaker), "open");
+    }
+
+    #[test]
+    fn opens_after_failures_in_a_row() {
+        let breaker = breaker();
+        permit_of(breaker).unwrap().failed();
+        permit_of(breaker).unwrap().failed();
+        permit_of(breaker).unwrap().succeeded();
+        permit_of(breaker).unwrap().failed();
+        permit_of(breaker).unwrap().failed();
+        assert_eq!(state(breaker), "closed");
+
+        permit_of(breaker).unwrap().failed();
+        assert_eq!(state(breaker), "open");
+        match permit_of(breaker) {
+            Err(Error::DBCircuitOpen(retry_after)) => assert!(retry_after <= OPEN_FOR),
+            _ => panic!("expected circuit to be open"),
+        }
+    }
+
+    #[test]
+    fn single_probe_closes_circuit() {
+        let breaker = breaker();
+        open(breaker);
+        std::thread::sleep(OPEN_FOR);
+
+        let probe = permit_of(breaker).unwrap();
+        assert_eq!(state(breaker), "half_open");
+        assert!(matches!(permit_of(breaker), Err(Error::DBCircuitOpen(_))));
+        probe.succeeded();
+        assert_eq!(state(breaker), "closed");
+        assert!(permit_of(breaker).is_ok());
+    }
+
+    #[test]
+    fn failed_probe_reopens_circuit() {
+        let breaker = breaker();
+        open(breaker);
+        std::thread::sleep(OPEN_FOR);
+
+        permit_of(breaker).unwrap().failed();
+        assert_eq!(state(breaker), "open");
+        assert!(permit_of(breaker).is_err());
+    }
+
+    #[test]
+    fn dropped_probe_lets_next_call_probe() {
+        let breaker = breaker();
+        open(breaker);
+        std::thread::sleep(OPEN_FOR);
+
+        drop(permit_of(breaker).unwrap());
+        assert_eq!(state(breaker), "open");
+        let probe = permit_of(breaker).unwrap();
+        assert_eq!(state(breaker), "half_open");
+        probe.succeeded();
+        assert_eq!(state(breaker), "closed");
+    }
+}
@@ -0,0 +1,211 @@
+use chrono::{DateTime, Duration, Utc};
+use mobc_postgres::tokio_postgres::error::SqlState;
+use mobc_postgres::tokio_postgres::Row;
+use tracing::instrument;
+
+use super::{get_db_con, retry, row_to_user};
+use crate::data::{User, UserCreateRequest};
+use crate::error::Error::{DBQueryError, LoginTaken};
+use crate::{DBPool, Result};
+
+#[derive(Debug)]
+pub struct Credentials {
+    pub user_id: i32,
+    pub login: String,
+    pub password_hash: String,
+    pub locked_until: Option<DateTime<Utc>>,
+}
+
+fn row_to_credentials(row: &Row) -> Credentials {
+    Credentials {
+        user_id: row.get("user_id"),
+        login: row.get("login"),
+        password_hash: row.get("password_hash"),
+        locked_until: row.get("locked_until"),
+    }
+}
+
+/// Creates user record along with its credentials in one transaction, so
+/// there are no users which can't log in
+#[instrument(skip(db_pool, body, password_hash))]
+pub async fn create_user_with_credentials(
+    db_pool: &DBPool,
+    body: UserCreateRequest,
+    subject: &str,
+    login: &str,
+    password_hash: &str,
+) -> Result<User> {
+    let mut con = get_db_con(db_pool).await?;
+    let tx = con.transaction().await.map_err(DBQueryError)?;
+    let user_row = tx
+        .query_one(
+            "INSERT INTO users (username, firstname, lastname, email, phone, subject) VALUES ($1, $2, $3, $4, $5, $6) RETURNING *",
+            &[
+                &body.username,
+                &body.firstname,
+                &body.lastname,
+                &body.email,
+                &body.phone,
+                &subject,
+            ],
+        )
+        .await;
+    let user = match user_row {
+        Ok(row) => row_to_user(&row),
+        Err(e) if e.code() == Some(&SqlState::UNIQUE_VIOLATION) => {
+            return Err(LoginTaken(login.to_owned()))
+        }
+        Err(e) => return Err(DBQueryError(e)),
+    };
+    let res = tx
+        .execute(
+            "INSERT INTO credentials (user_id, login, password_hash) VALUES ($1, $2, $3)",
+            &[&user.id, &login, &password_hash],
+        )
+        .await;
+    match res {
+        Ok(_) => {}
+        Err(e) if e.code() == Some(&SqlState::UNIQUE_VIOLATION) => {
+            return Err(LoginTaken(login.to_owned()))
+        }
+        Err(e) => return Err(DBQueryError(e)),
+    }
+    tx.commit().await.map_err(DBQueryError)?;
+    Ok(user)
+}
+
+#[instrument(skip(db_pool))]
+pub async fn get_credentials(db_pool: &DBPool, login: &str) -> Result<Option<Credentials>> {
+    let con = get_db_con(db_pool).await?;
+    let row = con
+        .query_opt("SELECT * FROM credentials WHERE login = $1", &[&login])
+        .await
+        .map_err(DBQueryError)?;
+    Ok(row.map(|r| row_to_credentials(&r)))
+}
+
+#[instrument(skip(db_pool))]
+pub async fn get_credentials_by_user(
+    db_pool: &DBPool,
+    user_id: i32,
+) -> Result<Option<Credentials>> {
+    let con = get_db_con(db_pool).await?;
+    let row = con
+        .query_opt("SELECT * FROM credentials WHERE user_id = $1", &[&user_id])
+        .await
+        .map_err(DBQueryError)?;
+    Ok(row.map(|r| row_to_credentials(&r)))
+}
+
+/// Counts failed attempt, and locks login once there are `max_attempts`
+/// of them in a row. Counter starts over after lock.
+#[instrument(skip(db_pool))]
+pub async fn record_failed_attempt(
+    db_pool: &DBPool,
+    user_id: i32,
+    max_attempts: i32,
+    lockout: Duration,
+) -> Result<Option<DateTime<Utc>>> {
+    let con = get_db_con(db_pool).await?;
+    let locked_until = Utc::now() + lockout;
+    let row = con
+        .query_one(
+            "UPDATE credentials SET
+                locked_until = CASE WHEN failed_attempts + 1 >= $2 THEN $3 ELSE locked_until END,
+                failed_attempts = CASE WHEN failed_attempts + 1 >= $2 THEN 0 ELSE failed_attempts + 1 END
+            WHERE user_id = $1 RETURNING locked_until",
+            &[&user_id, &max_attempts, &locked_until],
+        )
+        .await
+        .map_err(DBQueryError)?;
+    Ok(row.get(0))
+}
+
+#[instrument(skip(db_pool))]
+pub async fn reset_failed_attempts(db_pool: &DBPool, user_id: i32) -> Result<()> {
+    let con = get_db_con(db_pool).await?;
+    con.execute(
+        "UPDATE credentials SET failed_attempts = 0, locked_until = NULL WHERE user_id = $1",
+        &[&user_id],
+    )
+    .await
+    .map_err(DBQueryError)?;
+    Ok(())
+}
+
+#[instrument(skip(db_pool, password_hash))]
+pub async fn update_password(db_pool: &DBPool, user_id: i32, password_hash: &str) -> Result<()> {
+    let con = get_db_con(db_pool).await?;
+    con.execute(
+        "UPDATE credentials SET password_hash = $2, updated_at = $3 WHERE user_id = $1",
+        &[&user_id, &password_hash, &Utc::now()],
+    )
+    .await
+    .map_err(DBQueryError)?;
+    Ok(())
+}
+
+#[instrument(skip(db_pool, session_id))]
+pub async fn create_session(
+    db_pool: &DBPool,
+    session_id: &str,
+    user_id: i32,
+    expires_at: DateTime<Utc>,
+) -> Result<()> {
+    let con = get_db_con(db_pool).await?;
+    con.execute(
+        "INSERT INTO sessions (id, user_id, expires_at) VALUES ($1, $2, $3)",
+        &[&session_id, &user_id, &expires_at],
+    )
+    .await
+    .map_err(DBQueryError)?;
+    Ok(())
+}
+
+/// Subject of user owning session, if session is still active
+#[instrument(skip(db_pool, session_id))]
+pub async fn get_active_session(
+    db_pool: &DBPool,
+    session_id: &str,
+) -> Result<Option<(i32, String)>> {
+    let row = retry::idempotent("get_active_session", || async {
+        let con = get_db_con(db_pool).await?;
+        con.query_opt(
+            "SELECT s.user_id, u.subject FROM sessions s JOIN users u ON u.id = s.user_id
+            WHERE s.id = $1 AND s.revoked_at IS NULL AND s.expires_at > $2 AND u.subject IS NOT NULL",
+            &[&session_id, &Utc::now()],
+        )
+        .await
+        .map_err(DBQueryError)
+    })
+    .await?;
+    Ok(row.map(|r| (r.get(0), r.get(1))))
+}
+
+#[instrument(skip(db_pool, session_id))]
+pub async fn revoke_session(db_pool: &DBPool, session_id: &str) -> Result<()> {
+    let con = get_db_con(db_pool).await?;
+    con.execute(
+        "UPDATE sessions SET revoked_at = $2 WHERE id = $1 AND revoked_at IS NULL",
+        &[&session_id, &Utc::now()],
+    )
+    .await
+    .map_err(DBQueryError)?;
+    Ok(())
+}
+
+/// Revokes all user's sessions except given one, e.g. after password change
+#[instrument(skip(db_pool, keep_session_id))]
+pub async fn revoke_other_sessions(
+    db_pool: &DBPool,
+    user_id: i32,
+    keep_session_id: &str,
+) -> Result<u64> {
+    let con = get_db_con(db_pool).await?;
+    con.execute(
+        "UPDATE sessions SET revoked_at = $3 WHERE user_id = $1 AND id <> $2 AND revoked_at IS NULL",
+        &[&user_id, &keep_session_id, &Utc::now()],
+    )
+    .await
+    .map_err(DBQueryError)
+}
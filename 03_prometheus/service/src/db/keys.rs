@@ -0,0 +1,92 @@
+use chrono::{DateTime, Utc};
+use mobc_postgres::tokio_postgres::Row;
+use tracing::instrument;
+
+use super::{get_db_con, retry};
+use crate::error::Error::DBQueryError;
+use crate::{DBPool, Result};
+
+const TABLE: &str = "api_keys";
+
+/// API key record. Key itself is never stored, only its hash & prefix,
+/// so it can be told apart in listings.
+#[derive(Debug)]
+pub struct ApiKey {
+    pub id: i32,
+    pub name: String,
+    pub prefix: String,
+    pub scopes: Vec<String>,
+    pub created_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+fn row_to_key(row: &Row) -> ApiKey {
+    ApiKey {
+        id: row.get("id"),
+        name: row.get("name"),
+        prefix: row.get("prefix"),
+        scopes: row.get("scopes"),
+        created_at: row.get("created_at"),
+        revoked_at: row.get("revoked_at"),
+    }
+}
+
+#[instrument(skip(db_pool, key_hash))]
+pub async fn create_key(
+    db_pool: &DBPool,
+    name: &str,
+    prefix: &str,
+    key_hash: &str,
+    scopes: &[String],
+) -> Result<ApiKey> {
+    let con = get_db_con(db_pool).await?;
+    let query = format!(
+        "INSERT INTO {} (name, prefix, key_hash, scopes) VALUES ($1, $2, $3, $4) RETURNING *",
+        TABLE
+    );
+    let row = con
+        .query_one(query.as_str(), &[&name, &prefix, &key_hash, &scopes])
+        .await
+        .map_err(DBQueryError)?;
+    Ok(row_to_key(&row))
+}
+
+#[instrument(skip(db_pool))]
+pub async fn get_keys(db_pool: &DBPool) -> Result<Vec<ApiKey>> {
+    let con = get_db_con(db_pool).await?;
+    let query = format!("SELECT * FROM {} ORDER BY id ASC", TABLE);
+    let rows = con.query(query.as_str(), &[]).await.map_err(DBQueryError)?;
+    Ok(rows.iter().map(row_to_key).collect())
+}
+
+/// Active (not revoked) key with given hash
+#[instrument(skip(db_pool, key_hash))]
+pub async fn find_active_key(db_pool: &DBPool, key_hash: &str) -> Result<Option<ApiKey>> {
+    let query = format!(
+        "SELECT * FROM {} WHERE key_hash = $1 AND revoked_at IS NULL",
+        TABLE
+    );
+    let row = retry::idempotent("find_active_key", || async {
+        let con = get_db_con(db_pool).await?;
+        con.query_opt(query.as_str(), &[&key_hash])
+            .await
+            .map_err(DBQueryError)
+    })
+    .await?;
+    Ok(row.map(|r| row_to_key(&r)))
+}
+
+/// Returns false if there's no such active key
+#[instrument(skip(db_pool))]
+pub async fn revoke_key(db_pool: &DBPool, id: i32) -> Result<bool> {
+    let con = get_db_con(db_pool).await?;
+    let query = format!(
+        "UPDATE {} SET revoked_at = $1 WHERE id = $2 AND revoked_at IS NULL",
+        TABLE
+    );
+    let res = con
+        .execute(query.as_str(), &[&Utc::now(), &id])
+        .await
+        .map_err(DBQueryError)?;
+    Ok(res > 0)
+}
@@ -1,23 +1,31 @@
+use std::cmp::Ordering;
 use std::fs;
+use std::sync::OnceLock;
 use std::vec;
 
 use super::get_db_con;
 use crate::{
+    context::log_println,
     error::Error,
-    error::Error::{DBInitError, DBMigrateError, DBMigrationNotFoundError, DBQueryError},
+    error::Error::{
+        DBInitError, DBMigrateError, DBMigrationNotFoundError, DBMigrationVersionError,
+        DBQueryError,
+    },
     DBCon, DBPool,
 };
-use chrono::prelude::*;
 use mobc_postgres::tokio_postgres::Row;
 use serde::Deserialize;
 use tokio::time::{sleep, Duration};
 
 const WAIT_LOOP_DURATION: Duration = Duration::from_secs(1);
 
+// Migrations binary is shipped with don't change while it runs, so they are
+// read once on startup rather than on every readiness probe
+static SHIPPED_MIGRATION_ID: OnceLock<Option<String>> = OnceLock::new();
+
 #[derive(Deserialize, Debug)]
 struct MigrationRecord {
     pub migration_id: String,
-    pub migrated_at: DateTime<Utc>,
 }
 
 #[derive(Debug)]
@@ -26,6 +34,30 @@ struct Migration {
     pub migration_sql: String,
 }
 
+/// Outcome of migration run, reported to Pushgateway
+#[derive(Debug, Default)]
+pub struct MigrationSummary {
+    pub applied: usize,
+    pub last_migration_id: Option<String>,
+}
+
+/// Order of migrations by version number from `V<number>_name` id, so
+/// that `V10` goes after `V2`
+fn version_key(migration_id: &str) -> (u64, String) {
+    let version = migration_id
+        .trim_start_matches(['V', 'v'])
+        .chars()
+        .take_while(|c| c.is_ascii_digit())
+        .collect::<String>()
+        .parse()
+        .unwrap_or(0);
+    (version, migration_id.to_lowercase())
+}
+
+fn compare_versions(a: &str, b: &str) -> Ordering {
+    version_key(a).cmp(&version_key(b))
+}
+
 const INIT_MIGRATION: &str = "
     CREATE TABLE IF NOT EXISTS migrations (
         migration_id TEXT PRIMARY KEY NOT NULL,
@@ -33,59 +65,66 @@ const INIT_MIGRATION: &str = "
     );
 ";
 
-pub async fn migrate(db_pool: &DBPool) -> Result<(), Error> {
+pub async fn migrate(db_pool: &DBPool) -> Result<MigrationSummary, Error> {
     let db_con = get_db_con(db_pool).await?;
     init_migration_table(&db_con).await?;
-    apply_all_migrations(&db_con).await?;
-    Ok(())
+    let applied = apply_all_migrations(&db_con).await?;
+    let last_migration_id = get_last_applied_migration_record(&db_con)
+        .await?
+        .map(|r| r.migration_id);
+    Ok(MigrationSummary {
+        applied,
+        last_migration_id,
+    })
 }
 
-pub async fn wait_for_migrate(db_pool: &DBPool) -> Result<(), Error> {
+/// Schema is compatible with binary if it has at least all migrations
+/// binary is shipped with. Newer schema is fine too, since old replicas
+/// keep running while new release is rolled out after its migration.
+fn is_compatible(last_migration_id: Option<&str>, last_record_id: Option<&str>) -> bool {
+    match (last_migration_id, last_record_id) {
+        (None, _) => true,
+        (Some(_), None) => false,
+        (Some(m_id), Some(r_id)) => compare_versions(m_id, r_id) != Ordering::Greater,
+    }
+}
+
+/// Waits until last migration from disk (or newer one) is applied,
+/// returns id of last migration applied to database
+pub async fn wait_for_migrate(db_pool: &DBPool) -> Result<Option<String>, Error> {
     let db_con = get_db_con(db_pool).await?;
     let last_migration_id = get_last_migration_from_disk()
         .await?
         .map(|m| m.migration_id);
-    let last_migration_record_id = get_last_applied_migration_record(&db_con)
-        .await?
-        .map(|r| r.migration_id);
-
-    println!("Waiting for migration {:?}", last_migration_id);
-    match (last_migration_id, last_migration_record_id) {
-        (None, None) => Ok(()),
-        (None, Some(r_id)) => Err(DBMigrationNotFoundError(r_id)),
-        (Some(m_id), None) => loop_wait_for_migration_applied(&db_con, &m_id).await,
-        (Some(m_id), Some(r_id)) => {
-            if m_id.eq(&r_id) {
-                Ok(())
-            } else if m_id.lt(&r_id) {
-                Err(DBMigrationNotFoundError(r_id))
-            } else {
-                loop_wait_for_migration_applied(&db_con, &m_id).await
-            }
-        }
-    }
-}
 
-async fn loop_wait_for_migration_applied(
-    db_con: &DBCon,
-    migration_id: &String,
-) -> Result<(), Error> {
+    log_println!("Waiting for migration {:?}", last_migration_id);
     loop {
-        let last_migration_record_id = get_last_applied_migration_record(&db_con)
+        let last_record_id = get_last_applied_migration_record(&db_con)
             .await?
             .map(|r| r.migration_id);
-
-        if let Some(r_id) = last_migration_record_id {
-            if migration_id.eq(&r_id) {
-                return Ok(());
-            } else if migration_id.lt(&r_id) {
-                return Err(DBMigrationNotFoundError(r_id));
-            }
+        if is_compatible(last_migration_id.as_deref(), last_record_id.as_deref()) {
+            return Ok(last_record_id);
         }
         sleep(WAIT_LOOP_DURATION).await;
     }
 }
 
+/// Checks (without waiting) that database isn't behind migrations binary
+/// is shipped with
+pub async fn check_migration(db_pool: &DBPool) -> Result<(), Error> {
+    let db_con = get_db_con(db_pool).await?;
+    let last_migration_id = shipped_migration_id().await?;
+    let last_record_id = get_last_applied_migration_record(&db_con)
+        .await?
+        .map(|r| r.migration_id);
+
+    if is_compatible(last_migration_id.as_deref(), last_record_id.as_deref()) {
+        Ok(())
+    } else {
+        Err(DBMigrationVersionError(last_migration_id, last_record_id))
+    }
+}
+
 async fn init_migration_table(db_con: &DBCon) -> Result<(), Error> {
     match db_exec(db_con, INIT_MIGRATION).await {
         Err(DBQueryError(err)) => Err(DBInitError(err)),
@@ -94,7 +133,7 @@ async fn init_migration_table(db_con: &DBCon) -> Result<(), Error> {
     }
 }
 
-async fn apply_all_migrations(db_con: &DBCon) -> Result<(), Error> {
+async fn apply_all_migrations(db_con: &DBCon) -> Result<usize, Error> {
     let migrations = read_migrations_from_disk().await?;
     db_exec(
         db_con,
@@ -103,11 +142,13 @@ async fn apply_all_migrations(db_con: &DBCon) -> Result<(), Error> {
     .await?;
 
     let migrations = get_unapplied_migrations(db_con, migrations).await?;
-    println!("Migrations to apply: {:?}", migrations);
-    if migrations.len() == 0 {
-        return db_exec(db_con, "COMMIT").await;
+    log_println!("Migrations to apply: {:?}", migrations);
+    if migrations.is_empty() {
+        db_exec(db_con, "COMMIT").await?;
+        return Ok(0);
     }
 
+    let applied = migrations.len();
     if let Err(err) = apply_migrations(db_con, migrations).await {
         // We don't care about rollback success - even if it fails, we
         // want to return original error. It's either migration error
@@ -119,11 +160,11 @@ async fn apply_all_migrations(db_con: &DBCon) -> Result<(), Error> {
         db_exec(db_con, "COMMIT").await?;
     }
 
-    Ok(())
+    Ok(applied)
 }
 
 async fn db_exec(db_con: &DBCon, sql: &str) -> Result<(), Error> {
-    println!("Executing SQL: {}", sql);
+    log_println!("Executing SQL: {}", sql);
     db_con.batch_execute(sql).await.map_err(DBQueryError)?;
     Ok(())
 }
@@ -133,7 +174,7 @@ async fn get_unapplied_migrations(
     migrations: Vec<Migration>,
 ) -> Result<Vec<Migration>, Error> {
     let last_record = get_last_applied_migration_record(db_con).await?;
-    println!("Last record: {:?}", last_record);
+    log_println!("Last record: {:?}", last_record);
     match last_record {
         None => Ok(migrations),
         Some(rec) => {
@@ -151,18 +192,20 @@ async fn get_unapplied_migrations(
 async fn get_last_applied_migration_record(
     db_con: &DBCon,
 ) -> Result<Option<MigrationRecord>, Error> {
-    let query = "SELECT * FROM migrations ORDER BY migration_id DESC LIMIT 1";
-    let row = db_con.query_opt(query, &[]).await.map_err(DBQueryError)?;
-    Ok(row.map(|r| row_to_migration_record(&r)))
+    // Ids can't be ordered as strings, and there are few of them anyway
+    let rows = db_con
+        .query("SELECT * FROM migrations", &[])
+        .await
+        .map_err(DBQueryError)?;
+    Ok(rows
+        .iter()
+        .map(row_to_migration_record)
+        .max_by(|a, b| compare_versions(&a.migration_id, &b.migration_id)))
 }
 
 fn row_to_migration_record(row: &Row) -> MigrationRecord {
     let migration_id = row.get(0);
-    let migrated_at = row.get(1);
-    MigrationRecord {
-        migration_id,
-        migrated_at,
-    }
+    MigrationRecord { migration_id }
 }
 
 async fn apply_migrations(db_con: &DBCon, migrations: Vec<Migration>) -> Result<(), Error> {
@@ -203,11 +246,7 @@ async fn read_migrations_from_disk() -> Result<Vec<Migration>, Error> {
         }
     }
 
-    migrations.sort_by(|a, b| {
-        a.migration_id
-            .to_lowercase()
-            .cmp(&b.migration_id.to_lowercase())
-    });
+    migrations.sort_by(|a, b| compare_versions(&a.migration_id, &b.migration_id));
 
     Ok(migrations)
 }
@@ -215,3 +254,13 @@ async fn read_migrations_from_disk() -> Result<Vec<Migration>, Error> {
 async fn get_last_migration_from_disk() -> Result<Option<Migration>, Error> {
     Ok(read_migrations_from_disk().await?.pop())
 }
+
+async fn shipped_migration_id() -> Result<Option<String>, Error> {
+    if let Some(id) = SHIPPED_MIGRATION_ID.get() {
+        return Ok(id.clone());
+    }
+    let id = get_last_migration_from_disk()
+        .await?
+        .map(|m| m.migration_id);
+    Ok(SHIPPED_MIGRATION_ID.get_or_init(|| id).clone())
+}
@@ -1,40 +1,111 @@
+use crate::context::{log_eprintln, log_println};
 use crate::data::{User, UserCreateRequest, UserUpdateRequest};
-use crate::error::Error::{DBCreatePoolError, DBPoolError, DBQueryError};
+use crate::error::Error::{DBCreatePoolError, DBPoolError, DBQueryError, OwnUserExists};
 use crate::{DBCon, DBPool, Result};
-use chrono::{DateTime, Utc};
-use mobc_postgres::tokio_postgres::Row;
+use chrono::Utc;
+use mobc_postgres::tokio_postgres::{error::SqlState, Row};
 use mobc_postgres::{tokio_postgres, PgConnectionManager};
 use std::str::FromStr;
-use std::time::Duration;
-use tokio_postgres::{Config, NoTls};
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+use tokio_postgres::Config;
+use tracing::instrument;
 
+pub mod breaker;
+pub mod credentials;
+pub mod keys;
 pub mod migration;
-
-const DB_POOL_MAX_OPEN: u64 = 32;
-const DB_POOL_MAX_IDLE: u64 = 8;
-const DB_POOL_TIMEOUT_SECONDS: u64 = 15;
+pub mod retry;
+mod tls;
 
 const TABLE: &str = "users";
+const WAIT_FIRST_DELAY: Duration = Duration::from_millis(500);
 
-pub fn create_pool(conn_string: &String) -> Result<DBPool> {
-    let config = Config::from_str(conn_string).map_err(DBCreatePoolError)?;
+pub fn create_pool(config: &crate::config::Config) -> Result<DBPool> {
+    let (conn_string, connector) = tls::connector(config)?;
+    let pg_config = Config::from_str(&conn_string).map_err(DBCreatePoolError)?;
 
-    let manager = PgConnectionManager::new(config, NoTls);
+    let manager = PgConnectionManager::new(pg_config, connector);
     Ok(DBPool::builder()
-        .max_open(DB_POOL_MAX_OPEN)
-        .max_idle(DB_POOL_MAX_IDLE)
-        .get_timeout(Some(Duration::from_secs(DB_POOL_TIMEOUT_SECONDS)))
+        .max_open(config.db_pool_max_open)
+        .max_idle(config.db_pool_max_idle)
+        .get_timeout(Some(Duration::from_secs(config.db_pool_timeout_seconds)))
         .build(manager))
 }
 
+/// Closes idle connections & makes sure that connections are closed
+/// instead of returning to the pool, so pool can be dropped safely
+pub async fn close_pool(db_pool: &DBPool) {
+    db_pool.set_max_idle_conns(0).await;
+}
+
+/// Connection from pool, unless circuit breaker is open after failing
+/// to get connections
+#[instrument(skip(db_pool))]
 pub async fn get_db_con(db_pool: &DBPool) -> Result<DBCon> {
-    db_pool.get().await.map_err(DBPoolError)
+    let permit = breaker::permit()?;
+    match db_pool.get().await {
+        Ok(con) => {
+            permit.succeeded();
+            Ok(con)
+        }
+        Err(e) => {
+            permit.failed();
+            Err(DBPoolError(e))
+        }
+    }
 }
 
+#[instrument(skip(db_pool))]
 pub async fn check_db(db_pool: &DBPool) -> Result<()> {
-    let con = get_db_con(&db_pool).await?;
-    con.execute("SELECT 1", &[]).await.map_err(DBQueryError)?;
-    Ok(())
+    retry::idempotent("check_db", || async {
+        let con = get_db_con(db_pool).await?;
+        con.execute("SELECT 1", &[]).await.map_err(DBQueryError)?;
+        Ok(())
+    })
+    .await
+}
+
+/// Waits until database accepts connections, with delay between attempts
+/// doubling up to `max_delay`. Gives up after `timeout`, or right away on
+/// errors which won't go away by themselves, like wrong password.
+/// Bypasses circuit breaker, so that every attempt reaches database.
+pub async fn wait_for_db(db_pool: &DBPool, timeout: Duration, max_delay: Duration) -> Result<()> {
+    let deadline = Instant::now() + timeout;
+    let mut delay = WAIT_FIRST_DELAY.min(max_delay);
+    let mut attempt = 1;
+    loop {
+        let res = match db_pool.get().await {
+            Ok(con) => con
+                .execute("SELECT 1", &[])
+                .await
+                .map(|_| ())
+                .map_err(DBQueryError),
+            Err(e) => Err(DBPoolError(e)),
+        };
+        let e = match res {
+            Ok(()) => {
+                log_println!("Database is reachable (attempt {})", attempt);
+                return Ok(());
+            }
+            // Pool timeout is what unreachable host looks like
+            Err(e @ DBPoolError(mobc::Error::Timeout)) => e,
+            Err(e) if retry::is_transient(&e) => e,
+            Err(e) => return Err(e),
+        };
+        if Instant::now() + delay > deadline {
+            return Err(e);
+        }
+        log_eprintln!(
+            "Database is not reachable (attempt {}), retrying in {:?}: {}",
+            attempt,
+            delay,
+            e
+        );
+        sleep(delay).await;
+        delay = (delay * 2).min(max_delay);
+        attempt += 1;
+    }
 }
 
 fn row_to_user(row: &Row) -> User {
@@ -44,8 +115,6 @@ fn row_to_user(row: &Row) -> User {
     let lastname: String = row.get(3);
     let email: String = row.get(4);
     let phone: String = row.get(5);
-    let created_at: DateTime<Utc> = row.get(6);
-    let updated_at: DateTime<Utc> = row.get(7);
 
     User {
         id,
@@ -54,14 +123,24 @@ fn row_to_user(row: &Row) -> User {
         lastname,
         email,
         phone,
-        created_at,
-        updated_at,
     }
 }
 
-pub async fn create_user(db_pool: &DBPool, body: UserCreateRequest) -> Result<User> {
-    let con = get_db_con(db_pool).await?;
-    let query = format!("INSERT INTO {} (username, firstname, lastname, email, phone) VALUES ($1, $2, $3, $4, $5) RETURNING *", TABLE);
+/// Condition on owner passed as `$n`. Records are filtered by owner in
+/// queries, so that other users' records look exactly like missing ones.
+/// No owner means no restriction.
+fn owner_condition(n: usize) -> String {
+    format!("(${0}::text IS NULL OR subject = ${0})", n)
+}
+
+#[instrument(skip(db_pool, body))]
+pub async fn create_user(
+    db_pool: &DBPool,
+    body: UserCreateRequest,
+    subject: Option<&str>,
+) -> Result<User> {
+    let con = retry::connection(db_pool).await?;
+    let query = format!("INSERT INTO {} (username, firstname, lastname, email, phone, subject) VALUES ($1, $2, $3, $4, $5, $6) RETURNING *", TABLE);
     let row = con
         .query_one(
             query.as_str(),
@@ -71,44 +150,83 @@ pub async fn create_user(db_pool: &DBPool, body: UserCreateRequest) -> Result<Us
                 &body.lastname,
                 &body.email,
                 &body.phone,
+                &subject,
             ],
         )
-        .await
-        .map_err(DBQueryError)?;
-    Ok(row_to_user(&row))
+        .await;
+    match row {
+        Ok(row) => Ok(row_to_user(&row)),
+        // End user can have only one record
+        Err(e) if e.code() == Some(&SqlState::UNIQUE_VIOLATION) => Err(OwnUserExists),
+        Err(e) => Err(DBQueryError(e)),
+    }
 }
 
-pub async fn get_users(db_pool: &DBPool) -> Result<Vec<User>> {
-    println!("GET /user");
-    let con = get_db_con(db_pool).await?;
-    let query = format!("SELECT * FROM {} ORDER BY id ASC", TABLE);
-    let rows = con.query(query.as_str(), &[]).await.map_err(DBQueryError)?;
-    println!("Fetched rows: {:?}", rows);
-    Ok(rows.iter().map(|r| row_to_user(&r)).collect())
+#[instrument(skip(db_pool))]
+pub async fn get_users(db_pool: &DBPool, owner: Option<&str>) -> Result<Vec<User>> {
+    log_println!("GET /user");
+    let query = format!(
+        "SELECT * FROM {} WHERE {} ORDER BY id ASC",
+        TABLE,
+        owner_condition(1)
+    );
+    let rows = retry::idempotent("get_users", || async {
+        let con = get_db_con(db_pool).await?;
+        con.query(query.as_str(), &[&owner])
+            .await
+            .map_err(DBQueryError)
+    })
+    .await?;
+    log_println!("Fetched rows: {:?}", rows);
+    Ok(rows.iter().map(row_to_user).collect())
 }
 
-pub async fn get_user(db_pool: &DBPool, id: i32) -> Result<Option<User>> {
-    println!("GET /user/{:?}", id);
-    let con = get_db_con(db_pool).await?;
-    let query = format!("SELECT * FROM {} WHERE id = $1", TABLE);
-    let row = con
-        .query_opt(query.as_str(), &[&id])
-        .await
-        .map_err(DBQueryError)?;
+#[instrument(skip(db_pool))]
+pub async fn get_user(db_pool: &DBPool, id: i32, owner: Option<&str>) -> Result<Option<User>> {
+    log_println!("GET /user/{:?}", id);
+    let query = format!(
+        "SELECT * FROM {} WHERE id = $1 AND {}",
+        TABLE,
+        owner_condition(2)
+    );
+    let row = retry::idempotent("get_user", || async {
+        let con = get_db_con(db_pool).await?;
+        con.query_opt(query.as_str(), &[&id, &owner])
+            .await
+            .map_err(DBQueryError)
+    })
+    .await?;
 
-    println!("Fetched row: {:?}", row);
+    log_println!("Fetched row: {:?}", row);
     Ok(row.map(|r| row_to_user(&r)))
 }
 
+/// Id of record owned by given identity, if there's one
+#[instrument(skip(db_pool))]
+pub async fn get_user_id_by_subject(db_pool: &DBPool, subject: &str) -> Result<Option<i32>> {
+    let query = format!("SELECT id FROM {} WHERE subject = $1", TABLE);
+    let row = retry::idempotent("get_user_id_by_subject", || async {
+        let con = get_db_con(db_pool).await?;
+        con.query_opt(query.as_str(), &[&subject])
+            .await
+            .map_err(DBQueryError)
+    })
+    .await?;
+    Ok(row.map(|r| r.get(0)))
+}
+
+#[instrument(skip(db_pool, body))]
 pub async fn update_user(
     db_pool: &DBPool,
     id: i32,
     body: UserUpdateRequest,
+    owner: Option<&str>,
 ) -> Result<Option<User>> {
-    let con = get_db_con(db_pool).await?;
+    let con = retry::connection(db_pool).await?;
     let query = format!(
-        "UPDATE {} SET username = $1, firstname = $2, lastname = $3, email = $4, phone = $5, updated_at = $6 WHERE id = $7 RETURNING *",
-        TABLE
+        "UPDATE {} SET username = $1, firstname = $2, lastname = $3, email = $4, phone = $5, updated_at = $6 WHERE id = $7 AND {} RETURNING *",
+        TABLE,
+        owner_condition(8)
     );
     let now = Utc::now();
     let row = con
@@ -122,6 +240,7 @@ pub async fn update_user(
                 &body.phone,
                 &now,
                 &id,
+                &owner,
             ],
         )
         .await
@@ -129,11 +248,16 @@ pub async fn update_user(
     Ok(row.map(|r| row_to_user(&r)))
 }
 
-pub async fn delete_user(db_pool: &DBPool, id: i32) -> Result<bool> {
-    let con = get_db_con(db_pool).await?;
-    let query = format!("DELETE FROM {} WHERE id = $1", TABLE);
+#[instrument(skip(db_pool))]
+pub async fn delete_user(db_pool: &DBPool, id: i32, owner: Option<&str>) -> Result<bool> {
+    let con = retry::connection(db_pool).await?;
+    let query = format!(
+        "DELETE FROM {} WHERE id = $1 AND {}",
+        TABLE,
+        owner_condition(2)
+    );
     let res = con
-        .execute(query.as_str(), &[&id])
+        .execute(query.as_str(), &[&id, &owner])
         .await
         .map_err(DBQueryError)?;
     Ok(res > 0)
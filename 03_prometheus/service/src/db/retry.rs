@@ -0,0 +1,222 @@
+use std::error::Error as _;
+use std::future::Future;
+use std::sync::{Mutex, RwLock};
+
+use lazy_static::lazy_static;
+use mobc_postgres::tokio_postgres::{self, error::SqlState};
+use rand::Rng;
+use tokio::time::{sleep, Duration};
+
+use super::get_db_con;
+use crate::config::Config;
+use crate::context::log_eprintln;
+use crate::error::Error;
+use crate::metrics;
+use crate::{DBCon, DBPool, Result};
+
+// Retries saved up while DB is healthy. Each successful operation adds
+// `budget_ratio` of retry, so retries can't multiply load on struggling DB.
+const BUDGET_CAPACITY: f64 = 10.0;
+
+// Errors which mean that query didn't run, or was rolled back and can be run
+// again: failover, restart & serialization conflicts
+const TRANSIENT_CODES: [SqlState; 5] = [
+    SqlState::T_R_SERIALIZATION_FAILURE,
+    SqlState::T_R_DEADLOCK_DETECTED,
+    SqlState::ADMIN_SHUTDOWN,
+    SqlState::CRASH_SHUTDOWN,
+    SqlState::CANNOT_CONNECT_NOW,
+];
+
+#[derive(Clone)]
+struct Policy {
+    max_attempts: u32,
+    base_delay: Duration,
+    max_delay: Duration,
+    budget_ratio: f64,
+}
+
+lazy_static! {
+    static ref POLICY: RwLock<Policy> = RwLock::new(Policy {
+        max_attempts: 3,
+        base_delay: Duration::from_millis(50),
+        max_delay: Duration::from_secs(1),
+        budget_ratio: 0.2,
+    });
+    static ref BUDGET: Mutex<f64> = Mutex::new(BUDGET_CAPACITY);
+}
+
+pub fn configure(config: &Config) {
+    *POLICY.write().unwrap() = Policy {
+        max_attempts: config.db_retry_max_attempts,
+        base_delay: Duration::from_millis(config.db_retry_base_delay_ms),
+        max_delay: Duration::from_millis(config.db_retry_max_delay_ms),
+        budget_ratio: config.db_retry_budget_ratio,
+    };
+}
+
+fn is_transient_pg(e: &tokio_postgres::Error) -> bool {
+    if e.is_closed() {
+        return true;
+    }
+    match e.code() {
+        // Class 08 is connection exceptions
+        Some(code) => TRANSIENT_CODES.contains(code) || code.code().starts_with("08"),
+        None => e.source().is_some_and(|s| s.is::<std::io::Error>()),
+    }
+}
+
+/// Whether error is likely to go away on its own, e.g. during failover
+pub fn is_transient(e: &Error) -> bool {
+    match e {
+        Error::DBPoolError(mobc::Error::Inner(e)) | Error::DBQueryError(e) => is_transient_pg(e),
+        Error::DBPoolError(mobc::Error::BadConn) => true,
+        _ => false,
+    }
+}
+
+fn deposit(ratio: f64) {
+    let mut budget = BUDGET.lock().unwrap();
+    *budget = (*budget + ratio).min(BUDGET_CAPACITY);
+}
+
+fn withdraw() -> bool {
+    let mut budget = BUDGET.lock().unwrap();
+    if *budget < 1.0 {
+        return false;
+    }
+    *budget -= 1.0;
+    true
+}
+
+/// Exponential backoff with full jitter, so that instances don't retry in
+/// lockstep after failover
+fn backoff(policy: &Policy, attempt: u32) -> Duration {
+    let cap = policy
+        .base_delay
+        .saturating_mul(2u32.saturating_pow(attempt - 1))
+        .min(policy.max_delay);
+    cap.mul_f64(rand::thread_rng().gen::<f64>())
+}
+
+/// Runs operation, retrying it on transient errors. Only for operations
+/// which can safely run more than once, like reads.
+pub async fn idempotent<T, F, Fut>(operation: &'static str, run: F) -> Result<T>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let policy = POLICY.read().unwrap().clone();
+    let mut attempt = 1;
+    loop {
+        let e = match run().await {
+            Ok(value) => {
+                deposit(policy.budget_ratio);
+                return Ok(value);
+            }
+            Err(e) if is_transient(&e) => e,
+            Err(e) => return Err(e),
+        };
+        if attempt >= policy.max_attempts {
+            metrics::track_db_retries_exhausted(operation, "attempts");
+            return Err(e);
+        }
+        if !withdraw() {
+            metrics::track_db_retries_exhausted(operation, "budget");
+            return Err(e);
+        }
+        metrics::track_db_retry(operation);
+        log_eprintln!(
+            "{} failed on attempt {}, retrying: {}",
+            operation,
+            attempt,
+            e
+        );
+        sleep(backoff(&policy, attempt)).await;
+        attempt += 1;
+    }
+}
+
+/// Connection for writes. Only getting connection is retried, since failed
+/// statement could have been applied before connection broke.
+pub async fn connection(db_pool: &DBPool) -> Result<DBCon> {
+    idempotent("connect", || get_db_con(db_pool)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+
+    #[test]
+    fn backoff_grows_up_to_max_delay() {
+        let policy = Policy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(50),
+            max_delay: Duration::from_secs(1),
+            budget_ratio: 0.2,
+        };
+        for _ in 0..100 {
+            assert!(backoff(&policy, 1) <= Duration::from_millis(50));
+            assert!(backoff(&policy, 3) <= Duration::from_millis(200));
+            // Doesn't overflow on large attempts
+            assert!(backoff(&policy, 40) <= Duration::from_secs(1));
+        }
+        // Jitter spreads delays over the whole range
+        let delays: Vec<Duration> = (0..100).map(|_| backoff(&policy, 5)).collect();
+        assert!(delays.iter().any(|d| *d < Duration::from_millis(400)));
+        assert!(delays.iter().any(|d| *d > Duration::from_millis(400)));
+    }
+
+    #[tokio::test]
+    async fn connection_errors_are_transient() {
+        // Nothing listens on port 1
+        let e = tokio_postgres::connect("host=127.0.0.1 port=1", tokio_postgres::NoTls)
+            .await
+            .err()
+            .unwrap();
+        assert!(is_transient(&Error::DBQueryError(e)));
+        assert!(is_transient(&Error::DBPoolError(mobc::Error::BadConn)));
+
+        let e = "port=not-a-number"
+            .parse::<tokio_postgres::Config>()
+            .err()
+            .unwrap();
+        assert!(!is_transient(&Error::DBQueryError(e)));
+        assert!(!is_transient(&Error::DBPoolError(mobc::Error::Timeout)));
+    }
+
+    #[tokio::test]
+    async fn retries_transient_errors_only() {
+        let calls = AtomicU32::new(0);
+        let res: Result<()> = idempotent("test", || async {
+            calls.fetch_add(1, Ordering::SeqCst);
+            Err(Error::DBPoolError(mobc::Error::BadConn))
+        })
+        .await;
+        assert!(res.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        let calls = AtomicU32::new(0);
+        let res = idempotent("test", || async {
+            if calls.fetch_add(1, Ordering::SeqCst) == 0 {
+                Err(Error::DBPoolError(mobc::Error::BadConn))
+            } else {
+                Ok(42)
+            }
+        })
+        .await;
+        assert_eq!(res.unwrap(), 42);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        let calls = AtomicU32::new(0);
+        let res: Result<()> = idempotent("test", || async {
+            calls.fetch_add(1, Ordering::SeqCst);
+            Err(Error::DBPoolError(mobc::Error::Timeout))
+        })
+        .await;
+        assert!(res.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+}
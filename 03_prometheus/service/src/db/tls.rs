@@ -0,0 +1,192 @@
+use openssl::ssl::{SslConnector, SslFiletype, SslMethod, SslVerifyMode};
+use postgres_openssl::MakeTlsConnector;
+
+use crate::config::Config;
+use crate::error::Error::DBTlsError;
+use crate::Result;
+
+/// How server certificate is verified. `tokio_postgres` only knows
+/// `disable`, `prefer` & `require` modes, so `verify-ca` & `verify-full`
+/// are handled here and passed to it as `require`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Verify {
+    None,
+    Ca,
+    Full,
+}
+
+/// Connection string with `sslmode` understood by `tokio_postgres`, and
+/// verification requested by original `sslmode`. Works both for URL
+/// (`?sslmode=verify-full`) and key-value (`sslmode=verify-full`) format.
+fn split_ssl_mode(conn_string: &str) -> (String, Verify) {
+    for (mode, verify) in [
+        ("sslmode=verify-full", Verify::Full),
+        ("sslmode=verify-ca", Verify::Ca),
+    ] {
+        if conn_string.contains(mode) {
+            return (conn_string.replace(mode, "sslmode=require"), verify);
+        }
+    }
+    (conn_string.to_owned(), Verify::None)
+}
+
+/// Builds TLS connector for DB pool along with connection string to use.
+/// Without `sslmode` (which means `prefer`) TLS is used if server supports
+/// it, and `sslmode=disable` turns it off completely.
+pub fn connector(config: &Config) -> Result<(String, MakeTlsConnector)> {
+    let (conn_string, mut verify) = split_ssl_mode(&config.db_conn_string);
+    // Same as libpq, `require` with root certificate means `verify-ca`
+    if verify == Verify::None && config.db_ssl_root_cert.is_some() {
+        verify = Verify::Ca;
+    }
+
+    let mut builder = SslConnector::builder(SslMethod::tls()).map_err(DBTlsError)?;
+    if let Some(root_cert) = &config.db_ssl_root_cert {
+        builder.set_ca_file(root_cert).map_err(DBTlsError)?;
+    }
+    if let (Some(cert), Some(key)) = (&config.db_ssl_cert, &config.db_ssl_key) {
+        builder
+            .set_certificate_chain_file(cert)
+            .map_err(DBTlsError)?;
+        builder
+            .set_private_key_file(key, SslFiletype::PEM)
+            .map_err(DBTlsError)?;
+        builder.check_private_key().map_err(DBTlsError)?;
+    }
+    if verify == Verify::None {
+        builder.set_verify(SslVerifyMode::NONE);
+    }
+
+    let mut connector = MakeTlsConnector::new(builder.build());
+    if verify != Verify::Full {
+        connector.set_callback(|connect_config, _| {
+            connect_config.set_verify_hostname(false);
+            Ok(())
+        });
+    }
+    Ok((conn_string, connector))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+    use std::pin::Pin;
+    use std::process::Command;
+    use std::str::FromStr;
+
+    use mobc_postgres::tokio_postgres;
+    use openssl::ssl::{Ssl, SslAcceptor};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio::task::JoinHandle;
+    use tokio_openssl::SslStream;
+
+    use super::*;
+    use crate::tls::subject;
+
+    /// Self-signed certificates from `scripts/pg_tls_certs.sh`, unique to
+    /// test & process
+    fn certs(name: &str, host: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("pg-tls-{}-{}", std::process::id(), name));
+        let script = concat!(env!("CARGO_MANIFEST_DIR"), "/../scripts/pg_tls_certs.sh");
+        let status = Command::new("bash")
+            .args([script, dir.to_str().unwrap(), host, "postgres"])
+            .output()
+            .expect("certificate script can be run")
+            .status;
+        assert!(status.success());
+        dir
+    }
+
+    /// Postgres stub which takes one connection through TLS negotiation,
+    /// and reports subject of client certificate, if handshake succeeded
+    async fn stub_postgres(certs: &Path) -> (u16, JoinHandle<Option<Option<String>>>) {
+        let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).unwrap();
+        builder
+            .set_certificate_chain_file(certs.join("server.crt"))
+            .unwrap();
+        builder
+            .set_private_key_file(certs.join("server.key"), SslFiletype::PEM)
+            .unwrap();
+        builder.set_ca_file(certs.join("ca.crt")).unwrap();
+        builder.set_verify(SslVerifyMode::PEER);
+        let acceptor = builder.build();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut ssl_request = [0; 8];
+            stream.read_exact(&mut ssl_request).await.unwrap();
+            stream.write_all(b"S").await.unwrap();
+
+            let ssl = Ssl::new(acceptor.context()).unwrap();
+            let mut stream = SslStream::new(ssl, stream).unwrap();
+            Pin::new(&mut stream).accept().await.ok()?;
+            Some(
+                stream
+                    .ssl()
+                    .peer_certificate()
+                    .map(|cert| subject(cert.subject_name())),
+            )
+        });
+        (port, handle)
+    }
+
+    /// Connects to stub & returns its outcome. Connection itself always
+    /// fails, since stub never gets to Postgres protocol.
+    async fn handshake(
+        server: &Path,
+        client: &Path,
+        host: &str,
+        mode: &str,
+    ) -> Option<Option<String>> {
+        let (port, outcome) = stub_postgres(server).await;
+        let args = vec![
+            format!(
+                "--db-conn-string=postgres://postgres@{}:{}/app?sslmode={}",
+                host, port, mode
+            ),
+            format!("--db-ssl-root-cert={}", client.join("ca.crt").display()),
+            format!("--db-ssl-cert={}", client.join("client.crt").display()),
+            format!("--db-ssl-key={}", client.join("client.key").display()),
+        ];
+        let (conn_string, connector) = connector(&Config::load(&args).unwrap()).unwrap();
+        let pg_config = tokio_postgres::Config::from_str(&conn_string).unwrap();
+        assert!(pg_config.connect(connector).await.is_err());
+        outcome.await.unwrap()
+    }
+
+    #[test]
+    fn splits_verify_modes() {
+        assert_eq!(
+            split_ssl_mode("postgres://db/app?sslmode=verify-full"),
+            ("postgres://db/app?sslmode=require".to_owned(), Verify::Full)
+        );
+        assert_eq!(
+            split_ssl_mode("host=db sslmode=verify-ca"),
+            ("host=db sslmode=require".to_owned(), Verify::Ca)
+        );
+        assert_eq!(
+            split_ssl_mode("postgres://db/app?sslmode=require"),
+            ("postgres://db/app?sslmode=require".to_owned(), Verify::None)
+        );
+    }
+
+    #[tokio::test]
+    async fn verifies_self_signed_postgres() {
+        // Server certificate names db.internal & 127.0.0.1, but not localhost
+        let trusted = certs("trusted", "db.internal");
+        let other = certs("other", "db.internal");
+
+        let client_cn = Some(Some("CN=postgres".to_owned()));
+        let outcome = handshake(&trusted, &trusted, "127.0.0.1", "verify-full").await;
+        assert_eq!(outcome, client_cn);
+        let outcome = handshake(&trusted, &trusted, "localhost", "verify-full").await;
+        assert_eq!(outcome, None);
+        let outcome = handshake(&trusted, &trusted, "localhost", "verify-ca").await;
+        assert_eq!(outcome, client_cn);
+        let outcome = handshake(&trusted, &other, "localhost", "verify-ca").await;
+        assert_eq!(outcome, None);
+    }
+}
@@ -1,16 +1,27 @@
+use crate::context::{self, log_eprintln, log_println};
 use mobc_postgres::tokio_postgres;
 use thiserror::Error;
 
 use serde::Serialize;
 use std::convert::Infallible;
+use warp::http::header::{HeaderValue, RETRY_AFTER, WWW_AUTHENTICATE};
 use warp::{http::StatusCode, reply, Rejection, Reply};
 
 #[derive(Error, Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     #[error("error creating DB pool: {0}")]
     DBCreatePoolError(tokio_postgres::Error),
+    #[error("error configuring DB TLS: {0}")]
+    DBTlsError(openssl::error::ErrorStack),
+    #[error("error configuring TLS: {0}")]
+    TlsError(openssl::error::ErrorStack),
     #[error("error getting connection from DB pool: {0}")]
     DBPoolError(mobc::Error<tokio_postgres::Error>),
+    #[error("DB circuit breaker is open, retry after {0:?}")]
+    DBCircuitOpen(std::time::Duration),
+    #[error("DB pool is exhausted: {0} of {1} connections are in use")]
+    DBPoolExhaustedError(u64, u64),
     #[error("error executing DB query: {0}")]
     DBQueryError(#[from] tokio_postgres::Error),
     #[error("error initialising database: {0}")]
@@ -19,66 +30,175 @@ pub enum Error {
     DBMigrateError(String, tokio_postgres::Error),
     #[error("migration for record from database \"{0}\" not found in migration list")]
     DBMigrationNotFoundError(String),
+    #[error("database is at migration {1:?}, but at least {0:?} is expected")]
+    DBMigrationVersionError(Option<String>, Option<String>),
+    #[error("invalid config:\n  {}", .0.join("\n  "))]
+    InvalidConfig(Vec<String>),
+    #[error("error initialising tracing: {0}")]
+    TracingInitError(opentelemetry::trace::TraceError),
+    #[error("error pushing metrics: {0}")]
+    PushMetricsError(String),
     #[error("error reading file: {0}")]
     ReadFileError(#[from] std::io::Error),
     #[error("error reading path from directory: {0}")]
     DirectoryListError(std::io::Error),
+    #[error("service is shutting down")]
+    ShuttingDownError,
+    #[error("injected fault with status {0}")]
+    InjectedFault(u16),
+    #[error("missing or invalid credentials")]
+    Unauthorized,
+    #[error("invalid bearer token: {0}")]
+    InvalidToken(String),
+    #[error("error loading JWKS: {0}")]
+    JwksError(String),
+    #[error("login {0} is already taken")]
+    LoginTaken(String),
+    #[error("invalid login or password")]
+    InvalidCredentials,
+    #[error("password should be at least {0} characters long")]
+    WeakPassword(usize),
+    #[error("error hashing password: {0}")]
+    PasswordHashError(String),
+    #[error("rate limit exceeded: {0}")]
+    RateLimited(crate::rate_limit::Exceeded),
+    #[error("CORS request is forbidden: {0}")]
+    CorsForbidden(String),
+    #[error("request body is larger than {0} bytes")]
+    PayloadTooLarge(u64),
+    #[error("could not read request body: {0}")]
+    BodyReadError(String),
+    #[error("request was not handled within {0:?}")]
+    RequestTimeout(std::time::Duration),
+    #[error("too many requests in flight")]
+    Overloaded,
+    #[error("caller lacks {0} scope")]
+    Forbidden(crate::auth::Scope),
+    #[error("invalid API key command: {0}")]
+    InvalidKeyCommand(String),
+    #[error("invalid fault rule: {0}")]
+    InvalidFaultRule(String),
     // We introduce custom NotFound type since
     #[error("User with id {0} not found")]
     UserNotFound(i32),
+    #[error("caller has no user record")]
+    OwnUserNotFound,
+    #[error("caller already has user record")]
+    OwnUserExists,
 }
 
+impl warp::reject::Reject for Error {}
+
 impl Reply for Error {
     fn into_response(self) -> reply::Response {
-        let (code, message) = map_error(&self);
-        error_reply(code, message).into_response()
+        error_response(&self)
     }
 }
 
 #[derive(Serialize)]
 struct ErrorResponse {
     message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
 }
 
 fn map_error(err: &Error) -> (StatusCode, &'static str) {
     match err {
-        Error::UserNotFound(_) => (StatusCode::NOT_FOUND, "User not found"),
+        Error::UserNotFound(_) | Error::OwnUserNotFound => {
+            (StatusCode::NOT_FOUND, "User not found")
+        }
+        // Retries didn't help, but it's still worth trying later
+        e if matches!(e, Error::DBCircuitOpen(_)) || crate::db::retry::is_transient(e) => {
+            (StatusCode::SERVICE_UNAVAILABLE, "Database Unavailable")
+        }
         Error::DBQueryError(_) => (StatusCode::BAD_REQUEST, "Could not Execute request"),
+        Error::InjectedFault(code) => (
+            StatusCode::from_u16(*code).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR),
+            "Injected Fault",
+        ),
+        Error::InvalidFaultRule(_) => (StatusCode::BAD_REQUEST, "Invalid Fault Rule"),
+        Error::Unauthorized => (StatusCode::UNAUTHORIZED, "Unauthorized"),
+        Error::InvalidToken(_) => (StatusCode::UNAUTHORIZED, "Invalid Token"),
+        Error::LoginTaken(_) => (StatusCode::CONFLICT, "Login Taken"),
+        Error::OwnUserExists => (StatusCode::CONFLICT, "User Exists"),
+        Error::InvalidCredentials => (StatusCode::UNAUTHORIZED, "Invalid Credentials"),
+        Error::WeakPassword(_) => (StatusCode::BAD_REQUEST, "Password Too Short"),
+        Error::Forbidden(_) => (StatusCode::FORBIDDEN, "Forbidden"),
+        Error::CorsForbidden(_) => (StatusCode::FORBIDDEN, "CORS Forbidden"),
+        Error::RateLimited(_) => (StatusCode::TOO_MANY_REQUESTS, "Too Many Requests"),
+        Error::PayloadTooLarge(_) => (StatusCode::PAYLOAD_TOO_LARGE, "Payload Too Large"),
+        Error::BodyReadError(_) => (StatusCode::BAD_REQUEST, "Could not Read Body"),
+        Error::RequestTimeout(_) => (StatusCode::GATEWAY_TIMEOUT, "Request Timed Out"),
+        Error::Overloaded => (StatusCode::SERVICE_UNAVAILABLE, "Service Overloaded"),
         _ => {
-            eprintln!("unhandled application error: {:?}", err);
+            log_eprintln!("unhandled application error: {:?}", err);
             (StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error")
         }
     }
 }
 
-fn error_reply(code: StatusCode, message: &str) -> impl Reply {
+/// Reply for application error, with headers which tell client what to do
+fn error_response(err: &Error) -> reply::Response {
+    let (code, message) = map_error(err);
+    let mut res = error_reply(code, message);
+    match err {
+        Error::RateLimited(exceeded) => exceeded.add_headers(res.headers_mut()),
+        // Load is usually shed for a moment only
+        Error::Overloaded => {
+            res.headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from_static("1"));
+        }
+        Error::DBCircuitOpen(retry_after) => {
+            // Rounded up, so client doesn't come back while it's still open
+            let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+            res.headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from(seconds.max(1)));
+        }
+        e if crate::db::retry::is_transient(e) => {
+            res.headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from_static("1"));
+        }
+        _ => {}
+    }
+    res
+}
+
+fn error_reply(code: StatusCode, message: &str) -> reply::Response {
     let json = warp::reply::json(&ErrorResponse {
         message: message.into(),
+        request_id: context::request_id(),
     });
 
-    return warp::reply::with_status(json, code);
+    let mut res = warp::reply::with_status(json, code).into_response();
+    // Tells client which credentials are accepted
+    if code == StatusCode::UNAUTHORIZED {
+        res.headers_mut()
+            .insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+    }
+    res
 }
 
 pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible> {
     let code;
     let message;
 
-    println!("Error: {:?}", err);
+    log_println!("Error: {:?}", err);
     if err.is_not_found() {
         code = StatusCode::NOT_FOUND;
         message = "Not Found";
-    } else if let Some(_) = err.find::<warp::filters::body::BodyDeserializeError>() {
+    } else if err
+        .find::<warp::filters::body::BodyDeserializeError>()
+        .is_some()
+    {
         code = StatusCode::BAD_REQUEST;
         message = "Invalid Body";
     } else if let Some(e) = err.find::<Error>() {
-        let (mapped_code, mapped_message) = map_error(e);
-        code = mapped_code;
-        message = mapped_message;
-    } else if let Some(_) = err.find::<warp::reject::MethodNotAllowed>() {
+        return Ok(error_response(e));
+    } else if err.find::<warp::reject::MethodNotAllowed>().is_some() {
         code = StatusCode::METHOD_NOT_ALLOWED;
         message = "Method Not Allowed";
     } else {
-        eprintln!("unhandled error: {:?}", err);
+        log_eprintln!("unhandled error: {:?}", err);
         code = StatusCode::INTERNAL_SERVER_ERROR;
         message = "Internal Server Error";
     }
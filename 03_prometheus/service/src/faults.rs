@@ -0,0 +1,300 @@
+use std::convert::Infallible;
+use std::sync::{Arc, RwLock};
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tokio::time::{sleep, Duration};
+use tracing::instrument;
+use warp::http::{HeaderMap, Method, StatusCode};
+use warp::path::FullPath;
+use warp::reply::{json, with_status};
+use warp::{Filter, Rejection, Reply};
+
+use crate::auth::{self, Scope};
+use crate::context::{self, log_println};
+use crate::error::Error::{InjectedFault, InvalidFaultRule};
+use crate::routes::{enabled_if, path_matches};
+
+// Per-request faults, e.g. for ad-hoc checks with curl
+const LATENCY_HEADER: &str = "x-fault-latency-ms";
+const ERROR_RATE_HEADER: &str = "x-fault-error-rate";
+const ERROR_STATUS_HEADER: &str = "x-fault-error-status";
+
+fn default_error_status() -> u16 {
+    500
+}
+
+/// Fault applied to all requests matching method & path. Path is a route
+/// pattern, e.g. `/user/*` or `/user/**`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct FaultRule {
+    pub method: Option<String>,
+    pub path: String,
+    #[serde(default)]
+    pub latency_ms: u64,
+    #[serde(default)]
+    pub error_rate: f64,
+    #[serde(default = "default_error_status")]
+    pub error_status: u16,
+}
+
+impl FaultRule {
+    fn matches(&self, method: &Method, path: &str) -> bool {
+        if let Some(m) = &self.method {
+            if !m.eq_ignore_ascii_case(method.as_str()) {
+                return false;
+            }
+        }
+        path_matches(&self.path, path)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if !(0.0..=1.0).contains(&self.error_rate) {
+            return Err(format!("error_rate {} is not in [0, 1]", self.error_rate));
+        }
+        if !(400..600).contains(&self.error_status) {
+            return Err(format!(
+                "error_status {} is not an error status",
+                self.error_status
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct FaultRules {
+    pub rules: Vec<FaultRule>,
+}
+
+/// Fault injection for SLO & alert rehearsal. Never enabled in production.
+#[derive(Clone)]
+pub struct Faults {
+    enabled: bool,
+    rules: Arc<RwLock<Vec<FaultRule>>>,
+}
+
+impl Faults {
+    pub fn new(enabled: bool) -> Faults {
+        Faults {
+            enabled,
+            rules: Arc::new(RwLock::new(vec![])),
+        }
+    }
+
+    fn rule_for(&self, method: &Method, path: &str, headers: &HeaderMap) -> Option<FaultRule> {
+        let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+        if header(LATENCY_HEADER).is_some() || header(ERROR_RATE_HEADER).is_some() {
+            return Some(FaultRule {
+                method: None,
+                path: path.to_owned(),
+                latency_ms: header(LATENCY_HEADER)
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or(0),
+                error_rate: header(ERROR_RATE_HEADER)
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or(0.0),
+                error_status: header(ERROR_STATUS_HEADER)
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or_else(default_error_status),
+            })
+            .filter(|r| r.validate().is_ok());
+        }
+
+        let rules = self.rules.read().unwrap();
+        rules.iter().find(|r| r.matches(method, path)).cloned()
+    }
+
+    async fn inject(
+        &self,
+        method: Method,
+        path: FullPath,
+        headers: HeaderMap,
+    ) -> Result<(), Rejection> {
+        if !self.enabled {
+            return Ok(());
+        }
+        let rule = match self.rule_for(&method, path.as_str(), &headers) {
+            Some(rule) => rule,
+            None => return Ok(()),
+        };
+
+        if rule.latency_ms > 0 {
+            context::mark_fault_injected();
+            sleep(Duration::from_millis(rule.latency_ms)).await;
+        }
+        if rule.error_rate > 0.0 && rand::thread_rng().gen::<f64>() < rule.error_rate {
+            context::mark_fault_injected();
+            return Err(warp::reject::custom(InjectedFault(rule.error_status)));
+        }
+        Ok(())
+    }
+}
+
+/// Delays or fails request according to matching fault rule. Passes
+/// everything through if fault injection is disabled.
+pub fn inject(faults: Faults) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::method()
+        .and(warp::path::full())
+        .and(warp::header::headers_cloned())
+        .and_then(move |method, path, headers| {
+            let faults = faults.clone();
+            async move { faults.inject(method, path, headers).await }
+        })
+        .untuple_one()
+}
+
+#[instrument(skip(faults))]
+async fn get_faults_handler(faults: Faults) -> Result<impl Reply, Infallible> {
+    let rules = faults.rules.read().unwrap().clone();
+    Ok(json(&FaultRules { rules }))
+}
+
+#[instrument(skip(faults))]
+async fn set_faults_handler(body: FaultRules, faults: Faults) -> Result<impl Reply, Rejection> {
+    for rule in &body.rules {
+        rule.validate()
+            .map_err(|e| warp::reject::custom(InvalidFaultRule(e)))?;
+    }
+    log_println!("Fault rules set: {:?}", body.rules);
+    *faults.rules.write().unwrap() = body.rules.clone();
+    Ok(json(&body))
+}
+
+#[instrument(skip(faults))]
+async fn clear_faults_handler(faults: Faults) -> Result<impl Reply, Infallible> {
+    log_println!("Fault rules cleared");
+    faults.rules.write().unwrap().clear();
+    Ok(with_status(warp::reply(), StatusCode::NO_CONTENT))
+}
+
+/// `/admin/faults` endpoint to view & change fault rules at runtime
+pub fn admin_router<A>(
+    faults: Faults,
+    auth: &A,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone
+where
+    A: Filter<Extract = (), Error = Rejection> + Clone + Send + Sync + 'static,
+{
+    let enabled = faults.enabled;
+    let with_faults = warp::any().map(move || faults.clone());
+
+    let get_route = warp::get()
+        .and(with_faults.clone())
+        .and_then(get_faults_handler);
+    let set_route = warp::put()
+        .and(warp::body::json())
+        .and(with_faults.clone())
+        .and_then(set_faults_handler);
+    let clear_route = warp::delete()
+        .and(with_faults)
+        .and_then(clear_faults_handler);
+
+    warp::path!("admin" / "faults")
+        .and(enabled_if(enabled))
+        .and(auth.clone())
+        .and(auth::require(Scope::Admin))
+        .and(get_route.or(set_route).or(clear_route))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(method: Option<&str>, path: &str) -> FaultRule {
+        FaultRule {
+            method: method.map(|m| m.to_owned()),
+            path: path.to_owned(),
+            latency_ms: 0,
+            error_rate: 1.0,
+            error_status: 503,
+        }
+    }
+
+    #[test]
+    fn rule_matches_method_and_path() {
+        let get_user = rule(Some("get"), "/user/*");
+        assert!(get_user.matches(&Method::GET, "/user/1"));
+        assert!(!get_user.matches(&Method::DELETE, "/user/1"));
+        assert!(!get_user.matches(&Method::GET, "/user"));
+
+        let any = rule(None, "/user/**");
+        assert!(any.matches(&Method::DELETE, "/user"));
+        assert!(any.matches(&Method::POST, "/user/1/keys"));
+        assert!(!any.matches(&Method::POST, "/users"));
+    }
+
+    #[test]
+    fn validates_rate_and_status() {
+        assert!(rule(None, "/").validate().is_ok());
+        let too_often = FaultRule {
+            error_rate: 1.5,
+            ..rule(None, "/")
+        };
+        assert!(too_often.validate().is_err());
+        let not_error = FaultRule {
+            error_status: 200,
+            ..rule(None, "/")
+        };
+        assert!(not_error.validate().is_err());
+    }
+
+    #[test]
+    fn headers_override_rules() {
+        let faults = Faults::new(true);
+        faults.rules.write().unwrap().push(rule(None, "/user/**"));
+
+        let found = faults.rule_for(&Method::GET, "/user/1", &HeaderMap::new());
+        assert_eq!(found.map(|r| r.error_status), Some(503));
+        let found = faults.rule_for(&Method::GET, "/health", &HeaderMap::new());
+        assert!(found.is_none());
+
+        let mut headers = HeaderMap::new();
+        headers.insert(LATENCY_HEADER, "250".parse().unwrap());
+        headers.insert(ERROR_RATE_HEADER, "0.5".parse().unwrap());
+        let found = faults.rule_for(&Method::GET, "/user/1", &headers).unwrap();
+        assert_eq!(found.path, "/user/1");
+        assert_eq!(found.latency_ms, 250);
+        assert_eq!(found.error_rate, 0.5);
+        assert_eq!(found.error_status, 500);
+
+        // Invalid header values don't fall back to rules
+        headers.insert(ERROR_STATUS_HEADER, "200".parse().unwrap());
+        assert!(faults.rule_for(&Method::GET, "/user/1", &headers).is_none());
+    }
+
+    #[tokio::test]
+    async fn injects_only_when_enabled() {
+        for enabled in [false, true] {
+            let faults = Faults::new(enabled);
+            faults.rules.write().unwrap().push(rule(None, "/**"));
+            let passed = warp::test::request()
+                .path("/user/1")
+                .filter(&inject(faults))
+                .await;
+            match passed {
+                Ok(()) => assert!(!enabled),
+                Err(rejection) => {
+                    assert!(enabled);
+                    assert!(matches!(rejection.find(), Some(InjectedFault(503))));
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn keeps_rules_if_any_is_invalid() {
+        let faults = Faults::new(true);
+        let body = FaultRules {
+            rules: vec![
+                rule(None, "/user"),
+                FaultRule {
+                    error_rate: -1.0,
+                    ..rule(None, "/health")
+                },
+            ],
+        };
+        assert!(set_faults_handler(body, faults.clone()).await.is_err());
+        assert!(faults.rules.read().unwrap().is_empty());
+    }
+}
@@ -1,13 +1,25 @@
+use std::collections::HashMap;
 use std::convert::Infallible;
 
+use crate::auth::session::{Sessions, LOCAL_SUBJECT_PREFIX};
+use crate::auth::{self, password, require, Scope};
+use crate::context::log_println;
+use crate::db::credentials::{self, Credentials};
 use crate::{db, with_db, DBPool, Result};
+use chrono::Utc;
 use serde::Serialize;
+use tracing::instrument;
 use warp::Filter;
 use warp::{http::StatusCode, Reply};
 
-use crate::data::{UserCreateRequest, UserCreateResponce, UserUpdateRequest, UserUpdateResponse};
+use crate::data::{
+    LoginRequest, LoginResponse, PasswordChangeRequest, RegisterRequest, UserCreateRequest,
+    UserCreateResponce, UserUpdateRequest, UserUpdateResponse,
+};
 use crate::error::Error;
-use crate::metrics::REGISTRY;
+use crate::health::{self, ProbeReport};
+use crate::metrics::{exposition, slo};
+use crate::routes::enabled_if;
 use warp::reply::{json, with_status};
 
 type InfalliableResult<T> = std::result::Result<T, Infallible>;
@@ -16,6 +28,7 @@ type InfalliableResult<T> = std::result::Result<T, Infallible>;
 struct HealthCheckReply {
     status: String,
     db: String,
+    db_circuit_breaker: &'static str,
 }
 
 fn result_reply<T: Reply, E: Reply>(res: std::result::Result<T, E>) -> impl Reply {
@@ -25,17 +38,19 @@ fn result_reply<T: Reply, E: Reply>(res: std::result::Result<T, E>) -> impl Repl
     }
 }
 
+#[instrument]
 pub async fn root_handler() -> InfalliableResult<impl Reply> {
     Ok("Hello, world!")
 }
 
+#[instrument(skip(db_pool))]
 pub async fn health_handler(db_pool: DBPool) -> InfalliableResult<impl Reply> {
     let mut is_ok = true;
     let db = match db::check_db(&db_pool).await {
         Ok(()) => "OK".into(),
         Err(e) => {
             is_ok = false;
-            format!("FAIL: {}", e.to_string())
+            format!("FAIL: {}", e)
         }
     };
     let status = if is_ok { "OK".into() } else { "FAIL".into() };
@@ -45,143 +60,479 @@ pub async fn health_handler(db_pool: DBPool) -> InfalliableResult<impl Reply> {
         StatusCode::INTERNAL_SERVER_ERROR
     };
     Ok(with_status(
-        json(&HealthCheckReply { status, db }),
+        json(&HealthCheckReply {
+            status,
+            db,
+            db_circuit_breaker: db::breaker::state(),
+        }),
         status_code,
     ))
 }
 
-async fn metrics_handler() -> InfalliableResult<impl Reply> {
-    use prometheus::Encoder;
-    let encoder = prometheus::TextEncoder::new();
-
-    let mut buffer = Vec::new();
-    if let Err(e) = encoder.encode(&REGISTRY.gather(), &mut buffer) {
-        eprintln!("could not encode custom metrics: {}", e);
+fn probe_reply(report: ProbeReport, query: HashMap<String, String>) -> impl Reply {
+    let status_code = if report.is_ok() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
     };
-    let mut res = match String::from_utf8(buffer.clone()) {
-        Ok(v) => v,
-        Err(e) => {
-            eprintln!("custom metrics could not be from_utf8'd: {}", e);
-            String::default()
-        }
+    let verbose = query.contains_key("verbose");
+    let body = if verbose {
+        json(&report).into_response()
+    } else {
+        report.status.into_response()
     };
-    buffer.clear();
+    with_status(body, status_code)
+}
 
-    let mut buffer = Vec::new();
-    if let Err(e) = encoder.encode(&prometheus::gather(), &mut buffer) {
-        eprintln!("could not encode prometheus metrics: {}", e);
-    };
-    let res_custom = match String::from_utf8(buffer.clone()) {
-        Ok(v) => v,
-        Err(e) => {
-            eprintln!("prometheus metrics could not be from_utf8'd: {}", e);
-            String::default()
-        }
-    };
-    buffer.clear();
+#[instrument]
+pub async fn livez_handler(query: HashMap<String, String>) -> InfalliableResult<impl Reply> {
+    Ok(probe_reply(health::liveness().await, query))
+}
+
+#[instrument(skip(db_pool))]
+pub async fn readyz_handler(
+    query: HashMap<String, String>,
+    db_pool: DBPool,
+) -> InfalliableResult<impl Reply> {
+    Ok(probe_reply(health::readiness(&db_pool).await, query))
+}
 
-    res.push_str(&res_custom);
-    Ok(res)
+#[instrument(skip(db_pool))]
+pub async fn startupz_handler(
+    query: HashMap<String, String>,
+    db_pool: DBPool,
+) -> InfalliableResult<impl Reply> {
+    Ok(probe_reply(health::startup(&db_pool).await, query))
 }
 
-pub fn router(
+/// Current SLO burn rates, same as `slo_burn_rate` metric but with
+/// good/total counts and multi-window alert state
+#[instrument]
+pub async fn slo_handler() -> InfalliableResult<impl Reply> {
+    Ok(json(&slo::report()))
+}
+
+/// Routes for getting credentials, which are open to anyone
+fn login_router<A>(
     db_pool: &DBPool,
-) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    let root_router = warp::path::end().and(warp::get()).and_then(root_handler);
+    sessions: &Sessions,
+    auth: &A,
+) -> impl warp::Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone
+where
+    A: warp::Filter<Extract = (), Error = warp::Rejection> + Clone + Send + Sync + 'static,
+{
+    let register_route = warp::path!("auth" / "register")
+        .and(warp::post())
+        .and(auth.clone())
+        .and(warp::body::json())
+        .and(with_db(db_pool.clone()))
+        .then(register_handler)
+        .map(result_reply);
+    let sessions = sessions.clone();
+    let login_route = warp::path!("auth" / "login")
+        .and(warp::post())
+        .and(auth.clone())
+        .and(warp::body::json())
+        .and(with_db(db_pool.clone()))
+        .and(warp::any().map(move || sessions.clone()))
+        .then(login_handler)
+        .map(result_reply);
+
+    register_route.or(login_route)
+}
+
+/// All service routes. Each route authenticates caller with `auth` once
+/// path & method match, so that unknown paths are not found rather than
+/// unauthorized.
+pub fn router<A>(
+    db_pool: &DBPool,
+    sessions: &Sessions,
+    expose_metrics: bool,
+    auth: &A,
+) -> impl warp::Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone
+where
+    A: warp::Filter<Extract = (), Error = warp::Rejection> + Clone + Send + Sync + 'static,
+{
+    let root_router = warp::path::end()
+        .and(warp::get())
+        .and(auth.clone())
+        .and_then(root_handler);
     let health_router = warp::path!("health")
+        .and(auth.clone())
         .and(with_db(db_pool.clone()))
         .and_then(health_handler);
+    let slo_router = warp::path!("slo")
+        .and(warp::get())
+        .and(auth.clone())
+        .and_then(slo_handler);
 
-    let metrics_router = warp::path!("metrics")
+    // Metrics can be served on separate port instead
+    let metrics_router = enabled_if(expose_metrics)
+        .and(warp::path!("metrics"))
         .and(warp::get())
-        .and_then(metrics_handler);
+        .and(auth.clone())
+        .and(exposition::handler());
 
     root_router
         .or(health_router)
+        .or(probe_router(db_pool, auth))
+        .or(slo_router)
         .or(metrics_router)
-        .or(user_router(&db_pool))
+        .or(login_router(db_pool, sessions, auth))
+        .or(session_router(db_pool, sessions, auth))
+        .or(user_router(db_pool, auth))
 }
 
-fn user_router(
+/// Kubernetes probes. Also served on metrics port when clients have to
+/// present certificate, which kubelet can't do.
+pub fn probe_router<A>(
     db_pool: &DBPool,
-) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    auth: &A,
+) -> impl warp::Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone
+where
+    A: warp::Filter<Extract = (), Error = warp::Rejection> + Clone + Send + Sync + 'static,
+{
+    let livez_router = warp::path!("livez")
+        .and(warp::get())
+        .and(auth.clone())
+        .and(warp::query::<HashMap<String, String>>())
+        .and_then(livez_handler);
+    let readyz_router = warp::path!("readyz")
+        .and(warp::get())
+        .and(auth.clone())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(with_db(db_pool.clone()))
+        .and_then(readyz_handler);
+    let startupz_router = warp::path!("startupz")
+        .and(warp::get())
+        .and(auth.clone())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(with_db(db_pool.clone()))
+        .and_then(startupz_handler);
+
+    livez_router.or(readyz_router).or(startupz_router)
+}
+
+/// Routes for callers logged in with password
+fn session_router<A>(
+    db_pool: &DBPool,
+    sessions: &Sessions,
+    auth: &A,
+) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+where
+    A: warp::Filter<Extract = (), Error = warp::Rejection> + Clone + Send + Sync + 'static,
+{
+    let logout_route = warp::path!("auth" / "logout")
+        .and(warp::post())
+        .and(auth.clone())
+        .and(auth::session())
+        .and(with_db(db_pool.clone()))
+        .then(logout_handler)
+        .map(result_reply);
+    let sessions = sessions.clone();
+    let password_route = warp::path!("auth" / "password")
+        .and(warp::post())
+        .and(auth.clone())
+        .and(warp::body::json())
+        .and(auth::session())
+        .and(with_db(db_pool.clone()))
+        .and(warp::any().map(move || sessions.clone()))
+        .then(change_password_handler)
+        .map(result_reply);
+
+    logout_route.or(password_route)
+}
+
+fn user_router<A>(
+    db_pool: &DBPool,
+    auth: &A,
+) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+where
+    A: warp::Filter<Extract = (), Error = warp::Rejection> + Clone + Send + Sync + 'static,
+{
     let get_users_route = warp::path!("user")
         .and(warp::get())
+        .and(auth.clone())
+        .and(require(Scope::UsersRead))
+        .and(auth::owner())
         .and(with_db(db_pool.clone()))
         .then(get_users_handler)
         .map(result_reply);
 
     let get_user_route = warp::path!("user" / i32)
         .and(warp::get())
+        .and(auth.clone())
+        .and(require(Scope::UsersRead))
+        .and(auth::owner())
         .and(with_db(db_pool.clone()))
         .then(get_user_handler)
         .map(result_reply);
 
     let create_user_route = warp::path!("user")
         .and(warp::post())
+        .and(auth.clone())
+        .and(require(Scope::UsersWrite))
         .and(warp::body::json())
+        .and(auth::owner())
         .and(with_db(db_pool.clone()))
         .then(create_user_handler)
         .map(result_reply);
 
     let update_user_route = warp::path!("user" / i32)
         .and(warp::put())
+        .and(auth.clone())
+        .and(require(Scope::UsersWrite))
         .and(warp::body::json())
+        .and(auth::owner())
         .and(with_db(db_pool.clone()))
         .then(update_user_handler)
         .map(result_reply);
 
     let delete_user_route = warp::path!("user" / i32)
         .and(warp::delete())
+        .and(auth.clone())
+        .and(require(Scope::UsersWrite))
+        .and(auth::require_or_owner(Scope::Admin))
+        .and(auth::owner())
         .and(with_db(db_pool.clone()))
         .then(delete_user_handler)
         .map(result_reply);
 
-    get_user_route
+    // Same as routes above for caller's own record
+    let get_me_route = warp::path!("user" / "me")
+        .and(warp::get())
+        .and(auth.clone())
+        .and(require(Scope::UsersRead))
+        .and(auth::subject())
+        .and(with_db(db_pool.clone()))
+        .then(|subject, db_pool: DBPool| async move {
+            let (id, owner) = me(subject, &db_pool).await?;
+            get_user_handler(id, owner, db_pool).await
+        })
+        .map(result_reply);
+    let update_me_route = warp::path!("user" / "me")
+        .and(warp::put())
+        .and(auth.clone())
+        .and(require(Scope::UsersWrite))
+        .and(warp::body::json())
+        .and(auth::subject())
+        .and(with_db(db_pool.clone()))
+        .then(|body, subject, db_pool: DBPool| async move {
+            let (id, owner) = me(subject, &db_pool).await?;
+            update_user_handler(id, body, owner, db_pool).await
+        })
+        .map(result_reply);
+    let delete_me_route = warp::path!("user" / "me")
+        .and(warp::delete())
+        .and(auth.clone())
+        .and(require(Scope::UsersWrite))
+        .and(auth::require_or_owner(Scope::Admin))
+        .and(auth::subject())
+        .and(with_db(db_pool.clone()))
+        .then(|subject, db_pool: DBPool| async move {
+            let (id, owner) = me(subject, &db_pool).await?;
+            delete_user_handler(id, owner, db_pool).await
+        })
+        .map(result_reply);
+
+    get_me_route
+        .or(update_me_route)
+        .or(delete_me_route)
+        .or(get_user_route)
         .or(get_users_route)
         .or(create_user_route)
         .or(update_user_route)
         .or(delete_user_route)
 }
 
-pub async fn create_user_handler(body: UserCreateRequest, db_pool: DBPool) -> Result<impl Reply> {
-    let user = db::create_user(&db_pool, body).await?;
+/// Id of caller's own record, along with owner to access it with
+async fn me(subject: Option<String>, db_pool: &DBPool) -> Result<(i32, Option<String>)> {
+    let subject = subject.ok_or(Error::OwnUserNotFound)?;
+    match db::get_user_id_by_subject(db_pool, &subject).await? {
+        Some(id) => Ok((id, Some(subject))),
+        None => Err(Error::OwnUserNotFound),
+    }
+}
+
+/// Record created by end user belongs to them
+#[instrument(skip(body, db_pool))]
+pub async fn create_user_handler(
+    body: UserCreateRequest,
+    owner: Option<String>,
+    db_pool: DBPool,
+) -> Result<impl Reply> {
+    let user = db::create_user(&db_pool, body, owner.as_deref()).await?;
     Ok(json(&UserCreateResponce::of(user)))
 }
 
-pub async fn get_users_handler(db_pool: DBPool) -> Result<impl Reply> {
-    let todos = db::get_users(&db_pool).await?;
+/// End users only see their own record
+#[instrument(skip(db_pool))]
+pub async fn get_users_handler(owner: Option<String>, db_pool: DBPool) -> Result<impl Reply> {
+    let todos = db::get_users(&db_pool, owner.as_deref()).await?;
     Ok(json::<Vec<_>>(
-        &todos
-            .into_iter()
-            .map(|t| UserUpdateResponse::of(t))
-            .collect(),
+        &todos.into_iter().map(UserUpdateResponse::of).collect(),
     ))
 }
 
-pub async fn get_user_handler(id: i32, db_pool: DBPool) -> Result<impl Reply> {
-    let user = db::get_user(&db_pool, id).await?;
+// Other users' records are reported as missing, so that ids can't be
+// enumerated
+#[instrument(skip(db_pool))]
+pub async fn get_user_handler(
+    id: i32,
+    owner: Option<String>,
+    db_pool: DBPool,
+) -> Result<impl Reply> {
+    let user = db::get_user(&db_pool, id, owner.as_deref()).await?;
     match user {
         Some(user) => Ok(json(&UserUpdateResponse::of(user))),
-        None => Err(Error::UserNotFound(id).into()),
+        None => Err(Error::UserNotFound(id)),
     }
 }
 
+#[instrument(skip(body, db_pool))]
 pub async fn update_user_handler(
     id: i32,
     body: UserUpdateRequest,
+    owner: Option<String>,
     db_pool: DBPool,
 ) -> Result<impl Reply> {
-    match db::update_user(&db_pool, id, body).await? {
+    match db::update_user(&db_pool, id, body, owner.as_deref()).await? {
         Some(u) => Ok(json(&UserUpdateResponse::of(u))),
-        None => Err(Error::UserNotFound(id).into()),
+        None => Err(Error::UserNotFound(id)),
     }
 }
 
-pub async fn delete_user_handler(id: i32, db_pool: DBPool) -> Result<impl Reply> {
-    if db::delete_user(&db_pool, id).await? {
+#[instrument(skip(db_pool))]
+pub async fn delete_user_handler(
+    id: i32,
+    owner: Option<String>,
+    db_pool: DBPool,
+) -> Result<impl Reply> {
+    if db::delete_user(&db_pool, id, owner.as_deref()).await? {
         Ok(StatusCode::NO_CONTENT)
     } else {
-        Err(Error::UserNotFound(id).into())
+        Err(Error::UserNotFound(id))
+    }
+}
+
+fn check_password_length(password: &str) -> Result<()> {
+    if password.chars().count() < password::MIN_PASSWORD_LENGTH {
+        return Err(Error::WeakPassword(password::MIN_PASSWORD_LENGTH));
     }
+    Ok(())
+}
+
+/// Checks password, counting failed attempts towards lockout. Unknown and
+/// locked logins fail the same way as wrong password, so that existing
+/// logins can't be told apart. Lock is only logged.
+async fn check_password(
+    db_pool: &DBPool,
+    sessions: &Sessions,
+    credentials: Option<Credentials>,
+    password: String,
+) -> Result<Credentials> {
+    let now = Utc::now();
+    // Password is checked even for locked login, so it takes as long as
+    // for any other
+    let hash = credentials.as_ref().map(|c| c.password_hash.clone());
+    let matches = password::verify(hash, password).await?;
+    if let Some(credentials) = &credentials {
+        if let Some(locked_until) = credentials.locked_until.filter(|t| *t > now) {
+            log_println!(
+                "Login {} is locked until {}",
+                credentials.login,
+                locked_until
+            );
+            return Err(Error::InvalidCredentials);
+        }
+    }
+    match credentials {
+        Some(credentials) if matches => {
+            credentials::reset_failed_attempts(db_pool, credentials.user_id).await?;
+            Ok(credentials)
+        }
+        Some(credentials) => {
+            let locked_until = credentials::record_failed_attempt(
+                db_pool,
+                credentials.user_id,
+                sessions.max_failed_attempts,
+                sessions.lockout,
+            )
+            .await?;
+            if let Some(locked_until) = locked_until.filter(|t| *t > now) {
+                log_println!(
+                    "Login {} is locked until {} after failed attempts",
+                    credentials.login,
+                    locked_until
+                );
+            }
+            Err(Error::InvalidCredentials)
+        }
+        None => Err(Error::InvalidCredentials),
+    }
+}
+
+/// Registered user owns their record, as if created by gateway user
+#[instrument(skip(body, db_pool), fields(login = %body.login))]
+pub async fn register_handler(body: RegisterRequest, db_pool: DBPool) -> Result<impl Reply> {
+    check_password_length(&body.password)?;
+    let subject = format!("{}{}", LOCAL_SUBJECT_PREFIX, body.login);
+    let password_hash = password::hash(body.password).await?;
+    let user = credentials::create_user_with_credentials(
+        &db_pool,
+        body.user,
+        &subject,
+        &body.login,
+        &password_hash,
+    )
+    .await?;
+    Ok(with_status(
+        json(&UserCreateResponce::of(user)),
+        StatusCode::CREATED,
+    ))
+}
+
+#[instrument(skip(body, db_pool, sessions), fields(login = %body.login))]
+pub async fn login_handler(
+    body: LoginRequest,
+    db_pool: DBPool,
+    sessions: Sessions,
+) -> Result<impl Reply> {
+    let stored = credentials::get_credentials(&db_pool, &body.login).await?;
+    let stored = check_password(&db_pool, &sessions, stored, body.password).await?;
+    let subject = format!("{}{}", LOCAL_SUBJECT_PREFIX, stored.login);
+    let (token, expires_at) = sessions.issue(&db_pool, stored.user_id, &subject).await?;
+    Ok(json(&LoginResponse { token, expires_at }))
+}
+
+#[instrument(skip(session, db_pool))]
+pub async fn logout_handler(
+    session: Option<(String, String)>,
+    db_pool: DBPool,
+) -> Result<impl Reply> {
+    let (session_id, _) = session.ok_or(Error::Unauthorized)?;
+    credentials::revoke_session(&db_pool, &session_id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Other sessions are ended, since password change is usually done when
+/// it might have leaked
+#[instrument(skip(body, session, db_pool, sessions))]
+pub async fn change_password_handler(
+    body: PasswordChangeRequest,
+    session: Option<(String, String)>,
+    db_pool: DBPool,
+    sessions: Sessions,
+) -> Result<impl Reply> {
+    let (session_id, subject) = session.ok_or(Error::Unauthorized)?;
+    check_password_length(&body.new_password)?;
+    let user_id = db::get_user_id_by_subject(&db_pool, &subject)
+        .await?
+        .ok_or(Error::Unauthorized)?;
+    let stored = credentials::get_credentials_by_user(&db_pool, user_id).await?;
+    let stored = check_password(&db_pool, &sessions, stored, body.current_password).await?;
+    let password_hash = password::hash(body.new_password).await?;
+    credentials::update_password(&db_pool, stored.user_id, &password_hash).await?;
+    credentials::revoke_other_sessions(&db_pool, stored.user_id, &session_id).await?;
+    Ok(StatusCode::NO_CONTENT)
 }
@@ -0,0 +1,129 @@
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+
+use serde::Serialize;
+use tokio::time::{timeout, Duration};
+
+use crate::error::Error::{DBPoolExhaustedError, ShuttingDownError};
+use crate::{db, DBPool, Result};
+
+// Chart's probes give up after 3 seconds (`timeoutSeconds`), so there's no
+// point to wait for hanging check longer. Checks run concurrently, so whole
+// probe takes about as long as the slowest one.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Once startup checks passed, we never report failed startup again.
+// From that moment it's up to liveness & readiness probes.
+static STARTED: AtomicBool = AtomicBool::new(false);
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Serialize)]
+pub struct CheckResult {
+    pub name: &'static str,
+    pub ok: bool,
+    pub duration_ms: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ProbeReport {
+    pub status: &'static str,
+    pub checks: Vec<CheckResult>,
+}
+
+impl ProbeReport {
+    fn of(checks: Vec<CheckResult>) -> ProbeReport {
+        let status = if checks.iter().all(|c| c.ok) {
+            "OK"
+        } else {
+            "FAIL"
+        };
+        ProbeReport { status, checks }
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.status == "OK"
+    }
+}
+
+async fn run_check<F>(name: &'static str, check: F) -> CheckResult
+where
+    F: Future<Output = Result<()>>,
+{
+    let started_at = Instant::now();
+    let error = match timeout(CHECK_TIMEOUT, check).await {
+        Ok(Ok(())) => None,
+        Ok(Err(e)) => Some(e.to_string()),
+        Err(_) => Some(format!("timed out after {:?}", CHECK_TIMEOUT)),
+    };
+    CheckResult {
+        name,
+        ok: error.is_none(),
+        duration_ms: started_at.elapsed().as_secs_f64() * 1000.0,
+        error,
+    }
+}
+
+async fn check_pool(db_pool: &DBPool) -> Result<()> {
+    let state = db_pool.state().await;
+    if state.in_use >= state.max_open {
+        Err(DBPoolExhaustedError(state.in_use, state.max_open))
+    } else {
+        Ok(())
+    }
+}
+
+/// Makes readiness probe fail, so instance is removed from balancing
+/// while it finishes in-flight requests
+pub fn begin_shutdown() {
+    SHUTTING_DOWN.store(true, Ordering::Relaxed);
+}
+
+async fn check_not_shutting_down() -> Result<()> {
+    if SHUTTING_DOWN.load(Ordering::Relaxed) {
+        Err(ShuttingDownError)
+    } else {
+        Ok(())
+    }
+}
+
+async fn readiness_checks(db_pool: &DBPool) -> Vec<CheckResult> {
+    // Boxed, since all checks together are too large for worker stack
+    let (shutdown, db, db_pool, migrations) = Box::pin(async {
+        tokio::join!(
+            run_check("shutdown", check_not_shutting_down()),
+            run_check("db", db::check_db(db_pool)),
+            run_check("db_pool", check_pool(db_pool)),
+            run_check("migrations", db::migration::check_migration(db_pool)),
+        )
+    })
+    .await;
+    vec![shutdown, db, db_pool, migrations]
+}
+
+/// Process is up and able to serve requests. Intentionally doesn't touch
+/// any dependencies, so their outage doesn't lead to pod restarts.
+pub async fn liveness() -> ProbeReport {
+    ProbeReport::of(vec![run_check("process", async { Ok(()) }).await])
+}
+
+/// Instance can take traffic: database is reachable, pool has free
+/// connections & schema isn't behind version this binary expects.
+pub async fn readiness(db_pool: &DBPool) -> ProbeReport {
+    ProbeReport::of(readiness_checks(db_pool).await)
+}
+
+/// Instance has finished starting up. Passes as soon as readiness
+/// checks succeeded once, and stays passed afterwards.
+pub async fn startup(db_pool: &DBPool) -> ProbeReport {
+    if STARTED.load(Ordering::Relaxed) {
+        return ProbeReport::of(vec![run_check("started", async { Ok(()) }).await]);
+    }
+    let report = ProbeReport::of(readiness_checks(db_pool).await);
+    if report.is_ok() {
+        STARTED.store(true, Ordering::Relaxed);
+    }
+    report
+}
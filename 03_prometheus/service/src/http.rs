@@ -0,0 +1,67 @@
+use std::pin::Pin;
+
+use openssl::ssl::{SslConnector, SslMethod};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_openssl::SslStream;
+use warp::http::{HeaderValue, Request, Response, Uri};
+use warp::hyper::{self, Body};
+
+async fn send_over<S>(stream: S, mut req: Request<Body>) -> Result<Response<Vec<u8>>, String>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (mut sender, conn) = hyper::client::conn::handshake(stream)
+        .await
+        .map_err(|e| e.to_string())?;
+    tokio::spawn(conn);
+
+    // Connection is to the host already, so request only needs path
+    let uri = req.uri().clone();
+    let path = uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+    *req.uri_mut() = path
+        .parse()
+        .map_err(|e: warp::http::uri::InvalidUri| e.to_string())?;
+    let host = HeaderValue::from_str(uri.host().unwrap_or_default()).map_err(|e| e.to_string())?;
+    req.headers_mut().insert("host", host);
+
+    let res = sender.send_request(req).await.map_err(|e| e.to_string())?;
+    let (parts, body) = res.into_parts();
+    let body = hyper::body::to_bytes(body)
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(Response::from_parts(parts, body.to_vec()))
+}
+
+/// Sends request to its absolute `http(s)://` URI. Client from hyper has
+/// no TLS, and the service only makes a few such requests, e.g. to fetch
+/// JWKS or push metrics, so one connection per request is fine.
+pub async fn send(req: Request<Body>) -> Result<Response<Vec<u8>>, String> {
+    let uri: &Uri = req.uri();
+    let host = uri.host().ok_or("URL has no host")?.to_owned();
+    let https = match uri.scheme_str() {
+        Some("https") => true,
+        Some("http") => false,
+        _ => return Err("URL should be http:// or https://".to_owned()),
+    };
+    let port = uri.port_u16().unwrap_or(if https { 443 } else { 80 });
+
+    let tcp = TcpStream::connect((host.as_str(), port))
+        .await
+        .map_err(|e| e.to_string())?;
+    if !https {
+        return send_over(tcp, req).await;
+    }
+    let ssl = SslConnector::builder(SslMethod::tls())
+        .map_err(|e| e.to_string())?
+        .build()
+        .configure()
+        .and_then(|c| c.into_ssl(&host))
+        .map_err(|e| e.to_string())?;
+    let mut stream = SslStream::new(ssl, tcp).map_err(|e| e.to_string())?;
+    Pin::new(&mut stream)
+        .connect()
+        .await
+        .map_err(|e| e.to_string())?;
+    send_over(stream, req).await
+}
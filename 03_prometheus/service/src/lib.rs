@@ -1,22 +1,41 @@
 use std::convert::Infallible;
-use warp::Filter;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::time::Instant;
+use tokio::sync::oneshot;
+use tokio::time::{sleep, Duration};
+use tracing::Instrument;
+use warp::hyper::service::{make_service_fn, service_fn, Service};
+use warp::{Filter, Reply};
 
+use metrics::push::MigrationReport;
 use mobc::{Connection, Pool};
-use mobc_postgres::{
-    tokio_postgres::{self},
-    PgConnectionManager,
-};
-use tokio_postgres::NoTls;
+use mobc_postgres::PgConnectionManager;
+use postgres_openssl::MakeTlsConnector;
 
-type DBCon = Connection<PgConnectionManager<NoTls>>;
-type DBPool = Pool<PgConnectionManager<NoTls>>;
+type DBCon = Connection<PgConnectionManager<MakeTlsConnector>>;
+type DBPool = Pool<PgConnectionManager<MakeTlsConnector>>;
 
+mod auth;
 pub mod config;
+mod context;
+mod cors;
 mod data;
 mod db;
 mod error;
+mod faults;
 mod handler;
+mod health;
+mod http;
+mod limits;
+mod listener;
 mod metrics;
+mod rate_limit;
+mod reload;
+mod routes;
+mod shutdown;
+mod telemetry;
+mod tls;
 
 type Result<T> = std::result::Result<T, error::Error>;
 
@@ -24,52 +43,284 @@ fn with_db(db_pool: DBPool) -> impl Filter<Extract = (DBPool,), Error = Infallib
     warp::any().map(move || db_pool.clone())
 }
 
+async fn push_migration_report(config: &config::Config, report: MigrationReport) {
+    if let Some(url) = &config.pushgateway_url {
+        // Failed push should never fail migration itself
+        let timeout = Duration::from_secs(config.pushgateway_timeout_seconds);
+        if let Err(e) = metrics::push::push_migration_report(url, timeout, &report).await {
+            context::log_eprintln!("{}", e);
+        }
+    }
+}
+
+/// Startup phase shared by binaries: waits until database can be reached
+async fn wait_for_db(config: &config::Config, db_pool: &DBPool) -> Result<()> {
+    context::log_println!("Waiting for database...");
+    db::wait_for_db(
+        db_pool,
+        Duration::from_secs(config.db_wait_timeout_seconds),
+        Duration::from_secs(config.db_wait_max_delay_seconds),
+    )
+    .await
+}
+
 pub async fn migrate(config: &config::Config) {
-    let db_pool = db::create_pool(&config.db_conn_string).expect("database pool can be created");
+    let db_pool = db::create_pool(config).expect("database pool can be created");
 
-    db::migration::migrate(&db_pool)
-        .await
-        .expect("failed to migrate database");
+    let started_at = Instant::now();
+    let res = match wait_for_db(config, &db_pool).await {
+        Ok(()) => db::migration::migrate(&db_pool).await,
+        Err(e) => Err(e),
+    };
+    let report = MigrationReport {
+        mode: "migrate",
+        success: res.is_ok(),
+        duration: started_at.elapsed(),
+        applied: res.as_ref().ok().map(|s| s.applied),
+        last_migration_id: res.as_ref().ok().and_then(|s| s.last_migration_id.clone()),
+    };
+    push_migration_report(config, report).await;
+
+    res.expect("failed to migrate database");
 }
 
 pub async fn wait_for_migrate(config: &config::Config) {
-    let db_pool = db::create_pool(&config.db_conn_string).expect("database pool can be created");
+    let db_pool = db::create_pool(config).expect("database pool can be created");
 
-    db::migration::wait_for_migrate(&db_pool)
-        .await
-        .expect("failed to wait for database migration");
+    let started_at = Instant::now();
+    let res = match wait_for_db(config, &db_pool).await {
+        Ok(()) => db::migration::wait_for_migrate(&db_pool).await,
+        Err(e) => Err(e),
+    };
+    let report = MigrationReport {
+        mode: "wait",
+        success: res.is_ok(),
+        duration: started_at.elapsed(),
+        applied: None,
+        last_migration_id: res.as_ref().ok().cloned().flatten(),
+    };
+    push_migration_report(config, report).await;
+
+    res.expect("failed to wait for database migration");
 }
 
-pub async fn run(config: &config::Config) {
-    metrics::register_custom_metrics();
+/// Manages API keys, see `auth::key_command` for commands
+pub async fn keys(config: &config::Config, command: &[String]) {
+    let db_pool = db::create_pool(config).expect("database pool can be created");
+    let res = auth::key_command(&db_pool, command).await;
+    db::close_pool(&db_pool).await;
+    if let Err(e) = res {
+        context::log_eprintln!("{}", e);
+        let code = match e {
+            error::Error::InvalidKeyCommand(_) => 2,
+            _ => 1,
+        };
+        std::process::exit(code);
+    }
+}
+
+/// All routes along with access log & CORS. Rebuilt on config reload,
+/// since filters can't be changed once built.
+fn routes(
+    config: &config::Config,
+    db_pool: &DBPool,
+    faults: &faults::Faults,
+    jwt: &Option<auth::jwt::JwtValidator>,
+    sessions: &auth::session::Sessions,
+    limiter: &rate_limit::RateLimiter,
+) -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone {
     let env = config.env.clone();
+    let cors_policy = cors::Policy::of(config);
     let log = warp::log::custom(move |info| {
-        let path = info.path();
-        let method = info.method().as_str();
-        metrics::track_request_time(info.elapsed().as_secs_f64(), method, path, &env);
-        metrics::track_status_code(info.status().as_u16().into(), method, path, &env);
-
-        println!(
-            "{{\"method\":\"{}\",\"path\":\"{}\",\"status\":\"{}\",\"remote_addr\":\"{:?}\",\"version\":\"{:?}\",\"referer\":\"{:?}\",\"user_agent\":\"{:?}\",\"elapsed\":\"{:?}\",\"host\":\"{:?}\",\"request_headers\":{:?}}}",
-            info.method(),
+        metrics::track_response(
+            info.method().as_str(),
             info.path(),
-            info.status(),
-            info.remote_addr(),
-            info.version(),
-            info.referer(),
-            info.user_agent(),
-            info.elapsed(),
-            info.host(),
-            info.request_headers()
+            &env,
+            info.status().as_u16(),
+            info.elapsed().as_secs_f64(),
         );
+
+        // Access log is JSON, so it's not prefixed like other log lines
+        if context::log_enabled(config::LogLevel::Info) {
+            let request_headers: serde_json::Map<String, serde_json::Value> = info
+                .request_headers()
+                .iter()
+                .map(|(name, value)| {
+                    let value = String::from_utf8_lossy(value.as_bytes()).into_owned();
+                    (name.to_string(), value.into())
+                })
+                .collect();
+            println!(
+                "{}",
+                serde_json::json!({
+                    "request_id": context::request_id().unwrap_or_default(),
+                    "method": info.method().as_str(),
+                    "path": info.path(),
+                    "status": info.status().to_string(),
+                    "remote_addr": context::remote_addr().map(|a| a.to_string()),
+                    "caller": context::caller_identity().unwrap_or_default(),
+                    "version": format!("{:?}", info.version()),
+                    "referer": info.referer(),
+                    "user_agent": info.user_agent(),
+                    "elapsed": format!("{:?}", info.elapsed()),
+                    "host": info.host(),
+                    "request_headers": request_headers,
+                })
+            );
+        }
     });
-    let db_pool = db::create_pool(&config.db_conn_string).expect("database pool can be created");
 
-    let routes = handler::router(&db_pool)
+    let proxies = rate_limit::TrustedProxies::of(config);
+    // Applied by each route once it matches, so that callers are limited
+    // by identity rather than address
+    let limit_by_caller = rate_limit::limit(limiter, &config.rate_limits, &proxies);
+    let auth = auth::authenticate(config, db_pool, jwt, sessions).and(limit_by_caller);
+    rate_limit::limit_by_address(limiter, &config.address_rate_limits, &proxies)
+        .and(
+            faults::admin_router(faults.clone(), &auth).or(faults::inject(faults.clone()).and(
+                handler::router(db_pool, sessions, config.metrics_port.is_none(), &auth),
+            )),
+        )
+        // Rejections are recovered before logging, so that metrics get
+        // actual response status instead of generic rejection one
+        .recover(error::handle_rejection)
         .with(log)
-        .with(warp::cors().allow_any_origin())
-        .recover(error::handle_rejection);
+        .with(warp::wrap_fn(move |filter| {
+            cors::wrap(&cors_policy, filter)
+        }))
+}
+
+pub async fn run(config: &config::Config) {
+    telemetry::init(config).expect("tracing can be initialised");
+    metrics::register_custom_metrics(&config.env);
+    metrics::slo::configure(&config.slos);
+    db::retry::configure(config);
+    db::breaker::configure(config);
+    context::set_log_level(config.log_level);
+    let db_pool = db::create_pool(config).expect("database pool can be created");
+
+    if let Some(metrics_port) = config.metrics_port {
+        // Kubelet has no client certificate to pass mutual TLS with, so
+        // probes are served here instead, gated in place of authentication
+        let serve_probes = routes::enabled_if(config.tls_client_ca.is_some());
+        let probes = handler::probe_router(&db_pool, &serve_probes);
+        tokio::spawn(metrics::exposition::serve(metrics_port, probes));
+    }
+
+    // Serving traffic against unreachable database or schema lacking
+    // migrations of this release would only produce errors. Newer schema
+    // is fine, so that old replicas can restart during rollout.
+    let startup = match wait_for_db(config, &db_pool).await {
+        Ok(()) => db::migration::check_migration(&db_pool).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = startup {
+        context::log_eprintln!("Startup failed: {}", e);
+        std::process::exit(1);
+    }
+
+    let faults = faults::Faults::new(config.fault_injection);
+    if config.fault_injection {
+        context::log_println!("Fault injection is enabled");
+    }
+
+    let jwt = auth::jwt::JwtValidator::of(config).await;
+    if let Some(jwt) = &jwt {
+        tokio::spawn(jwt.clone().watch());
+    }
+
+    // Each request takes routes built from current config, so reload
+    // applies to all requests after it at once
+    let sessions = auth::session::Sessions::of(config);
+    let limiter = rate_limit::RateLimiter::new();
+    let limits = limits::Limits::of(config);
+    let current_routes = Arc::new(RwLock::new(routes(
+        config, &db_pool, &faults, &jwt, &sessions, &limiter,
+    )));
+    tokio::spawn({
+        let current_routes = current_routes.clone();
+        let db_pool = db_pool.clone();
+        let limits = limits.clone();
+        reload::watch(config.clone(), move |config| {
+            let new_routes = routes(&config, &db_pool, &faults, &jwt, &sessions, &limiter);
+            *current_routes.write().unwrap() = new_routes;
+            limits.reload(&config);
+            context::set_log_level(config.log_level);
+            let db_pool = db_pool.clone();
+            async move {
+                db_pool.set_max_idle_conns(config.db_pool_max_idle).await;
+            }
+        })
+    });
+
+    // We don't use `warp::serve` here, since every request should be handled
+    // within its own context (e.g. to tag all related logs with request id)
+    let env = config.env.clone();
+    let make_svc = make_service_fn(move |conn: &listener::Conn| {
+        let remote_addr = conn.remote_addr();
+        let peer_identity = conn.peer_identity();
+        let current_routes = current_routes.clone();
+        let limits = limits.clone();
+        let env = env.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let mut svc = warp::service(current_routes.read().unwrap().clone());
+                let ctx =
+                    context::RequestContext::of(&req, Some(remote_addr), peer_identity.clone());
+                let span = telemetry::request_span(&req);
+                let served = limits
+                    .clone()
+                    .serve(env.clone(), req, move |req| svc.call(req));
+                context::scope(ctx, served).instrument(span)
+            }))
+        }
+    });
+
+    let acceptor = tls::TlsFiles::of(config)
+        .map(|files| tls::Acceptor::new(files).expect("TLS can be configured"));
+    if let Some(acceptor) = &acceptor {
+        tokio::spawn(acceptor.clone().watch());
+    }
+    // Server config is loaded with `Config::load_server`, which requires it
+    let port = config.port.expect("server config has port");
+    let addr = SocketAddr::from(([0, 0, 0, 0], port));
+    let incoming = listener::bind(addr, acceptor)
+        .await
+        .expect("server can listen on port");
+    let drain_period = Duration::from_secs(config.shutdown_drain_seconds);
+    let shutdown_timeout = Duration::from_secs(config.shutdown_timeout_seconds);
+    let (draining_tx, draining_rx) = oneshot::channel::<()>();
+    let server = warp::hyper::Server::builder(incoming)
+        .serve(make_svc)
+        .with_graceful_shutdown(async move {
+            shutdown::drain(drain_period).await;
+            draining_tx.send(()).ok();
+        });
+
+    let scheme = if config.tls_cert.is_some() {
+        "HTTPS"
+    } else {
+        "HTTP"
+    };
+    context::log_println!("Starting {} server on port {}", scheme, port);
+    // Once server stops accepting connections, in-flight requests have
+    // limited time to finish. After that they are just dropped.
+    tokio::select! {
+        res = server => {
+            if let Err(e) = res {
+                context::log_eprintln!("server error: {}", e);
+            }
+        }
+        _ = async {
+            draining_rx.await.ok();
+            sleep(shutdown_timeout).await;
+        } => {
+            context::log_eprintln!("in-flight requests did not finish in {:?}, dropping them", shutdown_timeout);
+        }
+    }
 
-    println!("Starting server on port {}", config.port);
-    warp::serve(routes).run(([0, 0, 0, 0], config.port)).await;
+    context::log_println!("Closing database pool");
+    db::close_pool(&db_pool).await;
+    context::log_println!("Server stopped");
+    shutdown::flush();
 }
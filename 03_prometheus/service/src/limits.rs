@@ -0,0 +1,268 @@
+use std::convert::Infallible;
+use std::future::Future;
+use std::sync::{Arc, RwLock};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::{timeout, Duration, Instant};
+use warp::http::header::{CONTENT_LENGTH, TRANSFER_ENCODING};
+use warp::http::{HeaderValue, Method, Request};
+use warp::hyper::body::HttpBody;
+use warp::hyper::Body;
+use warp::reply::Response;
+use warp::Reply;
+
+use crate::config::{Config, RouteLimitConfig};
+use crate::context::log_eprintln;
+use crate::error::{self, Error};
+use crate::metrics;
+use crate::routes::path_matches;
+
+struct RouteLimits {
+    max_body_bytes: u64,
+    timeout: Duration,
+    routes: Vec<RouteLimitConfig>,
+    uncapped_paths: Vec<String>,
+}
+
+impl RouteLimits {
+    fn of(config: &Config) -> RouteLimits {
+        RouteLimits {
+            max_body_bytes: config.max_body_bytes,
+            timeout: Duration::from_secs(config.request_timeout_seconds),
+            routes: config.route_limits.clone(),
+            uncapped_paths: config.uncapped_paths.clone(),
+        }
+    }
+
+    fn is_uncapped(&self, path: &str) -> bool {
+        self.uncapped_paths.iter().any(|p| path_matches(p, path))
+    }
+
+    /// Body size & timeout for request, first matching route overrides
+    /// global ones
+    fn get(&self, method: &Method, path: &str) -> (u64, Duration) {
+        let route = self.routes.iter().find(|r| {
+            r.method
+                .as_ref()
+                .is_none_or(|m| m.eq_ignore_ascii_case(method.as_str()))
+                && path_matches(&r.path, path)
+        });
+        (
+            route
+                .and_then(|r| r.max_body_bytes)
+                .unwrap_or(self.max_body_bytes),
+            route
+                .and_then(|r| r.timeout_seconds)
+                .map(Duration::from_secs)
+                .unwrap_or(self.timeout),
+        )
+    }
+}
+
+struct Inner {
+    routes: RwLock<RouteLimits>,
+    in_flight: Arc<Semaphore>,
+}
+
+/// Limits applied to requests before routing: body size, time to respond
+/// and number of requests handled at once
+#[derive(Clone)]
+pub struct Limits {
+    inner: Arc<Inner>,
+}
+
+// Keeps in-flight gauge right even if client goes away mid-request
+struct InFlight;
+
+impl InFlight {
+    fn start() -> InFlight {
+        metrics::IN_FLIGHT_REQUESTS.inc();
+        InFlight
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        metrics::IN_FLIGHT_REQUESTS.dec();
+    }
+}
+
+/// Checks declared body length. Body without one (chunked, or HTTP/2 stream)
+/// is read up to the limit, and passed on with its length, so handlers see
+/// it as any other body.
+async fn limit_body(req: Request<Body>, max_body_bytes: u64) -> Result<Request<Body>, Error> {
+    let headers = req.headers();
+    let length = headers
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    match length {
+        Some(length) if length > max_body_bytes => Err(Error::PayloadTooLarge(max_body_bytes)),
+        None if !req.body().is_end_stream() => {
+            let (mut parts, mut body) = req.into_parts();
+            let mut buffer = Vec::new();
+            while let Some(chunk) = body.data().await {
+                let chunk = chunk.map_err(|e| Error::BodyReadError(e.to_string()))?;
+                if (buffer.len() + chunk.len()) as u64 > max_body_bytes {
+                    return Err(Error::PayloadTooLarge(max_body_bytes));
+                }
+                buffer.extend_from_slice(&chunk);
+            }
+            parts.headers.remove(TRANSFER_ENCODING);
+            parts
+                .headers
+                .insert(CONTENT_LENGTH, HeaderValue::from(buffer.len()));
+            Ok(Request::from_parts(parts, Body::from(buffer)))
+        }
+        _ => Ok(req),
+    }
+}
+
+impl Limits {
+    pub fn of(config: &Config) -> Limits {
+        Limits {
+            inner: Arc::new(Inner {
+                routes: RwLock::new(RouteLimits::of(config)),
+                in_flight: Arc::new(Semaphore::new(config.max_in_flight_requests)),
+            }),
+        }
+    }
+
+    /// Applies reloadable limits. In-flight cap is kept.
+    pub fn reload(&self, config: &Config) {
+        *self.inner.routes.write().unwrap() = RouteLimits::of(config);
+    }
+
+    /// Slot for request, or none if cap is reached. `Some(None)` means
+    /// request isn't capped.
+    fn permit(&self, path: &str) -> Option<Option<OwnedSemaphorePermit>> {
+        if self.inner.routes.read().unwrap().is_uncapped(path) {
+            return Some(None);
+        }
+        self.inner
+            .in_flight
+            .clone()
+            .try_acquire_owned()
+            .ok()
+            .map(Some)
+    }
+
+    /// Handles request with `call` within limits. Rejected requests are
+    /// answered as rejections from routes would be, and tracked the same
+    /// way, since they never reach access log.
+    pub async fn serve<F, Fut>(
+        self,
+        env: String,
+        req: Request<Body>,
+        call: F,
+    ) -> Result<Response, Infallible>
+    where
+        F: FnOnce(Request<Body>) -> Fut,
+        Fut: Future<Output = Result<Response, Infallible>>,
+    {
+        let started_at = Instant::now();
+        let method = req.method().clone();
+        let path = req.uri().path().to_owned();
+        let (max_body_bytes, request_timeout) =
+            self.inner.routes.read().unwrap().get(&method, &path);
+
+        // Slow body counts against handling time too
+        let body_read = timeout(request_timeout, limit_body(req, max_body_bytes)).await;
+        let (limit, e) = match body_read {
+            Err(_) => ("timeout", Error::RequestTimeout(request_timeout)),
+            Ok(Err(e)) => ("body_size", e),
+            Ok(Ok(req)) => match self.permit(&path) {
+                Some(_permit) => {
+                    let _in_flight = InFlight::start();
+                    match timeout(request_timeout, call(req)).await {
+                        Ok(res) => return res,
+                        Err(_) => ("timeout", Error::RequestTimeout(request_timeout)),
+                    }
+                }
+                None => ("in_flight", Error::Overloaded),
+            },
+        };
+
+        log_eprintln!("{} {} rejected: {}", method, path, e);
+        metrics::track_limited_request(limit);
+        let res = error::handle_rejection(warp::reject::custom(e))
+            .await?
+            .into_response();
+        metrics::track_response(
+            method.as_str(),
+            &path,
+            &env,
+            res.status().as_u16(),
+            started_at.elapsed().as_secs_f64(),
+        );
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Body of unknown length, as HTTP/2 stream without `Content-Length`
+    fn streamed(chunks: &[&'static str]) -> Body {
+        let (mut sender, body) = Body::channel();
+        let chunks = chunks.to_vec();
+        tokio::spawn(async move {
+            for chunk in chunks {
+                sender.send_data(chunk.into()).await.unwrap();
+            }
+        });
+        body
+    }
+
+    fn chunked(chunks: &[&'static str]) -> Request<Body> {
+        Request::post("/user")
+            .header(TRANSFER_ENCODING, "chunked")
+            .body(streamed(chunks))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn limits_declared_body_length() {
+        let req = Request::post("/user")
+            .header(CONTENT_LENGTH, "11")
+            .body(Body::from("hello world"))
+            .unwrap();
+        assert!(matches!(
+            limit_body(req, 10).await,
+            Err(Error::PayloadTooLarge(10))
+        ));
+        let req = Request::post("/user").body(Body::from("hello")).unwrap();
+        assert!(limit_body(req, 10).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn reads_chunked_body_up_to_limit() {
+        let req = limit_body(chunked(&["hello", " world"]), 11).await.unwrap();
+        assert_eq!(req.headers()[CONTENT_LENGTH], "11");
+        assert!(!req.headers().contains_key(TRANSFER_ENCODING));
+        let body = warp::hyper::body::to_bytes(req.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"hello world");
+
+        assert!(matches!(
+            limit_body(chunked(&["hello", " world"]), 10).await,
+            Err(Error::PayloadTooLarge(10))
+        ));
+    }
+
+    #[tokio::test]
+    async fn limits_body_without_length_or_encoding() {
+        let req = Request::post("/user")
+            .body(streamed(&["hello", " world"]))
+            .unwrap();
+        assert!(matches!(
+            limit_body(req, 10).await,
+            Err(Error::PayloadTooLarge(10))
+        ));
+
+        let req = limit_body(Request::get("/user").body(Body::empty()).unwrap(), 10)
+            .await
+            .unwrap();
+        assert!(!req.headers().contains_key(CONTENT_LENGTH));
+    }
+}
@@ -0,0 +1,172 @@
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use openssl::ssl::Ssl;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::time::{sleep, timeout, Duration};
+use tokio_openssl::SslStream;
+use warp::hyper::server::accept::Accept;
+
+use crate::context::{log_eprintln, log_println};
+use crate::tls::{self, Acceptor};
+
+// Handshake runs before connection is handed to server, so idle client
+// shouldn't hold resources forever
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+enum Stream {
+    Plain(TcpStream),
+    Tls(Box<SslStream<TcpStream>>),
+}
+
+/// Accepted connection, either plain or with TLS already negotiated
+pub struct Conn {
+    stream: Stream,
+    remote_addr: SocketAddr,
+    peer_identity: Option<String>,
+}
+
+impl Conn {
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.remote_addr
+    }
+
+    /// Subject of verified client certificate, if client presented one
+    pub fn peer_identity(&self) -> Option<String> {
+        self.peer_identity.clone()
+    }
+}
+
+impl AsyncRead for Conn {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().stream {
+            Stream::Plain(s) => Pin::new(s).poll_read(cx, buf),
+            Stream::Tls(s) => Pin::new(s.as_mut()).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Conn {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match &mut self.get_mut().stream {
+            Stream::Plain(s) => Pin::new(s).poll_write(cx, buf),
+            Stream::Tls(s) => Pin::new(s.as_mut()).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().stream {
+            Stream::Plain(s) => Pin::new(s).poll_flush(cx),
+            Stream::Tls(s) => Pin::new(s.as_mut()).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().stream {
+            Stream::Plain(s) => Pin::new(s).poll_shutdown(cx),
+            Stream::Tls(s) => Pin::new(s.as_mut()).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Connections accepted by background task, so that slow TLS handshake
+/// doesn't hold off other clients
+pub struct Incoming {
+    rx: mpsc::Receiver<Conn>,
+}
+
+impl Accept for Incoming {
+    type Conn = Conn;
+    type Error = io::Error;
+
+    fn poll_accept(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<io::Result<Conn>>> {
+        self.get_mut().rx.poll_recv(cx).map(|conn| conn.map(Ok))
+    }
+}
+
+async fn tls_handshake(
+    acceptor: &Acceptor,
+    tcp: TcpStream,
+    remote_addr: SocketAddr,
+) -> Result<Conn, String> {
+    let ssl = Ssl::new(acceptor.current().context()).map_err(|e| e.to_string())?;
+    let mut stream = SslStream::new(ssl, tcp).map_err(|e| e.to_string())?;
+    match timeout(TLS_HANDSHAKE_TIMEOUT, Pin::new(&mut stream).accept()).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => return Err(e.to_string()),
+        Err(_) => return Err("handshake timed out".to_owned()),
+    }
+    let peer_identity = stream
+        .ssl()
+        .peer_certificate()
+        .map(|cert| tls::subject(cert.subject_name()));
+    Ok(Conn {
+        stream: Stream::Tls(Box::new(stream)),
+        remote_addr,
+        peer_identity,
+    })
+}
+
+/// Accepts connections on given address, with TLS if acceptor is given.
+/// Stops accepting once server drops `Incoming` on shutdown.
+pub async fn bind(addr: SocketAddr, acceptor: Option<Acceptor>) -> io::Result<Incoming> {
+    let listener = TcpListener::bind(addr).await?;
+    let (tx, rx) = mpsc::channel(128);
+
+    tokio::spawn(async move {
+        loop {
+            let (tcp, remote_addr) = tokio::select! {
+                res = listener.accept() => match res {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        // Most likely out of file descriptors, which is
+                        // better to wait out than to spin on
+                        log_eprintln!("accept error: {}", e);
+                        sleep(Duration::from_secs(1)).await;
+                        continue;
+                    }
+                },
+                _ = tx.closed() => break,
+            };
+
+            let tx = tx.clone();
+            match &acceptor {
+                None => {
+                    let conn = Conn {
+                        stream: Stream::Plain(tcp),
+                        remote_addr,
+                        peer_identity: None,
+                    };
+                    tx.send(conn).await.ok();
+                }
+                Some(acceptor) => {
+                    let acceptor = acceptor.clone();
+                    tokio::spawn(async move {
+                        match tls_handshake(&acceptor, tcp, remote_addr).await {
+                            Ok(conn) => {
+                                tx.send(conn).await.ok();
+                            }
+                            Err(e) => {
+                                log_println!("TLS handshake with {} failed: {}", remote_addr, e)
+                            }
+                        }
+                    });
+                }
+            }
+        }
+    });
+
+    Ok(Incoming { rx })
+}
@@ -1,8 +1,10 @@
 use service::config::Config;
 use service::run;
+use std::env;
 
 #[tokio::main]
 async fn main() {
-    let config = Config::from_env();
+    let args: Vec<String> = env::args().skip(1).collect();
+    let config = Config::load_or_exit(&args, Config::load_server);
     run(&config).await
 }
@@ -0,0 +1,168 @@
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::io::Write;
+use std::net::SocketAddr;
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use prometheus::core::Collector;
+use prometheus::proto::MetricFamily;
+use prometheus::{Encoder, TextEncoder};
+use tracing::instrument;
+use warp::http::header::{CONTENT_ENCODING, CONTENT_TYPE};
+use warp::http::Response;
+use warp::hyper::Body;
+use warp::{Filter, Reply};
+
+use super::{openmetrics, REGISTRY};
+use crate::context::{log_eprintln, log_println};
+
+/// Registers collector in custom registry. Unlike `Registry::register`,
+/// also fails if any of collector metric names is already exposed by
+/// default registry, since families with the same name can't be merged
+/// into consistent output.
+pub fn register(collector: Box<dyn Collector>) -> prometheus::Result<()> {
+    let default_names: Vec<String> = prometheus::gather()
+        .iter()
+        .map(|mf| mf.get_name().to_owned())
+        .collect();
+    for desc in collector.desc() {
+        if default_names.contains(&desc.fq_name) {
+            return Err(prometheus::Error::Msg(format!(
+                "metric \"{}\" is already registered in default registry",
+                desc.fq_name
+            )));
+        }
+    }
+    REGISTRY.register(collector)
+}
+
+/// Gathers metric families from custom & default registries. Families
+/// with the same name are merged, so each one is written exactly once.
+pub fn gather() -> Vec<MetricFamily> {
+    let mut merged: BTreeMap<String, MetricFamily> = BTreeMap::new();
+    for mut mf in REGISTRY.gather().into_iter().chain(prometheus::gather()) {
+        match merged.get_mut(mf.get_name()) {
+            None => {
+                merged.insert(mf.get_name().to_owned(), mf);
+            }
+            Some(existing) if existing.get_field_type() == mf.get_field_type() => {
+                existing.mut_metric().extend(mf.take_metric());
+            }
+            Some(_) => {
+                log_eprintln!(
+                    "metric family \"{}\" is exposed with different types, skipping duplicate",
+                    mf.get_name()
+                );
+            }
+        }
+    }
+    merged.into_values().collect()
+}
+
+fn encode(families: &[MetricFamily], accept: Option<&str>) -> (Vec<u8>, String) {
+    if openmetrics::is_accepted(accept) {
+        let body = openmetrics::encode(families, &super::find_exemplar);
+        return (
+            body.into_bytes(),
+            openmetrics::OPENMETRICS_FORMAT.to_owned(),
+        );
+    }
+
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    if let Err(e) = encoder.encode(families, &mut buffer) {
+        log_eprintln!("could not encode metrics: {}", e);
+    };
+    (buffer, encoder.format_type().to_owned())
+}
+
+/// Whether gzip is acceptable, honouring `q=0` and the `*` wildcard
+fn accepts_gzip(accept_encoding: Option<&str>) -> bool {
+    let mut gzip = None;
+    let mut wildcard = None;
+    for entry in accept_encoding.unwrap_or("").split(',') {
+        let mut parts = entry.split(';').map(str::trim);
+        let coding = parts.next().unwrap_or("").to_ascii_lowercase();
+        let quality = parts
+            .filter_map(|p| p.strip_prefix("q=").or_else(|| p.strip_prefix("Q=")))
+            .next()
+            .map(|q| q.parse::<f32>().unwrap_or(0.0))
+            .unwrap_or(1.0);
+        match coding.as_str() {
+            "gzip" | "x-gzip" => gzip = Some(quality),
+            "*" => wildcard = Some(quality),
+            _ => {}
+        }
+    }
+    gzip.or(wildcard).map(|q| q > 0.0).unwrap_or(false)
+}
+
+fn gzip(body: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(body)?;
+    encoder.finish()
+}
+
+#[instrument]
+pub async fn metrics_handler(
+    accept: Option<String>,
+    accept_encoding: Option<String>,
+) -> Result<impl Reply, Infallible> {
+    let (body, content_type) = encode(&gather(), accept.as_deref());
+    let response = Response::builder().header(CONTENT_TYPE, content_type);
+
+    if accepts_gzip(accept_encoding.as_deref()) {
+        match gzip(&body) {
+            Ok(compressed) => {
+                return Ok(response
+                    .header(CONTENT_ENCODING, "gzip")
+                    .body(Body::from(compressed)));
+            }
+            Err(e) => log_eprintln!("could not compress metrics: {}", e),
+        }
+    }
+    Ok(response.body(Body::from(body)))
+}
+
+pub fn router() -> impl Filter<Extract = (impl Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("metrics").and(warp::get()).and(handler())
+}
+
+/// Metrics response for request already routed to metrics
+pub fn handler() -> impl Filter<Extract = (impl Reply,), Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>("accept")
+        .and(warp::header::optional::<String>("accept-encoding"))
+        .and_then(metrics_handler)
+}
+
+/// Serves metrics on separate port, so they are not reachable through
+/// the same ingress as public API. `extra` routes are served along.
+pub async fn serve<F>(port: u16, extra: F)
+where
+    F: Filter<Error = warp::Rejection> + Clone + Send + Sync + 'static,
+    F::Extract: Reply,
+{
+    let addr = SocketAddr::from(([0, 0, 0, 0], port));
+    log_println!("Serving metrics on port {}", port);
+    warp::serve(router().or(extra)).run(addr).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_gzip_honours_quality() {
+        assert!(accepts_gzip(Some("gzip")));
+        assert!(accepts_gzip(Some("deflate, GZIP;q=0.5")));
+        assert!(accepts_gzip(Some("br, *")));
+        assert!(!accepts_gzip(None));
+        assert!(!accepts_gzip(Some("identity")));
+        assert!(!accepts_gzip(Some("gzip;q=0")));
+        assert!(!accepts_gzip(Some("gzip; q=0.0, deflate")));
+        assert!(!accepts_gzip(Some("*, gzip;q=0")));
+        assert!(!accepts_gzip(Some("*;q=0")));
+        assert!(!accepts_gzip(Some("gzipped")));
+    }
+}
@@ -0,0 +1,305 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use lazy_static::lazy_static;
+use prometheus::proto::LabelPair;
+use prometheus::{
+    Gauge, GaugeVec, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, DEFAULT_BUCKETS,
+};
+
+use crate::{context, telemetry};
+use openmetrics::Exemplar;
+
+pub mod exposition;
+pub mod openmetrics;
+pub mod push;
+mod runtime;
+pub mod slo;
+
+const RESPONSE_TIME_NAME: &str = "response_time";
+
+lazy_static! {
+    pub static ref REGISTRY: Registry = Registry::new();
+    pub static ref RESPONSE_CODE_COLLECTOR: IntCounterVec = IntCounterVec::new(
+        Opts::new("response_code", "Response Codes"),
+        &["env", "method", "path", "statuscode", "type", "injected"]
+    )
+    .expect("metric can be created");
+    pub static ref RESPONSE_TIME_COLLECTOR: HistogramVec = HistogramVec::new(
+        HistogramOpts::new(RESPONSE_TIME_NAME, "Response Times").buckets(DEFAULT_BUCKETS.to_vec()),
+        &["env", "method", "path"]
+    )
+    .expect("metric can be created");
+    pub static ref BUILD_INFO: IntGaugeVec = IntGaugeVec::new(
+        Opts::new("service_build_info", "Build details, value is always 1"),
+        &["version", "git_commit", "rustc_version", "env"]
+    )
+    .expect("metric can be created");
+    // `process_start_time_seconds` is taken by default process collector,
+    // which isn't available on every platform
+    pub static ref START_TIME: Gauge = Gauge::new(
+        "service_start_time_seconds",
+        "Time when service was started"
+    )
+    .expect("metric can be created");
+    pub static ref CONFIG_INFO: IntGaugeVec = IntGaugeVec::new(
+        Opts::new("config_info", "Hash of effective config, value is always 1"),
+        &["hash"]
+    )
+    .expect("metric can be created");
+    pub static ref CONFIG_RELOADS_COLLECTOR: IntCounterVec = IntCounterVec::new(
+        Opts::new("config_reloads_total", "Config reload attempts"),
+        &["result"]
+    )
+    .expect("metric can be created");
+    pub static ref CONFIG_LAST_RELOAD_SUCCESSFUL: IntGauge = IntGauge::new(
+        "config_last_reload_successful",
+        "Whether last config reload attempt succeeded"
+    )
+    .expect("metric can be created");
+    pub static ref CONFIG_LAST_RELOAD_SUCCESS_TIME: Gauge = Gauge::new(
+        "config_last_reload_success_timestamp_seconds",
+        "Time when config was last loaded successfully"
+    )
+    .expect("metric can be created");
+    // Labelled with limit rather than request path, so that series are
+    // bounded by config
+    pub static ref RATE_LIMITED_COLLECTOR: IntCounterVec = IntCounterVec::new(
+        Opts::new("rate_limited_requests_total", "Requests rejected by rate limit"),
+        &["key", "method", "path"]
+    )
+    .expect("metric can be created");
+    pub static ref IN_FLIGHT_REQUESTS: IntGauge = IntGauge::new(
+        "in_flight_requests",
+        "Requests being handled"
+    )
+    .expect("metric can be created");
+    pub static ref LIMITED_REQUESTS_COLLECTOR: IntCounterVec = IntCounterVec::new(
+        Opts::new(
+            "limited_requests_total",
+            "Requests rejected for body size, timeout or in-flight cap"
+        ),
+        &["limit"]
+    )
+    .expect("metric can be created");
+    pub static ref DB_CIRCUIT_BREAKER_STATE: IntGauge = IntGauge::new(
+        "db_circuit_breaker_state",
+        "DB circuit breaker state: 0 closed, 1 half-open, 2 open"
+    )
+    .expect("metric can be created");
+    pub static ref DB_RETRIES_COLLECTOR: IntCounterVec = IntCounterVec::new(
+        Opts::new("db_retries_total", "DB operations retried after transient errors"),
+        &["operation"]
+    )
+    .expect("metric can be created");
+    pub static ref DB_RETRIES_EXHAUSTED_COLLECTOR: IntCounterVec = IntCounterVec::new(
+        Opts::new(
+            "db_retries_exhausted_total",
+            "DB operations failed with transient error without further retries"
+        ),
+        &["operation", "reason"]
+    )
+    .expect("metric can be created");
+    // No series unless HTTPS is enabled, so expiry alerts can't misfire
+    pub static ref TLS_CERT_EXPIRY: GaugeVec = GaugeVec::new(
+        Opts::new(
+            "tls_certificate_expiry_timestamp_seconds",
+            "Time when served TLS certificate expires"
+        ),
+        &["file"]
+    )
+    .expect("metric can be created");
+    // Latest exemplar for each bucket of each response time series,
+    // keyed by series label values (sorted by label name)
+    static ref RESPONSE_TIME_EXEMPLARS: Mutex<HashMap<Vec<String>, Vec<Option<Exemplar>>>> =
+        Mutex::new(HashMap::new());
+}
+
+fn now_seconds() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs_f64())
+        .unwrap_or_default()
+}
+
+pub fn register_custom_metrics(env: &str) {
+    exposition::register(Box::new(RESPONSE_CODE_COLLECTOR.clone()))
+        .expect("collector can be registered");
+
+    exposition::register(Box::new(RESPONSE_TIME_COLLECTOR.clone()))
+        .expect("collector can be registered");
+
+    BUILD_INFO
+        .with_label_values(&[
+            env!("CARGO_PKG_VERSION"),
+            env!("GIT_COMMIT"),
+            env!("RUSTC_VERSION"),
+            env,
+        ])
+        .set(1);
+    exposition::register(Box::new(BUILD_INFO.clone())).expect("collector can be registered");
+
+    START_TIME.set(now_seconds());
+    exposition::register(Box::new(START_TIME.clone())).expect("collector can be registered");
+
+    let runtime = runtime::RuntimeCollector::new().expect("metric can be created");
+    exposition::register(Box::new(runtime)).expect("collector can be registered");
+
+    exposition::register(Box::new(slo::SLO_REQUESTS_COLLECTOR.clone()))
+        .expect("collector can be registered");
+    exposition::register(Box::new(slo::SLO_GOOD_REQUESTS_COLLECTOR.clone()))
+        .expect("collector can be registered");
+    let burn_rate = slo::BurnRateCollector::new().expect("metric can be created");
+    exposition::register(Box::new(burn_rate)).expect("collector can be registered");
+
+    exposition::register(Box::new(CONFIG_INFO.clone())).expect("collector can be registered");
+    exposition::register(Box::new(TLS_CERT_EXPIRY.clone())).expect("collector can be registered");
+    exposition::register(Box::new(RATE_LIMITED_COLLECTOR.clone()))
+        .expect("collector can be registered");
+    exposition::register(Box::new(IN_FLIGHT_REQUESTS.clone()))
+        .expect("collector can be registered");
+    exposition::register(Box::new(LIMITED_REQUESTS_COLLECTOR.clone()))
+        .expect("collector can be registered");
+    exposition::register(Box::new(DB_CIRCUIT_BREAKER_STATE.clone()))
+        .expect("collector can be registered");
+    exposition::register(Box::new(DB_RETRIES_COLLECTOR.clone()))
+        .expect("collector can be registered");
+    exposition::register(Box::new(DB_RETRIES_EXHAUSTED_COLLECTOR.clone()))
+        .expect("collector can be registered");
+    exposition::register(Box::new(CONFIG_RELOADS_COLLECTOR.clone()))
+        .expect("collector can be registered");
+    exposition::register(Box::new(CONFIG_LAST_RELOAD_SUCCESSFUL.clone()))
+        .expect("collector can be registered");
+    exposition::register(Box::new(CONFIG_LAST_RELOAD_SUCCESS_TIME.clone()))
+        .expect("collector can be registered");
+}
+
+/// Records config in use. Startup counts as successful load, so that
+/// reload alerts don't fire on fresh instances.
+pub fn track_config(hash: &str) {
+    CONFIG_INFO.reset();
+    CONFIG_INFO.with_label_values(&[hash]).set(1);
+    CONFIG_LAST_RELOAD_SUCCESSFUL.set(1);
+    CONFIG_LAST_RELOAD_SUCCESS_TIME.set(now_seconds());
+}
+
+/// Records config reload outcome, with hash of new config if it succeeded
+pub fn track_config_reload(hash: Option<&str>) {
+    let result = if hash.is_some() { "success" } else { "failure" };
+    CONFIG_RELOADS_COLLECTOR.with_label_values(&[result]).inc();
+    match hash {
+        Some(hash) => track_config(hash),
+        None => CONFIG_LAST_RELOAD_SUCCESSFUL.set(0),
+    }
+}
+
+/// Records response time, status & SLO outcome of finished request
+pub fn track_response(method: &str, path: &str, env: &str, status: u16, elapsed: f64) {
+    // Exemplar points to trace if there's one, otherwise at least
+    // request id can be found in logs
+    let exemplar_label = telemetry::current_trace_id()
+        .map(|id| ("trace_id", id))
+        .or_else(|| context::request_id().map(|id| ("request_id", id)))
+        // Truncated id wouldn't find anything, so long one is dropped
+        .filter(|(name, value)| {
+            name.chars().count() + value.chars().count() <= openmetrics::EXEMPLAR_LABELS_MAX_CHARS
+        });
+    track_request_time(elapsed, method, path, env, exemplar_label);
+    track_status_code(
+        status.into(),
+        method,
+        path,
+        env,
+        context::is_fault_injected(),
+    );
+    slo::track(method, path, status, elapsed);
+    telemetry::record_status_code(status);
+}
+
+pub fn track_limited_request(limit: &str) {
+    LIMITED_REQUESTS_COLLECTOR.with_label_values(&[limit]).inc();
+}
+
+pub fn track_db_retry(operation: &str) {
+    DB_RETRIES_COLLECTOR.with_label_values(&[operation]).inc();
+}
+
+/// Reason is either `attempts` or `budget`
+pub fn track_db_retries_exhausted(operation: &str, reason: &str) {
+    DB_RETRIES_EXHAUSTED_COLLECTOR
+        .with_label_values(&[operation, reason])
+        .inc();
+}
+
+pub fn track_tls_cert_expiry(file: &str, not_after: f64) {
+    TLS_CERT_EXPIRY.with_label_values(&[file]).set(not_after);
+}
+
+pub fn track_request_time(
+    response_time: f64,
+    method: &str,
+    path: &str,
+    env: &str,
+    exemplar_label: Option<(&'static str, String)>,
+) {
+    RESPONSE_TIME_COLLECTOR
+        .with_label_values(&[env, method, path])
+        .observe(response_time);
+
+    if let Some(label) = exemplar_label {
+        let timestamp = now_seconds();
+        let bucket = DEFAULT_BUCKETS
+            .iter()
+            .position(|b| response_time <= *b)
+            .unwrap_or(DEFAULT_BUCKETS.len());
+        let key = vec![env.to_owned(), method.to_owned(), path.to_owned()];
+
+        let mut exemplars = RESPONSE_TIME_EXEMPLARS.lock().unwrap();
+        let series = exemplars
+            .entry(key)
+            .or_insert_with(|| vec![None; DEFAULT_BUCKETS.len() + 1]);
+        series[bucket] = Some(Exemplar {
+            label,
+            value: response_time,
+            timestamp,
+        });
+    }
+}
+
+/// Exemplar lookup for OpenMetrics encoder
+pub fn find_exemplar(name: &str, labels: &[LabelPair], bucket: usize) -> Option<Exemplar> {
+    if name != RESPONSE_TIME_NAME {
+        return None;
+    }
+    let key: Vec<String> = labels.iter().map(|l| l.get_value().to_owned()).collect();
+    let exemplars = RESPONSE_TIME_EXEMPLARS.lock().unwrap();
+    exemplars
+        .get(&key)
+        .and_then(|series| series.get(bucket).cloned().flatten())
+}
+
+pub fn track_status_code(
+    status_code: usize,
+    method: &str,
+    path: &str,
+    env: &str,
+    fault_injected: bool,
+) {
+    let status_code_group = if status_code > 100 && status_code < 600 {
+        (status_code / 100).to_string()
+    } else {
+        String::from("Unknown")
+    };
+    RESPONSE_CODE_COLLECTOR
+        .with_label_values(&[
+            env,
+            method,
+            path,
+            &status_code.to_string(),
+            &status_code_group,
+            if fault_injected { "true" } else { "false" },
+        ])
+        .inc();
+}
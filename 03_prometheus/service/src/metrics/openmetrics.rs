@@ -0,0 +1,299 @@
+use std::fmt::Write;
+
+use prometheus::proto::{LabelPair, Metric, MetricFamily, MetricType};
+
+/// Content type of OpenMetrics text exposition format
+pub const OPENMETRICS_FORMAT: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Limit on combined length of exemplar label names & values
+pub const EXEMPLAR_LABELS_MAX_CHARS: usize = 128;
+
+/// Example observation attached to histogram bucket, so that slow
+/// request can be found by its trace (or request) id
+#[derive(Clone, Debug)]
+pub struct Exemplar {
+    pub label: (&'static str, String),
+    pub value: f64,
+    pub timestamp: f64,
+}
+
+/// Looks up exemplar for given family name, metric labels & bucket index
+pub type ExemplarLookup<'a> = &'a dyn Fn(&str, &[LabelPair], usize) -> Option<Exemplar>;
+
+/// Checks if client accepts OpenMetrics, i.e. lists it without `q=0`
+pub fn is_accepted(accept: Option<&str>) -> bool {
+    accept.unwrap_or("").split(',').any(|entry| {
+        let mut parts = entry.split(';').map(str::trim);
+        let media_type = parts.next().unwrap_or("");
+        let quality = parts
+            .filter_map(|p| p.strip_prefix("q=").or_else(|| p.strip_prefix("Q=")))
+            .next()
+            .map(|q| q.parse::<f32>().unwrap_or(0.0))
+            .unwrap_or(1.0);
+        media_type.eq_ignore_ascii_case("application/openmetrics-text") && quality > 0.0
+    })
+}
+
+/// Encodes metric families into OpenMetrics text format. Unlike classic
+/// text format, OpenMetrics supports exemplars on histogram buckets.
+pub fn encode(families: &[MetricFamily], exemplars: ExemplarLookup) -> String {
+    let mut out = String::new();
+    for mf in families {
+        let name = mf.get_name();
+        let metric_type = mf.get_field_type();
+        // OpenMetrics counter family is named without suffix,
+        // while its sample always has it
+        let family_name = match metric_type {
+            MetricType::COUNTER => name.strip_suffix("_total").unwrap_or(name),
+            _ => name,
+        };
+        let type_name = match metric_type {
+            MetricType::COUNTER => "counter",
+            MetricType::GAUGE => "gauge",
+            MetricType::HISTOGRAM => "histogram",
+            MetricType::SUMMARY => "summary",
+            MetricType::UNTYPED => "unknown",
+        };
+
+        writeln!(out, "# TYPE {} {}", family_name, type_name).ok();
+        if !mf.get_help().is_empty() {
+            writeln!(out, "# HELP {} {}", family_name, escape(mf.get_help())).ok();
+        }
+
+        for m in mf.get_metric() {
+            match metric_type {
+                MetricType::COUNTER => {
+                    let sample_name = format!("{}_total", family_name);
+                    write_sample(
+                        &mut out,
+                        &sample_name,
+                        m,
+                        None,
+                        m.get_counter().get_value(),
+                        None,
+                    );
+                }
+                MetricType::GAUGE => {
+                    write_sample(&mut out, name, m, None, m.get_gauge().get_value(), None);
+                }
+                MetricType::UNTYPED => {
+                    write_sample(&mut out, name, m, None, m.get_untyped().get_value(), None);
+                }
+                MetricType::HISTOGRAM => write_histogram(&mut out, name, m, exemplars),
+                MetricType::SUMMARY => {
+                    let s = m.get_summary();
+                    for q in s.get_quantile() {
+                        let quantile = format_float(q.get_quantile());
+                        write_sample(
+                            &mut out,
+                            name,
+                            m,
+                            Some(("quantile", &quantile)),
+                            q.get_value(),
+                            None,
+                        );
+                    }
+                    let sum_name = format!("{}_sum", name);
+                    write_sample(&mut out, &sum_name, m, None, s.get_sample_sum(), None);
+                    let count_name = format!("{}_count", name);
+                    write_sample(
+                        &mut out,
+                        &count_name,
+                        m,
+                        None,
+                        s.get_sample_count() as f64,
+                        None,
+                    );
+                }
+            }
+        }
+    }
+    out.push_str("# EOF\n");
+    out
+}
+
+fn write_histogram(out: &mut String, name: &str, m: &Metric, exemplars: ExemplarLookup) {
+    let h = m.get_histogram();
+    let bucket_name = format!("{}_bucket", name);
+
+    let mut inf_seen = false;
+    for (idx, b) in h.get_bucket().iter().enumerate() {
+        let upper_bound = b.get_upper_bound();
+        inf_seen = inf_seen || (upper_bound.is_infinite() && upper_bound.is_sign_positive());
+        let le = format_float(upper_bound);
+        write_sample(
+            out,
+            &bucket_name,
+            m,
+            Some(("le", &le)),
+            b.get_cumulative_count() as f64,
+            exemplars(name, m.get_label(), idx),
+        );
+    }
+    if !inf_seen {
+        let idx = h.get_bucket().len();
+        write_sample(
+            out,
+            &bucket_name,
+            m,
+            Some(("le", "+Inf")),
+            h.get_sample_count() as f64,
+            exemplars(name, m.get_label(), idx),
+        );
+    }
+
+    let sum_name = format!("{}_sum", name);
+    write_sample(out, &sum_name, m, None, h.get_sample_sum(), None);
+    let count_name = format!("{}_count", name);
+    write_sample(out, &count_name, m, None, h.get_sample_count() as f64, None);
+}
+
+fn write_sample(
+    out: &mut String,
+    name: &str,
+    m: &Metric,
+    additional_label: Option<(&str, &str)>,
+    value: f64,
+    exemplar: Option<Exemplar>,
+) {
+    out.push_str(name);
+    let labels = m
+        .get_label()
+        .iter()
+        .map(|l| (l.get_name(), l.get_value()))
+        .chain(additional_label);
+    write_labels(out, labels);
+    write!(out, " {}", format_float(value)).ok();
+    if m.get_timestamp_ms() != 0 {
+        write!(
+            out,
+            " {}",
+            format_float(m.get_timestamp_ms() as f64 / 1000.0)
+        )
+        .ok();
+    }
+    if let Some(e) = exemplar {
+        out.push_str(" # ");
+        write_labels(out, std::iter::once((e.label.0, e.label.1.as_str())));
+        write!(
+            out,
+            " {} {}",
+            format_float(e.value),
+            format_float(e.timestamp)
+        )
+        .ok();
+    }
+    out.push('\n');
+}
+
+fn write_labels<'a>(out: &mut String, labels: impl Iterator<Item = (&'a str, &'a str)>) {
+    let mut first = true;
+    for (name, value) in labels {
+        out.push(if first { '{' } else { ',' });
+        first = false;
+        write!(out, "{}=\"{}\"", name, escape(value)).ok();
+    }
+    if !first {
+        out.push('}');
+    }
+}
+
+fn format_float(v: f64) -> String {
+    if v.is_nan() {
+        "NaN".into()
+    } else if v.is_infinite() {
+        if v.is_sign_positive() {
+            "+Inf".into()
+        } else {
+            "-Inf".into()
+        }
+    } else {
+        v.to_string()
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use prometheus::{Histogram, HistogramOpts, IntCounterVec, IntGauge, Opts, Registry};
+
+    use super::*;
+
+    #[test]
+    fn encodes_counter_histogram_and_gauge() {
+        let registry = Registry::new();
+        let counter =
+            IntCounterVec::new(Opts::new("requests_total", "Requests"), &["path"]).unwrap();
+        let histogram = Histogram::with_opts(
+            HistogramOpts::new("latency", "Latency \"seconds\"").buckets(vec![0.1, 1.0]),
+        )
+        .unwrap();
+        let gauge = IntGauge::new("in_flight", "In flight").unwrap();
+        registry.register(Box::new(counter.clone())).unwrap();
+        registry.register(Box::new(histogram.clone())).unwrap();
+        registry.register(Box::new(gauge.clone())).unwrap();
+
+        counter.with_label_values(&["/user"]).inc_by(3);
+        histogram.observe(0.5);
+        gauge.set(2);
+
+        let exemplars = |name: &str, _: &[LabelPair], idx: usize| {
+            (name == "latency" && idx == 1).then(|| Exemplar {
+                label: ("trace_id", "abc".to_owned()),
+                value: 0.5,
+                timestamp: 1700000000.25,
+            })
+        };
+        let expected = "\
+# TYPE in_flight gauge
+# HELP in_flight In flight
+in_flight 2
+# TYPE latency histogram
+# HELP latency Latency \\\"seconds\\\"
+latency_bucket{le=\"0.1\"} 0
+latency_bucket{le=\"1\"} 1 # {trace_id=\"abc\"} 0.5 1700000000.25
+latency_bucket{le=\"+Inf\"} 1
+latency_sum 0.5
+latency_count 1
+# TYPE requests counter
+# HELP requests Requests
+requests_total{path=\"/user\"} 3
+# EOF
+";
+        assert_eq!(encode(&registry.gather(), &exemplars), expected);
+    }
+
+    #[test]
+    fn keeps_counter_name_without_suffix() {
+        let registry = Registry::new();
+        let counter = IntCounterVec::new(Opts::new("response_code", "Codes"), &[]).unwrap();
+        registry.register(Box::new(counter.clone())).unwrap();
+        counter.with_label_values(&[]).inc();
+
+        let encoded = encode(&registry.gather(), &|_, _, _| None);
+        assert_eq!(
+            encoded,
+            "# TYPE response_code counter\n# HELP response_code Codes\nresponse_code_total 1\n# EOF\n"
+        );
+    }
+
+    #[test]
+    fn accepts_openmetrics_only_when_asked() {
+        assert!(is_accepted(Some(OPENMETRICS_FORMAT)));
+        assert!(is_accepted(Some(
+            "application/openmetrics-text;version=1.0.0,text/plain;q=0.5"
+        )));
+        assert!(!is_accepted(Some(
+            "application/openmetrics-text;q=0, text/plain"
+        )));
+        assert!(!is_accepted(Some("application/openmetrics-text-v2")));
+        assert!(!is_accepted(Some("text/plain; version=0.0.4")));
+        assert!(!is_accepted(Some("*/*")));
+        assert!(!is_accepted(None));
+    }
+}
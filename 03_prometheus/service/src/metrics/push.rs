@@ -0,0 +1,218 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use prometheus::{Encoder, Gauge, GaugeVec, IntGauge, Opts, Registry, TextEncoder};
+use warp::http::{Method, Request};
+use warp::hyper::Body;
+
+use crate::context::log_println;
+use crate::error::Error::PushMetricsError;
+use crate::{http, Result};
+
+const JOB: &str = "migrate";
+
+/// Result of `migrate` binary run. Job is short-lived, so it's never
+/// scraped by Prometheus & has to push its metrics to Pushgateway.
+#[derive(Debug)]
+pub struct MigrationReport {
+    /// Either `migrate` or `wait`
+    pub mode: &'static str,
+    pub success: bool,
+    pub duration: Duration,
+    pub applied: Option<usize>,
+    pub last_migration_id: Option<String>,
+}
+
+fn build_registry(report: &MigrationReport) -> prometheus::Result<Registry> {
+    let registry = Registry::new();
+
+    let success = IntGauge::new("migrate_success", "Whether last migrate run succeeded")?;
+    success.set(report.success as i64);
+    registry.register(Box::new(success))?;
+
+    let timestamp = Gauge::new(
+        "migrate_last_run_timestamp_seconds",
+        "Time when last migrate run finished",
+    )?;
+    timestamp.set(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs_f64())
+            .unwrap_or_default(),
+    );
+    registry.register(Box::new(timestamp))?;
+
+    let (duration_name, duration_help) = match report.mode {
+        "wait" => (
+            "migrate_wait_duration_seconds",
+            "Time spent waiting for migration to be applied",
+        ),
+        _ => ("migrate_duration_seconds", "Time spent applying migrations"),
+    };
+    let duration = Gauge::new(duration_name, duration_help)?;
+    duration.set(report.duration.as_secs_f64());
+    registry.register(Box::new(duration))?;
+
+    if let Some(applied) = report.applied {
+        let applied_gauge = IntGauge::new(
+            "migrate_applied_migrations",
+            "Number of migrations applied by last run",
+        )?;
+        applied_gauge.set(applied as i64);
+        registry.register(Box::new(applied_gauge))?;
+    }
+
+    if let Some(migration_id) = &report.last_migration_id {
+        let info = GaugeVec::new(
+            Opts::new(
+                "migrate_last_applied_info",
+                "Last applied migration, value is always 1",
+            ),
+            &["migration_id"],
+        )?;
+        info.with_label_values(&[migration_id]).set(1.0);
+        registry.register(Box::new(info))?;
+    }
+
+    Ok(registry)
+}
+
+/// Replaces metrics of `migrate` job group (one per mode) in Pushgateway.
+/// Gives up after `timeout`, so that unreachable Pushgateway can't hang job.
+pub async fn push_migration_report(
+    pushgateway_url: &str,
+    timeout: Duration,
+    report: &MigrationReport,
+) -> Result<()> {
+    let registry = build_registry(report).map_err(|e| PushMetricsError(e.to_string()))?;
+    let encoder = TextEncoder::new();
+    let mut body = Vec::new();
+    encoder
+        .encode(&registry.gather(), &mut body)
+        .map_err(|e| PushMetricsError(e.to_string()))?;
+
+    let url = format!(
+        "{}/metrics/job/{}/mode/{}",
+        pushgateway_url.trim_end_matches('/'),
+        JOB,
+        report.mode
+    );
+    let req = Request::builder()
+        .method(Method::PUT)
+        .uri(&url)
+        .header("content-type", encoder.format_type())
+        .body(Body::from(body))
+        .map_err(|e| PushMetricsError(e.to_string()))?;
+
+    let res = tokio::time::timeout(timeout, http::send(req))
+        .await
+        .unwrap_or_else(|_| Err(format!("request timed out after {:?}", timeout)))
+        .map_err(PushMetricsError)?;
+    if !res.status().is_success() {
+        return Err(PushMetricsError(format!(
+            "pushgateway responded with {}",
+            res.status()
+        )));
+    }
+    log_println!("Pushed metrics to {}", url);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use tokio::net::TcpListener;
+    use warp::hyper::body::Bytes;
+    use warp::path::FullPath;
+    use warp::Filter;
+
+    use super::*;
+
+    fn report() -> MigrationReport {
+        MigrationReport {
+            mode: "migrate",
+            success: true,
+            duration: Duration::from_millis(1500),
+            applied: Some(2),
+            last_migration_id: Some("V4_credentials".to_owned()),
+        }
+    }
+
+    /// Path & body of last push
+    type Received = Arc<Mutex<Option<(String, String)>>>;
+
+    /// Pushgateway stub which records pushed path & body
+    fn stub(status: u16) -> (String, Received) {
+        let received = Arc::new(Mutex::new(None));
+        let pushed = received.clone();
+        let routes = warp::put()
+            .and(warp::path::full())
+            .and(warp::body::bytes())
+            .map(move |path: FullPath, body: Bytes| {
+                let body = String::from_utf8_lossy(&body).into_owned();
+                *pushed.lock().unwrap() = Some((path.as_str().to_owned(), body));
+                warp::http::StatusCode::from_u16(status).unwrap()
+            });
+        let (addr, server) = warp::serve(routes).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        (format!("http://{}/", addr), received)
+    }
+
+    #[tokio::test]
+    async fn pushes_report_to_job_group() {
+        let (url, received) = stub(200);
+        push_migration_report(&url, Duration::from_secs(5), &report())
+            .await
+            .unwrap();
+
+        let (path, body) = received.lock().unwrap().take().unwrap();
+        assert_eq!(path, "/metrics/job/migrate/mode/migrate");
+        assert!(body.contains("migrate_success 1"));
+        assert!(body.contains("migrate_duration_seconds 1.5"));
+        assert!(body.contains("migrate_applied_migrations 2"));
+        assert!(body.contains("migrate_last_applied_info{migration_id=\"V4_credentials\"} 1"));
+        assert!(body.contains("migrate_last_run_timestamp_seconds"));
+    }
+
+    #[tokio::test]
+    async fn pushes_wait_duration_in_wait_mode() {
+        let (url, received) = stub(200);
+        let report = MigrationReport {
+            mode: "wait",
+            success: false,
+            applied: None,
+            last_migration_id: None,
+            ..report()
+        };
+        push_migration_report(&url, Duration::from_secs(5), &report)
+            .await
+            .unwrap();
+
+        let (path, body) = received.lock().unwrap().take().unwrap();
+        assert_eq!(path, "/metrics/job/migrate/mode/wait");
+        assert!(body.contains("migrate_success 0"));
+        assert!(body.contains("migrate_wait_duration_seconds 1.5"));
+        assert!(!body.contains("migrate_applied_migrations"));
+    }
+
+    #[tokio::test]
+    async fn fails_on_error_status() {
+        let (url, _) = stub(500);
+        let res = push_migration_report(&url, Duration::from_secs(5), &report()).await;
+        assert!(matches!(res, Err(PushMetricsError(e)) if e.contains("500")));
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_unresponsive_pushgateway() {
+        // Accepts connection, but never responds
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let _conn = listener.accept().await;
+            std::future::pending::<()>().await
+        });
+
+        let res = push_migration_report(&url, Duration::from_millis(200), &report()).await;
+        assert!(matches!(res, Err(PushMetricsError(e)) if e.contains("timed out")));
+    }
+}
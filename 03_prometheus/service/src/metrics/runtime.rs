@@ -0,0 +1,110 @@
+use std::sync::Mutex;
+
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::MetricFamily;
+#[cfg(tokio_unstable)]
+use prometheus::GaugeVec;
+use prometheus::{CounterVec, IntCounterVec, IntGauge, Opts};
+use tokio::runtime::Handle;
+
+/// Tokio runtime metrics, read from runtime handle at scrape time.
+/// Poll durations are only available if built with `tokio_unstable`.
+pub struct RuntimeCollector {
+    workers: IntGauge,
+    alive_tasks: IntGauge,
+    global_queue_depth: IntGauge,
+    busy_seconds: CounterVec,
+    parks: IntCounterVec,
+    #[cfg(tokio_unstable)]
+    mean_poll_seconds: GaugeVec,
+    // Counters are reset & set to runtime totals on each scrape, so
+    // concurrent scrapes should not interleave
+    lock: Mutex<()>,
+}
+
+impl RuntimeCollector {
+    pub fn new() -> prometheus::Result<RuntimeCollector> {
+        Ok(RuntimeCollector {
+            workers: IntGauge::new("tokio_workers", "Number of runtime worker threads")?,
+            alive_tasks: IntGauge::new("tokio_alive_tasks", "Number of alive runtime tasks")?,
+            global_queue_depth: IntGauge::new(
+                "tokio_global_queue_depth",
+                "Number of tasks in runtime global queue",
+            )?,
+            busy_seconds: CounterVec::new(
+                Opts::new(
+                    "tokio_worker_busy_seconds_total",
+                    "Time worker spent busy executing tasks",
+                ),
+                &["worker"],
+            )?,
+            parks: IntCounterVec::new(
+                Opts::new("tokio_worker_park_total", "Times worker has parked"),
+                &["worker"],
+            )?,
+            #[cfg(tokio_unstable)]
+            mean_poll_seconds: GaugeVec::new(
+                Opts::new(
+                    "tokio_worker_mean_poll_seconds",
+                    "Exponentially weighted moving average of task poll duration",
+                ),
+                &["worker"],
+            )?,
+            lock: Mutex::new(()),
+        })
+    }
+
+    fn update(&self, handle: &Handle) {
+        let metrics = handle.metrics();
+        self.workers.set(metrics.num_workers() as i64);
+        self.alive_tasks.set(metrics.num_alive_tasks() as i64);
+        self.global_queue_depth
+            .set(metrics.global_queue_depth() as i64);
+
+        for worker in 0..metrics.num_workers() {
+            let label = worker.to_string();
+            let busy = self.busy_seconds.with_label_values(&[&label]);
+            busy.reset();
+            busy.inc_by(metrics.worker_total_busy_duration(worker).as_secs_f64());
+            let parks = self.parks.with_label_values(&[&label]);
+            parks.reset();
+            parks.inc_by(metrics.worker_park_count(worker));
+            #[cfg(tokio_unstable)]
+            self.mean_poll_seconds
+                .with_label_values(&[&label])
+                .set(metrics.worker_mean_poll_time(worker).as_secs_f64());
+        }
+    }
+}
+
+impl Collector for RuntimeCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        let mut descs = vec![];
+        descs.extend(self.workers.desc());
+        descs.extend(self.alive_tasks.desc());
+        descs.extend(self.global_queue_depth.desc());
+        descs.extend(self.busy_seconds.desc());
+        descs.extend(self.parks.desc());
+        #[cfg(tokio_unstable)]
+        descs.extend(self.mean_poll_seconds.desc());
+        descs
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let _guard = self.lock.lock().unwrap();
+        // Metrics may be gathered outside of runtime, e.g. on push
+        if let Ok(handle) = Handle::try_current() {
+            self.update(&handle);
+        }
+
+        let mut families = vec![];
+        families.extend(self.workers.collect());
+        families.extend(self.alive_tasks.collect());
+        families.extend(self.global_queue_depth.collect());
+        families.extend(self.busy_seconds.collect());
+        families.extend(self.parks.collect());
+        #[cfg(tokio_unstable)]
+        families.extend(self.mean_poll_seconds.collect());
+        families
+    }
+}
@@ -0,0 +1,390 @@
+use std::sync::{Mutex, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use lazy_static::lazy_static;
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::MetricFamily;
+use prometheus::{GaugeVec, IntCounterVec, Opts};
+use serde::Serialize;
+
+use crate::config::{SloConfig, SloKind};
+use crate::routes::path_matches;
+
+// Events are counted in per-minute buckets, enough of them to cover
+// the longest window
+const BUCKET_SECONDS: u64 = 60;
+const BUCKETS: u64 = 6 * 60;
+
+/// Windows burn rate is computed for, with their length in buckets
+const WINDOWS: &[(&str, u64)] = &[("5m", 5), ("30m", 30), ("1h", 60), ("6h", 360)];
+
+struct BurnRateAlert {
+    severity: &'static str,
+    long_window: &'static str,
+    short_window: &'static str,
+    threshold: f64,
+}
+
+// Multiwindow, multi-burn-rate alerts from Google SRE workbook. Page if 2%
+// of 30 days budget is spent within an hour, ticket if 5% within 6 hours.
+// Short window makes alert reset soon after burning stopped.
+const ALERTS: &[BurnRateAlert] = &[
+    BurnRateAlert {
+        severity: "page",
+        long_window: "1h",
+        short_window: "5m",
+        threshold: 14.4,
+    },
+    BurnRateAlert {
+        severity: "ticket",
+        long_window: "6h",
+        short_window: "30m",
+        threshold: 6.0,
+    },
+];
+
+#[derive(Clone, Copy, Default)]
+struct Bucket {
+    minute: u64,
+    good: u64,
+    total: u64,
+}
+
+struct Slo {
+    config: SloConfig,
+    buckets: Mutex<Vec<Bucket>>,
+}
+
+lazy_static! {
+    static ref SLOS: RwLock<Vec<Slo>> = RwLock::new(vec![]);
+    pub static ref SLO_REQUESTS_COLLECTOR: IntCounterVec = IntCounterVec::new(
+        Opts::new("slo_requests_total", "Requests counted towards SLO"),
+        &["slo"]
+    )
+    .expect("metric can be created");
+    pub static ref SLO_GOOD_REQUESTS_COLLECTOR: IntCounterVec = IntCounterVec::new(
+        Opts::new("slo_good_requests_total", "Requests which met SLO"),
+        &["slo"]
+    )
+    .expect("metric can be created");
+}
+
+fn now_minute() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+        / BUCKET_SECONDS
+}
+
+impl Slo {
+    fn new(config: SloConfig) -> Slo {
+        Slo {
+            config,
+            buckets: Mutex::new(vec![Bucket::default(); BUCKETS as usize]),
+        }
+    }
+
+    fn matches(&self, method: &str, path: &str) -> bool {
+        if let Some(m) = &self.config.method {
+            if !m.eq_ignore_ascii_case(method) {
+                return false;
+            }
+        }
+        match &self.config.path {
+            Some(pattern) => path_matches(pattern, path),
+            None => true,
+        }
+    }
+
+    fn is_good(&self, status_code: u16, response_time: f64) -> bool {
+        match self.config.kind {
+            SloKind::Availability => status_code < 500,
+            SloKind::Latency => self
+                .config
+                .latency_threshold_seconds
+                .is_none_or(|t| response_time <= t),
+        }
+    }
+
+    fn record(&self, good: bool) {
+        let minute = now_minute();
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = &mut buckets[(minute % BUCKETS) as usize];
+        if bucket.minute != minute {
+            *bucket = Bucket {
+                minute,
+                ..Bucket::default()
+            };
+        }
+        bucket.total += 1;
+        if good {
+            bucket.good += 1;
+        }
+    }
+
+    fn window(&self, name: &'static str, minutes: u64) -> WindowReport {
+        let now = now_minute();
+        let buckets = self.buckets.lock().unwrap();
+        let (good, total) = buckets
+            .iter()
+            .filter(|b| b.minute + minutes > now)
+            .fold((0, 0), |(good, total), b| (good + b.good, total + b.total));
+        let error_ratio = if total > 0 {
+            1.0 - good as f64 / total as f64
+        } else {
+            0.0
+        };
+        WindowReport {
+            window: name,
+            good,
+            total,
+            error_ratio,
+            // How many times faster than allowed error budget is spent
+            burn_rate: error_ratio / (1.0 - self.config.objective),
+        }
+    }
+
+    fn report(&self) -> SloReport {
+        let windows: Vec<WindowReport> = WINDOWS
+            .iter()
+            .map(|(name, minutes)| self.window(name, *minutes))
+            .collect();
+        let burn_rate = |name: &str| {
+            windows
+                .iter()
+                .find(|w| w.window == name)
+                .map_or(0.0, |w| w.burn_rate)
+        };
+        let alerts = ALERTS
+            .iter()
+            .map(|a| AlertReport {
+                severity: a.severity,
+                long_window: a.long_window,
+                short_window: a.short_window,
+                threshold: a.threshold,
+                firing: burn_rate(a.long_window) > a.threshold
+                    && burn_rate(a.short_window) > a.threshold,
+            })
+            .collect();
+        SloReport {
+            name: self.config.name.clone(),
+            kind: self.config.kind,
+            objective: self.config.objective,
+            windows,
+            alerts,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct WindowReport {
+    pub window: &'static str,
+    pub good: u64,
+    pub total: u64,
+    pub error_ratio: f64,
+    pub burn_rate: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AlertReport {
+    pub severity: &'static str,
+    pub long_window: &'static str,
+    pub short_window: &'static str,
+    pub threshold: f64,
+    pub firing: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SloReport {
+    pub name: String,
+    pub kind: SloKind,
+    pub objective: f64,
+    pub windows: Vec<WindowReport>,
+    pub alerts: Vec<AlertReport>,
+}
+
+/// Replaces tracked SLOs. Series are created upfront, so that rates
+/// can be computed even before first matching request.
+pub fn configure(slos: &[SloConfig]) {
+    for slo in slos {
+        SLO_REQUESTS_COLLECTOR.with_label_values(&[&slo.name]);
+        SLO_GOOD_REQUESTS_COLLECTOR.with_label_values(&[&slo.name]);
+    }
+    *SLOS.write().unwrap() = slos.iter().cloned().map(Slo::new).collect();
+}
+
+/// Counts finished request towards every matching SLO
+pub fn track(method: &str, path: &str, status_code: u16, response_time: f64) {
+    for slo in SLOS.read().unwrap().iter() {
+        if !slo.matches(method, path) {
+            continue;
+        }
+        let good = slo.is_good(status_code, response_time);
+        slo.record(good);
+        SLO_REQUESTS_COLLECTOR
+            .with_label_values(&[&slo.config.name])
+            .inc();
+        if good {
+            SLO_GOOD_REQUESTS_COLLECTOR
+                .with_label_values(&[&slo.config.name])
+                .inc();
+        }
+    }
+}
+
+pub fn report() -> Vec<SloReport> {
+    SLOS.read().unwrap().iter().map(|s| s.report()).collect()
+}
+
+/// Burn rates computed at scrape time, so alert rules need no PromQL
+/// beyond comparing them with threshold
+pub struct BurnRateCollector {
+    burn_rate: GaugeVec,
+    objective: GaugeVec,
+    // Gauges are reset & set on each scrape, so concurrent scrapes
+    // should not interleave
+    lock: Mutex<()>,
+}
+
+impl BurnRateCollector {
+    pub fn new() -> prometheus::Result<BurnRateCollector> {
+        Ok(BurnRateCollector {
+            burn_rate: GaugeVec::new(
+                Opts::new("slo_burn_rate", "Error budget burn rate over window"),
+                &["slo", "window"],
+            )?,
+            objective: GaugeVec::new(
+                Opts::new("slo_objective", "Target ratio of good requests"),
+                &["slo"],
+            )?,
+            lock: Mutex::new(()),
+        })
+    }
+}
+
+impl Collector for BurnRateCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        let mut descs = vec![];
+        descs.extend(self.burn_rate.desc());
+        descs.extend(self.objective.desc());
+        descs
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let _guard = self.lock.lock().unwrap();
+        // SLOs could have been replaced since last scrape
+        self.burn_rate.reset();
+        self.objective.reset();
+        for slo in report() {
+            self.objective
+                .with_label_values(&[&slo.name])
+                .set(slo.objective);
+            for w in &slo.windows {
+                self.burn_rate
+                    .with_label_values(&[&slo.name, w.window])
+                    .set(w.burn_rate);
+            }
+        }
+
+        let mut families = vec![];
+        families.extend(self.burn_rate.collect());
+        families.extend(self.objective.collect());
+        families
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn slo(kind: SloKind, method: Option<&str>, path: Option<&str>) -> Slo {
+        Slo::new(SloConfig {
+            name: "test".to_owned(),
+            kind,
+            method: method.map(|m| m.to_owned()),
+            path: path.map(|p| p.to_owned()),
+            objective: 0.99,
+            latency_threshold_seconds: Some(0.3),
+        })
+    }
+
+    /// Puts events into bucket of minute given number of minutes ago
+    fn put(slo: &Slo, minutes_ago: u64, good: u64, total: u64) {
+        let minute = now_minute() - minutes_ago;
+        slo.buckets.lock().unwrap()[(minute % BUCKETS) as usize] = Bucket {
+            minute,
+            good,
+            total,
+        };
+    }
+
+    #[test]
+    fn matches_method_and_path() {
+        let any = slo(SloKind::Availability, None, None);
+        assert!(any.matches("DELETE", "/anything"));
+
+        let get_users = slo(SloKind::Availability, Some("get"), Some("/user/**"));
+        assert!(get_users.matches("GET", "/user/1"));
+        assert!(!get_users.matches("POST", "/user/1"));
+        assert!(!get_users.matches("GET", "/health"));
+    }
+
+    #[test]
+    fn good_requests() {
+        let availability = slo(SloKind::Availability, None, None);
+        assert!(availability.is_good(404, 10.0));
+        assert!(!availability.is_good(503, 0.01));
+
+        let latency = slo(SloKind::Latency, None, None);
+        assert!(latency.is_good(500, 0.3));
+        assert!(!latency.is_good(200, 0.31));
+    }
+
+    #[test]
+    fn windows_count_recent_buckets_only() {
+        let slo = slo(SloKind::Availability, None, None);
+        put(&slo, 0, 90, 100);
+        put(&slo, 10, 100, 100);
+        put(&slo, 100, 0, 100);
+        // Older than longest window, so should be ignored
+        put(&slo, BUCKETS + 20, 0, 100);
+
+        let report = slo.report();
+        let window = |name: &str| report.windows.iter().find(|w| w.window == name).unwrap();
+        assert_eq!((window("5m").good, window("5m").total), (90, 100));
+        assert_eq!((window("30m").good, window("30m").total), (190, 200));
+        assert_eq!((window("6h").good, window("6h").total), (190, 300));
+
+        assert!((window("5m").error_ratio - 0.1).abs() < 1e-9);
+        // 10% errors against 1% budget
+        assert!((window("5m").burn_rate - 10.0).abs() < 1e-9);
+        assert!((window("6h").burn_rate - 36.666).abs() < 1e-3);
+    }
+
+    #[test]
+    fn alerts_need_both_windows_burning() {
+        let slo = slo(SloKind::Availability, None, None);
+        // Long windows burn fast, but errors stopped 40 minutes ago
+        put(&slo, 40, 0, 1000);
+        put(&slo, 0, 100, 100);
+
+        let report = slo.report();
+        assert!(report.alerts.iter().all(|a| !a.firing));
+
+        put(&slo, 0, 0, 100);
+        let report = slo.report();
+        assert!(report.alerts.iter().all(|a| a.firing));
+    }
+
+    #[test]
+    fn record_resets_stale_bucket() {
+        let slo = slo(SloKind::Availability, None, None);
+        // Same slot, but from previous cycle of buckets
+        put(&slo, BUCKETS, 5, 5);
+        slo.record(false);
+
+        let report = slo.report();
+        assert_eq!((report.windows[0].good, report.windows[0].total), (0, 1));
+    }
+}
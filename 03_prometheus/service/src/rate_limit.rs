@@ -0,0 +1,423 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+use ipnet::IpNet;
+
+use tokio::time::{Duration, Instant};
+use warp::http::header::{HeaderMap, HeaderName, HeaderValue, RETRY_AFTER};
+use warp::http::Method;
+use warp::path::FullPath;
+use warp::{Filter, Rejection};
+
+use crate::config::{Config, RateLimitConfig};
+use crate::context;
+use crate::error::Error::RateLimited;
+use crate::metrics;
+use crate::routes::path_matches;
+
+// Full buckets are dropped from time to time, so that one-off clients
+// don't pile up. Dropped bucket is the same as new one.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Why request was rejected, for response headers
+#[derive(Debug)]
+pub struct Exceeded {
+    burst: u32,
+    retry_after: Duration,
+    reset: Duration,
+}
+
+fn seconds_header(duration: Duration) -> HeaderValue {
+    // Headers have whole seconds, and 0 would invite retry right away
+    HeaderValue::from(duration.as_secs_f64().ceil().max(1.0) as u64)
+}
+
+impl Exceeded {
+    /// `Retry-After` along with `RateLimit-*` ones from IETF draft
+    pub fn add_headers(&self, headers: &mut HeaderMap) {
+        headers.insert(RETRY_AFTER, seconds_header(self.retry_after));
+        headers.insert(
+            HeaderName::from_static("ratelimit-limit"),
+            HeaderValue::from(self.burst),
+        );
+        headers.insert(
+            HeaderName::from_static("ratelimit-remaining"),
+            HeaderValue::from(0),
+        );
+        headers.insert(
+            HeaderName::from_static("ratelimit-reset"),
+            seconds_header(self.reset),
+        );
+    }
+}
+
+impl fmt::Display for Exceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "burst of {} used up, retry in {:?}",
+            self.burst, self.retry_after
+        )
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+    full_at: Instant,
+}
+
+/// What client is told apart by
+#[derive(Clone, Copy)]
+enum Key {
+    /// Client address, before authentication
+    Address,
+    /// Authenticated caller, or address for anonymous requests
+    Caller,
+}
+
+impl Key {
+    fn name(&self) -> &'static str {
+        match self {
+            Key::Address => "address",
+            Key::Caller => "caller",
+        }
+    }
+}
+
+struct State {
+    // Keyed by limit & client
+    buckets: HashMap<(String, String), Bucket>,
+    last_sweep: Instant,
+}
+
+/// Token buckets of all clients. Kept across config reloads, so that
+/// reload doesn't hand out fresh bursts.
+#[derive(Clone)]
+pub struct RateLimiter {
+    state: Arc<Mutex<State>>,
+}
+
+fn limit_key(key: Key, limit: &RateLimitConfig) -> String {
+    format!(
+        "{} {} {}",
+        key.name(),
+        limit.method.as_deref().unwrap_or("*"),
+        limit.path.as_deref().unwrap_or("/**")
+    )
+}
+
+fn matches(limit: &RateLimitConfig, method: &Method, path: &str) -> bool {
+    limit
+        .method
+        .as_ref()
+        .is_none_or(|m| m.eq_ignore_ascii_case(method.as_str()))
+        && limit.path.as_ref().is_none_or(|p| path_matches(p, path))
+}
+
+/// Proxies in front of service, e.g. ingress pods. Client address is taken
+/// from `X-Forwarded-For` entries appended by them, since otherwise all
+/// clients would share proxy address.
+#[derive(Clone)]
+pub struct TrustedProxies {
+    nets: Arc<Vec<IpNet>>,
+}
+
+/// Parses `trusted_proxies` entry, either network like `10.0.0.0/8` or
+/// single address
+pub fn parse_proxy(entry: &str) -> Option<IpNet> {
+    entry
+        .parse::<IpNet>()
+        .ok()
+        .or_else(|| entry.parse::<IpAddr>().ok().map(IpNet::from))
+}
+
+impl TrustedProxies {
+    pub fn of(config: &Config) -> TrustedProxies {
+        TrustedProxies {
+            nets: Arc::new(
+                config
+                    .trusted_proxies
+                    .iter()
+                    .filter_map(|p| parse_proxy(p))
+                    .collect(),
+            ),
+        }
+    }
+
+    fn is_trusted(&self, ip: &IpAddr) -> bool {
+        self.nets.iter().any(|net| net.contains(ip))
+    }
+
+    /// Last address in chain which isn't trusted proxy. Entries before it
+    /// could be made up by client.
+    fn client_ip(&self, remote: Option<IpAddr>, forwarded_for: Option<&str>) -> Option<IpAddr> {
+        let mut client = remote?;
+        let mut forwarded = forwarded_for
+            .unwrap_or_default()
+            .rsplit(',')
+            .map(|entry| entry.trim().parse::<IpAddr>());
+        while self.is_trusted(&client) {
+            match forwarded.next() {
+                Some(Ok(ip)) => client = ip,
+                // Garbage from proxy, nothing before it can be trusted
+                _ => break,
+            }
+        }
+        Some(client)
+    }
+}
+
+impl RateLimiter {
+    pub fn new() -> RateLimiter {
+        RateLimiter {
+            state: Arc::new(Mutex::new(State {
+                buckets: HashMap::new(),
+                last_sweep: Instant::now(),
+            })),
+        }
+    }
+
+    /// Takes token from client's bucket for first matching limit
+    fn check(
+        &self,
+        key: Key,
+        limits: &[RateLimitConfig],
+        method: &Method,
+        path: &str,
+        client: String,
+    ) -> Result<(), Rejection> {
+        let limit = match limits.iter().find(|l| matches(l, method, path)) {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+        let limit_key = limit_key(key, limit);
+        let now = Instant::now();
+        let burst = limit.burst as f64;
+        let rate = limit.requests_per_second;
+
+        let mut state = self.state.lock().unwrap();
+        if now - state.last_sweep >= SWEEP_INTERVAL {
+            state.buckets.retain(|_, b| b.full_at > now);
+            state.last_sweep = now;
+        }
+        let bucket = state
+            .buckets
+            .entry((limit_key, client))
+            .or_insert_with(|| Bucket {
+                tokens: burst,
+                updated_at: now,
+                full_at: now,
+            });
+        let refilled = (now - bucket.updated_at).as_secs_f64() * rate;
+        bucket.tokens = (bucket.tokens + refilled).min(burst);
+        bucket.updated_at = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            bucket.full_at = now + Duration::from_secs_f64((burst - bucket.tokens) / rate);
+            return Ok(());
+        }
+
+        metrics::RATE_LIMITED_COLLECTOR
+            .with_label_values(&[
+                key.name(),
+                limit.method.as_deref().unwrap_or(""),
+                limit.path.as_deref().unwrap_or(""),
+            ])
+            .inc();
+        Err(warp::reject::custom(RateLimited(Exceeded {
+            burst: limit.burst,
+            retry_after: Duration::from_secs_f64((1.0 - bucket.tokens) / rate),
+            reset: Duration::from_secs_f64((burst - bucket.tokens) / rate),
+        })))
+    }
+}
+
+fn limit_by(
+    key: Key,
+    limiter: &RateLimiter,
+    limits: &[RateLimitConfig],
+    proxies: &TrustedProxies,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    let limiter = limiter.clone();
+    let limits = Arc::new(limits.to_vec());
+    let proxies = proxies.clone();
+    warp::method()
+        .and(warp::path::full())
+        .and(warp::header::optional::<String>("x-forwarded-for"))
+        .and_then(
+            move |method: Method, path: FullPath, forwarded_for: Option<String>| {
+                let limiter = limiter.clone();
+                let limits = limits.clone();
+                let address = proxies
+                    .client_ip(
+                        context::remote_addr().map(|a| a.ip()),
+                        forwarded_for.as_deref(),
+                    )
+                    .map(|ip| ip.to_string());
+                async move {
+                    let client = match key {
+                        Key::Address => address,
+                        Key::Caller => context::caller_identity().or(address),
+                    };
+                    limiter.check(
+                        key,
+                        &limits,
+                        &method,
+                        path.as_str(),
+                        client.unwrap_or_default(),
+                    )
+                }
+            },
+        )
+        .untuple_one()
+}
+
+/// Rejects request with 429 if client address used up its bucket for the
+/// route. Goes before authentication, so that requests with bad
+/// credentials are limited too, before they reach DB.
+pub fn limit_by_address(
+    limiter: &RateLimiter,
+    limits: &[RateLimitConfig],
+    proxies: &TrustedProxies,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    limit_by(Key::Address, limiter, limits, proxies)
+}
+
+/// Rejects request with 429 if caller used up its bucket for the route.
+/// Goes after authentication, so that callers are told apart by identity
+/// rather than address.
+pub fn limit(
+    limiter: &RateLimiter,
+    limits: &[RateLimitConfig],
+    proxies: &TrustedProxies,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    limit_by(Key::Caller, limiter, limits, proxies)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+
+    fn limit(
+        method: Option<&str>,
+        path: Option<&str>,
+        requests_per_second: f64,
+    ) -> RateLimitConfig {
+        RateLimitConfig {
+            method: method.map(|m| m.to_owned()),
+            path: path.map(|p| p.to_owned()),
+            requests_per_second,
+            burst: 2,
+        }
+    }
+
+    fn check(limiter: &RateLimiter, limits: &[RateLimitConfig], path: &str, client: &str) -> bool {
+        limiter
+            .check(Key::Caller, limits, &Method::GET, path, client.to_owned())
+            .is_ok()
+    }
+
+    fn proxies(nets: &[&str]) -> TrustedProxies {
+        TrustedProxies {
+            nets: Arc::new(nets.iter().filter_map(|n| parse_proxy(n)).collect()),
+        }
+    }
+
+    #[test]
+    fn bucket_allows_burst_then_refills() {
+        let limiter = RateLimiter::new();
+        let limits = [limit(None, None, 20.0)];
+        assert!(check(&limiter, &limits, "/user", "a"));
+        assert!(check(&limiter, &limits, "/user", "a"));
+        assert!(!check(&limiter, &limits, "/user", "a"));
+
+        // One token is back after 50ms
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(check(&limiter, &limits, "/user", "a"));
+        assert!(!check(&limiter, &limits, "/user", "a"));
+    }
+
+    #[test]
+    fn buckets_are_per_client_and_key() {
+        let limiter = RateLimiter::new();
+        let limits = [limit(None, None, 0.001)];
+        assert!(check(&limiter, &limits, "/user", "a"));
+        assert!(check(&limiter, &limits, "/user", "a"));
+        assert!(!check(&limiter, &limits, "/user", "a"));
+        assert!(check(&limiter, &limits, "/user", "b"));
+        assert!(limiter
+            .check(Key::Address, &limits, &Method::GET, "/user", "a".to_owned())
+            .is_ok());
+    }
+
+    #[test]
+    fn first_matching_limit_applies() {
+        let limiter = RateLimiter::new();
+        let limits = [
+            limit(Some("post"), Some("/user/**"), 0.001),
+            RateLimitConfig {
+                burst: 100,
+                ..limit(None, Some("/user/**"), 0.001)
+            },
+        ];
+        for _ in 0..10 {
+            assert!(check(&limiter, &limits, "/user/1", "a"));
+        }
+        // Unmatched paths aren't limited
+        for _ in 0..10 {
+            assert!(check(&limiter, &limits, "/health", "a"));
+        }
+        let post = |limiter: &RateLimiter| {
+            limiter.check(Key::Caller, &limits, &Method::POST, "/user", "a".to_owned())
+        };
+        assert!(post(&limiter).is_ok());
+        assert!(post(&limiter).is_ok());
+        let rejection = post(&limiter).unwrap_err();
+        match rejection.find::<Error>() {
+            Some(Error::RateLimited(exceeded)) => {
+                let mut headers = HeaderMap::new();
+                exceeded.add_headers(&mut headers);
+                assert_eq!(headers["ratelimit-limit"], "2");
+                assert_eq!(headers["ratelimit-remaining"], "0");
+                assert_eq!(headers[RETRY_AFTER], "1000");
+            }
+            other => panic!("expected rate limit, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn client_ip_skips_trusted_proxies() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        let proxies = proxies(&["10.0.0.0/8", "192.168.1.1"]);
+
+        // Direct client, header is made up
+        assert_eq!(
+            proxies.client_ip(Some(ip("1.2.3.4")), Some("5.6.7.8")),
+            Some(ip("1.2.3.4"))
+        );
+        // Spoofed entries before the one appended by proxy are ignored
+        assert_eq!(
+            proxies.client_ip(Some(ip("10.0.0.5")), Some("5.6.7.8, 1.2.3.4")),
+            Some(ip("1.2.3.4"))
+        );
+        // Chain of proxies
+        assert_eq!(
+            proxies.client_ip(Some(ip("10.0.0.5")), Some("1.2.3.4, 192.168.1.1")),
+            Some(ip("1.2.3.4"))
+        );
+        // Garbage from proxy stops the walk at last trusted address
+        assert_eq!(
+            proxies.client_ip(Some(ip("10.0.0.5")), Some("1.2.3.4, bogus")),
+            Some(ip("10.0.0.5"))
+        );
+        assert_eq!(
+            proxies.client_ip(Some(ip("10.0.0.5")), None),
+            Some(ip("10.0.0.5"))
+        );
+        assert_eq!(proxies.client_ip(None, Some("1.2.3.4")), None);
+    }
+}
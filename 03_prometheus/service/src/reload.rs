@@ -0,0 +1,75 @@
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time::{interval, Duration, MissedTickBehavior};
+
+use crate::config::Config;
+use crate::context::{log_eprintln, log_println};
+use crate::metrics;
+
+// Kubelet syncs mounted ConfigMaps about once a minute anyway,
+// so there's no point to check file more often
+pub const FILE_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+pub fn file_hash(path: &str) -> Option<u64> {
+    let content = fs::read(path).ok()?;
+    let mut hasher = DefaultHasher::new();
+    content.hash(&mut hasher);
+    Some(hasher.finish())
+}
+
+/// Reloads config on SIGHUP or when config file content changes (mounted
+/// ConfigMap is updated in place), and passes it to `apply`. Invalid config
+/// is reported & ignored, so service keeps running with the last good one.
+pub async fn watch<F, Fut>(config: Config, apply: F)
+where
+    F: Fn(Config) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let mut sighup = signal(SignalKind::hangup()).expect("SIGHUP handler can be installed");
+    let mut ticker = interval(FILE_CHECK_INTERVAL);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut last_file_hash = config.file().and_then(file_hash);
+    let mut current = config;
+    metrics::track_config(&current.hash());
+
+    loop {
+        let trigger = tokio::select! {
+            _ = sighup.recv() => "SIGHUP",
+            _ = ticker.tick() => {
+                let file_hash = current.file().and_then(file_hash);
+                if file_hash == last_file_hash {
+                    continue;
+                }
+                "config file change"
+            }
+        };
+        // File is read on reload anyway, so SIGHUP after file edit
+        // shouldn't cause second reload
+        last_file_hash = current.file().and_then(file_hash);
+
+        log_println!("Reloading config on {}", trigger);
+        match current.reload() {
+            Ok((config, ignored)) => {
+                if !ignored.is_empty() {
+                    log_eprintln!(
+                        "Config reload ignored changes of {}, they require restart",
+                        ignored.join(", ")
+                    );
+                }
+                let hash = config.hash();
+                apply(config.clone()).await;
+                metrics::track_config_reload(Some(&hash));
+                log_println!("Config reloaded, hash {} (was {})", hash, current.hash());
+                current = config;
+            }
+            Err(e) => {
+                metrics::track_config_reload(None);
+                log_eprintln!("Config reload failed, keeping current config: {}", e);
+            }
+        }
+    }
+}
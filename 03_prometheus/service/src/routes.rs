@@ -0,0 +1,57 @@
+use warp::Filter;
+
+/// Passes requests through only if `enabled`, so that routes behind it are
+/// not found otherwise
+pub fn enabled_if(enabled: bool) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+    warp::any()
+        .and_then(move || async move {
+            if enabled {
+                Ok(())
+            } else {
+                Err(warp::reject::not_found())
+            }
+        })
+        .untuple_one()
+}
+
+/// Matches path against route pattern. Path segments can be replaced with
+/// `*` wildcard, and `**` at the end matches any number of segments, e.g.
+/// `/user/*` or `/user/**`.
+pub fn path_matches(pattern: &str, path: &str) -> bool {
+    let pattern: Vec<&str> = pattern.trim_matches('/').split('/').collect();
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let (pattern, any_rest) = match pattern.split_last() {
+        Some((&"**", init)) => (init, true),
+        _ => (&pattern[..], false),
+    };
+    let len_matches = if any_rest {
+        segments.len() >= pattern.len()
+    } else {
+        segments.len() == pattern.len()
+    };
+    len_matches
+        && pattern
+            .iter()
+            .zip(segments.iter())
+            .all(|(p, s)| *p == "*" || p == s)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn path_patterns() {
+        assert!(path_matches("/user", "/user"));
+        assert!(path_matches("/user", "/user/"));
+        assert!(!path_matches("/user", "/user/1"));
+        assert!(path_matches("/user/*", "/user/1"));
+        assert!(!path_matches("/user/*", "/user"));
+        assert!(!path_matches("/user/*", "/user/1/keys"));
+        assert!(path_matches("/user/**", "/user"));
+        assert!(path_matches("/user/**", "/user/1/keys"));
+        assert!(!path_matches("/user/**", "/users/1"));
+        assert!(path_matches("/*/keys", "/user/keys"));
+        assert!(path_matches("/**", "/anything/at/all"));
+    }
+}
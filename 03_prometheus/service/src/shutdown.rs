@@ -0,0 +1,37 @@
+use std::io::Write;
+
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time::{sleep, Duration};
+
+use crate::context::log_println;
+use crate::health;
+
+/// Resolves once process is asked to stop, either by orchestrator (SIGTERM)
+/// or from terminal (SIGINT)
+pub async fn signal_received() {
+    let mut sigterm = signal(SignalKind::terminate()).expect("SIGTERM handler can be installed");
+    let mut sigint = signal(SignalKind::interrupt()).expect("SIGINT handler can be installed");
+    tokio::select! {
+        _ = sigterm.recv() => log_println!("Received SIGTERM"),
+        _ = sigint.recv() => log_println!("Received SIGINT"),
+    };
+}
+
+/// Waits for shutdown signal, then starts failing readiness probe and
+/// keeps serving for drain period, so that load balancer has time to
+/// remove instance before it stops accepting connections.
+pub async fn drain(drain_period: Duration) {
+    signal_received().await;
+    health::begin_shutdown();
+    log_println!("Draining for {:?} before shutdown", drain_period);
+    sleep(drain_period).await;
+    log_println!("Stopping to accept new connections");
+}
+
+/// Prometheus metrics are pulled, so there's nothing to push, but
+/// traces & logs are buffered and must be flushed before exit.
+pub fn flush() {
+    crate::telemetry::shutdown();
+    std::io::stdout().flush().ok();
+    std::io::stderr().flush().ok();
+}
@@ -0,0 +1,206 @@
+use opentelemetry::propagation::Extractor;
+use opentelemetry::trace::TraceContextExt;
+use opentelemetry::{global, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::{self, Sampler};
+use opentelemetry_sdk::{runtime, Resource};
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::layer::SubscriberExt;
+use warp::http::{HeaderMap, Request};
+
+use crate::config::Config;
+use crate::context::log_println;
+use crate::error::Error::TracingInitError;
+use crate::Result;
+
+const SERVICE_NAME: &str = "service";
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl<'a> Extractor for HeaderExtractor<'a> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|v| v.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|k| k.as_str()).collect()
+    }
+}
+
+/// Sets up span export to OTLP collector. If no endpoint is configured,
+/// nothing is installed, so all spans are effectively no-op.
+pub fn init(config: &Config) -> Result<()> {
+    let endpoint = match &config.otlp_endpoint {
+        Some(endpoint) => endpoint,
+        None => return Ok(()),
+    };
+
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let sampler = Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+        config.trace_sampling_ratio,
+    )));
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(
+            trace::config()
+                .with_sampler(sampler)
+                .with_resource(Resource::new(vec![
+                    KeyValue::new("service.name", SERVICE_NAME),
+                    KeyValue::new("deployment.environment", config.env.clone()),
+                ])),
+        )
+        .install_batch(runtime::Tokio)
+        .map_err(TracingInitError)?;
+
+    let subscriber =
+        tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer));
+    tracing::subscriber::set_global_default(subscriber)
+        .map_err(|e| TracingInitError(e.to_string().into()))?;
+
+    log_println!("Exporting traces to {}", endpoint);
+    Ok(())
+}
+
+/// Flushes all pending spans to collector
+pub fn shutdown() {
+    global::shutdown_tracer_provider();
+}
+
+/// Root span for incoming request. If caller passes W3C `traceparent`
+/// header, span becomes part of caller's trace.
+pub fn request_span<B>(req: &Request<B>) -> Span {
+    let parent = global::get_text_map_propagator(|propagator| {
+        propagator.extract(&HeaderExtractor(req.headers()))
+    });
+    let span = tracing::info_span!(
+        "request",
+        otel.name = %format!("{} {}", req.method(), req.uri().path()),
+        otel.kind = "server",
+        http.method = %req.method(),
+        http.target = %req.uri().path(),
+        http.status_code = tracing::field::Empty,
+    );
+    span.set_parent(parent);
+    span
+}
+
+/// Trace id of current span, if it is going to be exported
+pub fn current_trace_id() -> Option<String> {
+    let context = Span::current().context();
+    let span_context = context.span().span_context().clone();
+    if span_context.is_valid() && span_context.is_sampled() {
+        Some(span_context.trace_id().to_string())
+    } else {
+        None
+    }
+}
+
+pub fn record_status_code(status_code: u16) {
+    Span::current().record("http.status_code", status_code);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use opentelemetry_proto::tonic::collector::trace::v1::trace_service_server::{
+        TraceService, TraceServiceServer,
+    };
+    use opentelemetry_proto::tonic::collector::trace::v1::{
+        ExportTraceServiceRequest, ExportTraceServiceResponse,
+    };
+    use opentelemetry_proto::tonic::common::v1::any_value::Value;
+    use tokio::net::TcpListener;
+    use tokio_stream::wrappers::TcpListenerStream;
+
+    use super::*;
+
+    /// Local collector, which keeps all exported requests
+    #[derive(Clone, Default)]
+    struct Collector(Arc<Mutex<Vec<ExportTraceServiceRequest>>>);
+
+    #[tonic::async_trait]
+    impl TraceService for Collector {
+        async fn export(
+            &self,
+            request: tonic::Request<ExportTraceServiceRequest>,
+        ) -> std::result::Result<tonic::Response<ExportTraceServiceResponse>, tonic::Status>
+        {
+            self.0.lock().unwrap().push(request.into_inner());
+            Ok(tonic::Response::new(ExportTraceServiceResponse {
+                partial_success: None,
+            }))
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn exports_request_spans_to_collector() {
+        let collector = Collector::default();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(TraceServiceServer::new(collector.clone()))
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+
+        let args = vec![
+            "--db-conn-string=postgres://db/app".to_owned(),
+            format!("--otlp-endpoint=http://{}", addr),
+        ];
+        init(&Config::load(&args).unwrap()).unwrap();
+
+        let trace_id = "4bf92f3577b34da6a3ce929d0e0e4736";
+        let req = Request::get("/user/1")
+            .header(
+                "traceparent",
+                format!("00-{}-00f067aa0ba902b7-01", trace_id),
+            )
+            .body(())
+            .unwrap();
+        let span = request_span(&req);
+        span.in_scope(|| {
+            assert_eq!(current_trace_id().as_deref(), Some(trace_id));
+            record_status_code(200);
+        });
+        drop(span);
+        tokio::task::spawn_blocking(shutdown).await.unwrap();
+
+        let requests = collector.0.lock().unwrap();
+        let resource_spans: Vec<_> = requests.iter().flat_map(|r| &r.resource_spans).collect();
+        let service_name = resource_spans[0]
+            .resource
+            .as_ref()
+            .unwrap()
+            .attributes
+            .iter()
+            .find(|a| a.key == "service.name")
+            .and_then(|a| a.value.as_ref()?.value.clone());
+        assert_eq!(
+            service_name,
+            Some(Value::StringValue(SERVICE_NAME.to_owned()))
+        );
+
+        // Collector's own transport spans are exported too
+        let span = resource_spans
+            .iter()
+            .flat_map(|r| &r.scope_spans)
+            .flat_map(|s| &s.spans)
+            .find(|s| s.name == "GET /user/1")
+            .expect("request span is exported");
+        assert_eq!(hex(&span.trace_id), trace_id);
+        assert_eq!(hex(&span.parent_span_id), "00f067aa0ba902b7");
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
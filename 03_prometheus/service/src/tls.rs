@@ -0,0 +1,134 @@
+use std::sync::{Arc, RwLock};
+
+use openssl::asn1::Asn1Time;
+use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod, SslVerifyMode};
+use openssl::x509::{X509NameRef, X509};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time::{interval, MissedTickBehavior};
+
+use crate::config::Config;
+use crate::context::{log_eprintln, log_println};
+use crate::error::Error::TlsError;
+use crate::metrics;
+use crate::reload::{file_hash, FILE_CHECK_INTERVAL};
+use crate::Result;
+
+/// Server certificate, key & client CA paths from config
+#[derive(Clone, Debug)]
+pub struct TlsFiles {
+    cert: String,
+    key: String,
+    client_ca: Option<String>,
+}
+
+impl TlsFiles {
+    /// TLS files if HTTPS is enabled in config
+    pub fn of(config: &Config) -> Option<TlsFiles> {
+        match (&config.tls_cert, &config.tls_key) {
+            (Some(cert), Some(key)) => Some(TlsFiles {
+                cert: cert.clone(),
+                key: key.clone(),
+                client_ca: config.tls_client_ca.clone(),
+            }),
+            _ => None,
+        }
+    }
+
+    fn hash(&self) -> Vec<Option<u64>> {
+        let mut paths = vec![&self.cert, &self.key];
+        paths.extend(&self.client_ca);
+        paths.into_iter().map(|p| file_hash(p)).collect()
+    }
+
+    fn acceptor(&self) -> Result<SslAcceptor> {
+        let mut builder =
+            SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).map_err(TlsError)?;
+        builder
+            .set_certificate_chain_file(&self.cert)
+            .map_err(TlsError)?;
+        builder
+            .set_private_key_file(&self.key, SslFiletype::PEM)
+            .map_err(TlsError)?;
+        builder.check_private_key().map_err(TlsError)?;
+        if let Some(client_ca) = &self.client_ca {
+            builder.set_ca_file(client_ca).map_err(TlsError)?;
+            builder.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+        }
+
+        let cert = X509::from_pem(&std::fs::read(&self.cert)?).map_err(TlsError)?;
+        metrics::track_tls_cert_expiry(&self.cert, not_after_seconds(&cert)?);
+        Ok(builder.build())
+    }
+}
+
+fn not_after_seconds(cert: &X509) -> Result<f64> {
+    let epoch = Asn1Time::from_unix(0).map_err(TlsError)?;
+    let diff = epoch.diff(cert.not_after()).map_err(TlsError)?;
+    Ok(diff.days as f64 * 86400.0 + diff.secs as f64)
+}
+
+/// Certificate subject in `CN=client,O=org` form, used as caller identity
+pub fn subject(name: &X509NameRef) -> String {
+    name.entries()
+        .map(|e| {
+            let key = e.object().nid().short_name().unwrap_or("?");
+            let value = e.data().to_string().unwrap_or_default();
+            format!("{}={}", key, value)
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Acceptor built from current certificate files. Connections take it
+/// on accept, so new certificate only affects new connections.
+#[derive(Clone)]
+pub struct Acceptor {
+    files: TlsFiles,
+    current: Arc<RwLock<Arc<SslAcceptor>>>,
+}
+
+impl Acceptor {
+    pub fn new(files: TlsFiles) -> Result<Acceptor> {
+        let acceptor = files.acceptor()?;
+        Ok(Acceptor {
+            files,
+            current: Arc::new(RwLock::new(Arc::new(acceptor))),
+        })
+    }
+
+    pub fn current(&self) -> Arc<SslAcceptor> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Rebuilds acceptor on SIGHUP or when certificate files change, e.g.
+    /// when cert-manager renews certificate in mounted secret. Broken files
+    /// are reported & ignored, so service keeps using previous certificate.
+    pub async fn watch(self) {
+        let mut sighup = signal(SignalKind::hangup()).expect("SIGHUP handler can be installed");
+        let mut ticker = interval(FILE_CHECK_INTERVAL);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut last_hash = self.files.hash();
+
+        loop {
+            tokio::select! {
+                _ = sighup.recv() => {},
+                _ = ticker.tick() => {
+                    if self.files.hash() == last_hash {
+                        continue;
+                    }
+                }
+            };
+            last_hash = self.files.hash();
+
+            match self.files.acceptor() {
+                Ok(acceptor) => {
+                    *self.current.write().unwrap() = Arc::new(acceptor);
+                    log_println!("TLS certificate reloaded from {}", self.files.cert);
+                }
+                Err(e) => {
+                    log_eprintln!("TLS certificate reload failed, keeping current one: {}", e)
+                }
+            }
+        }
+    }
+}